@@ -1,31 +1,154 @@
-use rig::{agent::AgentBuilder, completion::CompletionModel, embeddings::EmbeddingModel};
-use tracing::info;
+use std::sync::Arc;
 
-use crate::{character::Character, knowledge::KnowledgeBase};
+use rig::{
+    agent::AgentBuilder,
+    completion::{CompletionModel, ModelChoice, ToolDefinition},
+    embeddings::EmbeddingModel,
+    tool::Tool,
+};
+use tracing::{error, info};
+
+use crate::{
+    character::Character,
+    knowledge::{
+        ChannelSettings, Conversation, DocumentFilter, HistoryEntry, HybridConfig, KnowledgeBase,
+        SummaryScope,
+    },
+    memory::{FactConfig, FactExtractor, Summarizer, SummaryConfig},
+};
+
+/// Messages a conversation needs before it gets a title.
+const TITLE_AFTER_MESSAGES: i64 = 4;
 
 #[derive(Clone)]
 pub struct Agent<M: CompletionModel, E: EmbeddingModel + 'static> {
     pub character: Character,
     completion_model: M,
     knowledge: KnowledgeBase<E>,
+    summarizer: Summarizer<M, E>,
+    fact_extractor: FactExtractor<M, E>,
+    retrieval: HybridConfig,
+    /// Document collections queried for context, with the results taken
+    /// from each. Empty queries all documents at once.
+    collections: Vec<(String, usize)>,
+    /// Tools offered in every channel that allows them.
+    tools: Vec<Arc<dyn AgentTool<M>>>,
 }
 
 impl<M: CompletionModel, E: EmbeddingModel> Agent<M, E> {
     pub fn new(character: Character, completion_model: M, knowledge: KnowledgeBase<E>) -> Self {
         info!(name = character.name, "Creating new agent");
 
+        let summarizer = Summarizer::new(
+            SummaryConfig::default(),
+            completion_model.clone(),
+            knowledge.clone(),
+        );
+        let fact_extractor = FactExtractor::new(
+            FactConfig::default(),
+            completion_model.clone(),
+            knowledge.clone(),
+        );
+
         Self {
             character,
             completion_model,
             knowledge,
+            summarizer,
+            fact_extractor,
+            retrieval: HybridConfig::default(),
+            collections: Vec::new(),
+            tools: Vec::new(),
         }
     }
 
+    pub fn with_summary_config(mut self, config: SummaryConfig) -> Self {
+        self.summarizer = Summarizer::new(
+            config,
+            self.completion_model.clone(),
+            self.knowledge.clone(),
+        );
+        self
+    }
+
+    pub fn with_fact_config(mut self, config: FactConfig) -> Self {
+        self.fact_extractor = FactExtractor::new(
+            config,
+            self.completion_model.clone(),
+            self.knowledge.clone(),
+        );
+        self
+    }
+
+    /// Sets how keyword and vector search are combined when retrieving
+    /// documents for the agent's context.
+    pub fn with_retrieval_config(mut self, config: HybridConfig) -> Self {
+        self.retrieval = config;
+        self
+    }
+
+    /// Adds the top `results` documents of a collection to the agent's
+    /// context, searched with the collection's retrieval settings. Once a
+    /// collection is set, only the collections set are queried.
+    pub fn with_collection(mut self, name: impl Into<String>, results: usize) -> Self {
+        self.collections.push((name.into(), results));
+        self
+    }
+
+    /// Lets the agent call `tool`, in the channels whose settings allow it.
+    pub fn with_tool(mut self, tool: impl Tool + 'static) -> Self {
+        self.tools.push(Arc::new(SharedTool(Arc::new(tool))));
+        self
+    }
+
     pub fn builder(&self) -> AgentBuilder<M> {
-        let builder = AgentBuilder::new(self.completion_model.clone())
-            .preamble(&self.character.preamble)
-            .context(&format!("Your name: {}", self.character.name))
-            .dynamic_context(2, self.knowledge.clone().document_index());
+        self.builder_for_channel(None)
+    }
+
+    /// Like `builder`, with the persona override of the channel in place of
+    /// the character's preamble and only the tools the channel allows.
+    pub fn builder_for_channel(&self, settings: Option<&ChannelSettings>) -> AgentBuilder<M> {
+        let preamble = settings
+            .and_then(|settings| settings.persona_override.as_deref())
+            .unwrap_or(&self.character.preamble);
+
+        let filter = settings.and_then(|settings| settings.document_filter.clone());
+
+        let mut builder = AgentBuilder::new(self.completion_model.clone())
+            .preamble(preamble)
+            .context(&format!("Your name: {}", self.character.name));
+
+        for tool in &self.tools {
+            if settings.map_or(true, |settings| settings.allows_tool(tool.name())) {
+                builder = tool.add_to(builder);
+            }
+        }
+
+        if self.collections.is_empty() {
+            let mut index = self
+                .knowledge
+                .clone()
+                .hybrid_document_index(self.retrieval.clone());
+            if let Some(filter) = filter {
+                index = index.with_filter(filter);
+            }
+            builder = builder.dynamic_context(2, index);
+        } else {
+            for (name, results) in &self.collections {
+                let mut index = self.knowledge.clone().collection_index(name);
+                if let Some(filter) = &filter {
+                    // A channel filter naming a collection leaves out the others
+                    if filter.collection.as_ref().is_some_and(|only| only != name) {
+                        continue;
+                    }
+                    index = index.with_filter(DocumentFilter {
+                        collection: Some(name.clone()),
+                        ..filter.clone()
+                    });
+                }
+                builder = builder.dynamic_context(*results, index);
+            }
+        }
 
         builder
     }
@@ -33,4 +156,181 @@ impl<M: CompletionModel, E: EmbeddingModel> Agent<M, E> {
     pub fn knowledge(&self) -> &KnowledgeBase<E> {
         &self.knowledge
     }
+
+    /// Adds the latest summaries of the channel and of the author, and what is
+    /// known about the author, to the agent's context.
+    pub async fn with_memory(
+        &self,
+        mut builder: AgentBuilder<M>,
+        channel_id: &str,
+        account_id: Option<i64>,
+    ) -> AgentBuilder<M> {
+        let channel = SummaryScope::Channel(channel_id.to_string());
+        match self.knowledge.latest_summary(&channel).await {
+            Ok(Some(summary)) => {
+                builder = builder.context(&format!(
+                    "Summary of the earlier conversation in this channel:\n{}",
+                    summary.content
+                ));
+            }
+            Ok(None) => {}
+            Err(err) => error!(?err, "Failed to fetch channel summary"),
+        }
+
+        if let Some(account_id) = account_id {
+            match self
+                .knowledge
+                .latest_summary(&SummaryScope::Account(account_id))
+                .await
+            {
+                Ok(Some(summary)) => {
+                    builder = builder.context(&format!(
+                        "Summary of your earlier conversations with this user:\n{}",
+                        summary.content
+                    ));
+                }
+                Ok(None) => {}
+                Err(err) => error!(?err, "Failed to fetch account summary"),
+            }
+
+            let config = self.fact_extractor.config();
+            match self
+                .knowledge
+                .account_facts(account_id, config.min_confidence)
+                .await
+            {
+                Ok(facts) if !facts.is_empty() => {
+                    builder = builder.context(&format!(
+                        "What you know about this user:\n{}",
+                        facts
+                            .iter()
+                            .take(config.max_facts_in_context)
+                            .map(|fact| format!("- {}", fact.content))
+                            .collect::<Vec<_>>()
+                            .join("\n")
+                    ));
+                }
+                Ok(_) => {}
+                Err(err) => error!(?err, "Failed to fetch account facts"),
+            }
+        }
+
+        builder
+    }
+
+    /// Condenses older messages of the channel and of the author into new
+    /// summaries and extracts facts about the author, when enough messages
+    /// have accumulated.
+    pub async fn update_memory(&self, channel_id: &str, account_id: Option<i64>) {
+        if let Some(account_id) = account_id {
+            if let Err(err) = self.fact_extractor.extract(account_id).await {
+                error!(?err, account_id, "Failed to extract facts");
+            }
+        }
+
+        let mut scopes = vec![SummaryScope::Channel(channel_id.to_string())];
+        scopes.extend(account_id.map(SummaryScope::Account));
+
+        for scope in scopes {
+            if let Err(err) = self
+                .summarizer
+                .summarize(&scope, &self.character.name)
+                .await
+            {
+                error!(?err, scope = scope.kind(), "Failed to summarize messages");
+            }
+        }
+    }
+
+    /// Generates a title for `conversation` once it has enough messages to
+    /// tell what it is about. Conversations that already have one are left
+    /// alone.
+    pub async fn title_conversation(&self, conversation: &Conversation) {
+        if conversation.title.is_some() {
+            return;
+        }
+
+        let messages = match self
+            .knowledge
+            .conversation_messages(conversation.id, TITLE_AFTER_MESSAGES)
+            .await
+        {
+            Ok(messages) => messages,
+            Err(err) => {
+                error!(?err, "Failed to fetch conversation messages");
+                return;
+            }
+        };
+
+        if (messages.len() as i64) < TITLE_AFTER_MESSAGES {
+            return;
+        }
+
+        let prompt = format!(
+            "Give this conversation a title of at most six words. Reply with the title only.\n\n{}",
+            HistoryEntry::transcript(&messages, &self.character.name)
+        );
+        let request = self.completion_model.completion_request(&prompt).build();
+
+        let title = match self.completion_model.completion(request).await {
+            Ok(response) => match response.choice {
+                ModelChoice::Message(text) => text,
+                ModelChoice::ToolCall(_, _) => return,
+            },
+            Err(err) => {
+                error!(?err, "Failed to generate conversation title");
+                return;
+            }
+        };
+
+        let title = title.trim().trim_matches('"').trim();
+        if title.is_empty() {
+            return;
+        }
+
+        if let Err(err) = self
+            .knowledge
+            .set_conversation_title(conversation.id, title)
+            .await
+        {
+            error!(?err, "Failed to store conversation title");
+        }
+    }
+}
+
+/// A tool kept by the agent and added to each builder it creates.
+trait AgentTool<M: CompletionModel>: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn add_to(&self, builder: AgentBuilder<M>) -> AgentBuilder<M>;
+}
+
+/// Shares one tool between the builders, which each take ownership of the
+/// tools they are given.
+struct SharedTool<T>(Arc<T>);
+
+impl<T: Tool> Tool for SharedTool<T> {
+    const NAME: &'static str = T::NAME;
+
+    type Error = T::Error;
+    type Args = T::Args;
+    type Output = T::Output;
+
+    async fn definition(&self, prompt: String) -> ToolDefinition {
+        self.0.definition(prompt).await
+    }
+
+    async fn call(&self, args: Self::Args) -> Result<Self::Output, Self::Error> {
+        self.0.call(args).await
+    }
+}
+
+impl<M: CompletionModel, T: Tool + 'static> AgentTool<M> for SharedTool<T> {
+    fn name(&self) -> &'static str {
+        T::NAME
+    }
+
+    fn add_to(&self, builder: AgentBuilder<M>) -> AgentBuilder<M> {
+        builder.tool(SharedTool(self.0.clone()))
+    }
 }
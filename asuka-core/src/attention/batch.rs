@@ -0,0 +1,326 @@
+use rig::completion::CompletionModel;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tracing::debug;
+
+use super::{
+    Attention, AttentionCommand, AttentionContext, AGENT_SPEAKER, IGNORE_COMMAND, RESPOND_COMMAND,
+};
+use crate::knowledge::HistoryEntry;
+
+const MAX_CACHED_DECISIONS: usize = 1024;
+
+/// Classifications of single messages keyed by channel and normalized
+/// content, as the decision also depends on the channel's history.
+#[derive(Clone)]
+pub(super) struct DecisionCache {
+    ttl: Duration,
+    entries: Arc<Mutex<HashMap<(String, String), (AttentionCommand, Instant)>>>,
+}
+
+impl DecisionCache {
+    pub(super) fn new(ttl_secs: u64) -> Self {
+        Self {
+            ttl: Duration::from_secs(ttl_secs),
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub(super) fn get(&self, channel_id: &str, content: &str) -> Option<AttentionCommand> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(&(channel_id.to_string(), content.to_string()))
+            .filter(|(_, decided_at)| decided_at.elapsed() < self.ttl)
+            .map(|(command, _)| *command)
+    }
+
+    pub(super) fn insert(&self, channel_id: &str, content: String, command: AttentionCommand) {
+        if self.ttl.is_zero() {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() >= MAX_CACHED_DECISIONS {
+            entries.retain(|_, (_, decided_at)| decided_at.elapsed() < self.ttl);
+        }
+
+        if entries.len() >= MAX_CACHED_DECISIONS {
+            if let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, (_, decided_at))| *decided_at)
+                .map(|(key, _)| key.clone())
+            {
+                entries.remove(&oldest);
+            }
+        }
+
+        entries.insert((channel_id.to_string(), content), (command, Instant::now()));
+    }
+}
+
+struct PendingMessage {
+    content: String,
+    decision: oneshot::Sender<AttentionCommand>,
+}
+
+/// Messages waiting for a batched classification, per channel.
+#[derive(Clone, Default)]
+pub(super) struct Batcher {
+    pending: Arc<Mutex<HashMap<String, Vec<PendingMessage>>>>,
+}
+
+impl Batcher {
+    /// Queues a message. Returns whether it opened the batch, in which case
+    /// the caller schedules its classification for when the window closes.
+    fn push(
+        &self,
+        channel_id: &str,
+        content: String,
+    ) -> (bool, oneshot::Receiver<AttentionCommand>) {
+        let (decision, receiver) = oneshot::channel();
+        let mut pending = self.pending.lock().unwrap();
+        let batch = pending.entry(channel_id.to_string()).or_default();
+        let opened = batch.is_empty();
+
+        batch.push(PendingMessage { content, decision });
+
+        (opened, receiver)
+    }
+
+    fn take(&self, channel_id: &str) -> Vec<PendingMessage> {
+        self.pending
+            .lock()
+            .unwrap()
+            .remove(channel_id)
+            .unwrap_or_default()
+    }
+}
+
+impl<M: CompletionModel + 'static> Attention<M> {
+    /// Waits for the batch window of the message's channel to close and returns
+    /// the decision taken for this message.
+    pub(super) async fn classify_batched(&self, context: &AttentionContext) -> AttentionCommand {
+        let (opened, decision) = self.batcher.push(
+            &context.channel_id,
+            format!("{}: {}", context.author_name, context.message_content),
+        );
+
+        // Classified by a task of its own, as the caller that opened the batch
+        // may stop waiting for it
+        if opened {
+            let attention = self.clone();
+            let context = context.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(attention.config.batch_window_ms)).await;
+                attention.classify_batch(&context).await;
+            });
+        }
+
+        decision.await.unwrap_or(AttentionCommand::Ignore)
+    }
+
+    /// Classifies the batch `context` opened and sends every caller the
+    /// decision for its message.
+    async fn classify_batch(&self, context: &AttentionContext) {
+        let batch = self.batcher.take(&context.channel_id);
+        debug!(
+            channel_id = context.channel_id,
+            batch_size = batch.len(),
+            "Classifying message batch"
+        );
+
+        // The batch always starts with the message of the caller that opened it.
+        // Only a message classified on its own is cached: in a batch, losing
+        // to another message doesn't make it uninteresting.
+        let commands = if batch.len() == 1 {
+            let command = self.classify(context, false).await;
+            self.decision_cache.insert(
+                &context.channel_id,
+                context.message_content.to_lowercase(),
+                command,
+            );
+            vec![command]
+        } else {
+            let contents = batch
+                .iter()
+                .map(|pending| pending.content.as_str())
+                .collect::<Vec<_>>();
+            let chosen = self.choose_from_batch(context, &contents).await;
+
+            (0..batch.len())
+                .map(|index| {
+                    if chosen == Some(index) {
+                        AttentionCommand::Respond
+                    } else {
+                        AttentionCommand::Ignore
+                    }
+                })
+                .collect()
+        };
+
+        for (pending, command) in batch.into_iter().zip(commands) {
+            let _ = pending.decision.send(command);
+        }
+    }
+
+    /// Asks the LLM which message of the batch, if any, is worth a reply.
+    async fn choose_from_batch(
+        &self,
+        context: &AttentionContext,
+        contents: &[&str],
+    ) -> Option<usize> {
+        let prompt = format!(
+            "You are in a room with other users. You should only respond when addressed or when the conversation is relevant to you.\n\n\
+            Recent messages:\n{}\n\nNew messages:\n{}\n\n\
+            Reply with {RESPOND_COMMAND} followed by the number of the single message most worth answering, \
+            or {IGNORE_COMMAND} if none of them is directed at you or relevant.",
+            HistoryEntry::transcript(&context.history, AGENT_SPEAKER),
+            contents
+                .iter()
+                .enumerate()
+                .map(|(index, content)| format!("[{}] {}", index + 1, content))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        );
+
+        let text = self.complete(&prompt).await?;
+        parse_batch_choice(&text, contents.len())
+    }
+}
+
+/// Extracts the zero-based index following the respond command, e.g. "[RESPOND] 2".
+fn parse_batch_choice(text: &str, batch_size: usize) -> Option<usize> {
+    let (_, rest) = text.split_once(RESPOND_COMMAND)?;
+    let number = rest
+        .trim_start_matches(|c: char| c.is_whitespace() || c == '[' || c == '#')
+        .chars()
+        .take_while(char::is_ascii_digit)
+        .collect::<String>();
+
+    match number.parse::<usize>() {
+        Ok(choice) if (1..=batch_size).contains(&choice) => Some(choice - 1),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use rig::completion::{CompletionError, CompletionRequest, CompletionResponse, ModelChoice};
+
+    use super::*;
+    use crate::attention::AttentionConfig;
+    use crate::knowledge::{ChannelType, Source};
+
+    /// Picks the first message of a batch, and replies to any single message.
+    #[derive(Clone)]
+    struct FirstChoiceModel;
+
+    impl CompletionModel for FirstChoiceModel {
+        type Response = ();
+
+        async fn completion(
+            &self,
+            request: CompletionRequest,
+        ) -> Result<CompletionResponse<()>, CompletionError> {
+            let text = if request.prompt.contains("New messages:") {
+                format!("{RESPOND_COMMAND} 1")
+            } else {
+                RESPOND_COMMAND.to_string()
+            };
+
+            Ok(CompletionResponse {
+                choice: ModelChoice::Message(text),
+                raw_response: (),
+            })
+        }
+    }
+
+    fn context(author_name: &str, message_content: &str) -> AttentionContext {
+        AttentionContext {
+            channel_id: "general".to_string(),
+            author_name: author_name.to_string(),
+            message_content: message_content.to_string(),
+            mentioned_names: HashSet::new(),
+            history: Vec::new(),
+            channel_type: ChannelType::Text,
+            source: Source::Discord,
+            is_reply_to_agent: false,
+            thread_started_by_agent: false,
+            channel_settings: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_message_losing_its_batch_is_not_cached() {
+        let attention = Attention::new(
+            AttentionConfig {
+                batch_window_ms: 20,
+                ..Default::default()
+            },
+            FirstChoiceModel,
+        );
+        let winner = context("ada", "what is on the roadmap this week");
+        let loser = context("grace", "anyone tried the new build yet");
+
+        let (first, second) = tokio::join!(
+            attention.should_reply(&winner),
+            attention.should_reply(&loser)
+        );
+        assert_eq!(first, AttentionCommand::Respond);
+        assert_eq!(second, AttentionCommand::Ignore);
+
+        // Asked again on its own, the message is classified rather than
+        // reusing the batch outcome
+        assert_eq!(
+            attention.should_reply(&loser).await,
+            AttentionCommand::Respond
+        );
+    }
+
+    #[tokio::test]
+    async fn test_batch_outlives_the_caller_that_opened_it() {
+        let attention = Attention::new(
+            AttentionConfig {
+                batch_window_ms: 20,
+                ..Default::default()
+            },
+            FirstChoiceModel,
+        );
+        let opener = context("ada", "what is on the roadmap this week");
+        let other = context("grace", "anyone tried the new build yet");
+
+        // The caller that opened the batch gives up before the window closes
+        let abandoned =
+            tokio::time::timeout(Duration::from_millis(1), attention.should_reply(&opener));
+        assert!(abandoned.await.is_err());
+
+        let decision = tokio::time::timeout(Duration::from_secs(1), attention.should_reply(&other));
+        assert_eq!(decision.await.unwrap(), AttentionCommand::Ignore);
+    }
+
+    #[test]
+    fn test_parse_batch_choice() {
+        assert_eq!(parse_batch_choice("[RESPOND] 2", 3), Some(1));
+        assert_eq!(parse_batch_choice("[RESPOND] [3]", 3), Some(2));
+        assert_eq!(parse_batch_choice("[RESPOND] 4", 3), None);
+        assert_eq!(parse_batch_choice("[IGNORE]", 3), None);
+    }
+
+    #[test]
+    fn test_decision_cache_respects_ttl() {
+        let cache = DecisionCache::new(60);
+        cache.insert("general", "gm".to_string(), AttentionCommand::Ignore);
+        assert_eq!(cache.get("general", "gm"), Some(AttentionCommand::Ignore));
+        assert_eq!(cache.get("general", "gn"), None);
+        assert_eq!(cache.get("random", "gm"), None);
+
+        let disabled = DecisionCache::new(0);
+        disabled.insert("general", "gm".to_string(), AttentionCommand::Ignore);
+        assert_eq!(disabled.get("general", "gm"), None);
+    }
+}
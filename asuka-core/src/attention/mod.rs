@@ -0,0 +1,458 @@
+use rig::completion::{CompletionModel, ModelChoice};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use crate::knowledge::{AttentionOverrides, ChannelSettings, ChannelType, HistoryEntry, Source};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+mod batch;
+mod proactive;
+
+use batch::{Batcher, DecisionCache};
+use proactive::TopicDecisions;
+pub use proactive::{ProactiveAction, ProactiveConfig, RateLimiter};
+
+const RESPOND_COMMAND: &str = "[RESPOND]";
+const IGNORE_COMMAND: &str = "[IGNORE]";
+const STOP_COMMAND: &str = "[STOP]";
+/// How the agent's own messages are labelled in classification prompts.
+const AGENT_SPEAKER: &str = "you";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AttentionCommand {
+    Respond,
+    Ignore,
+    Stop,
+}
+
+#[derive(Debug, Clone)]
+pub struct AttentionContext {
+    pub channel_id: String,
+    pub author_name: String,
+    pub message_content: String,
+    pub mentioned_names: HashSet<String>,
+    /// Recent channel messages, oldest first.
+    pub history: Vec<HistoryEntry>,
+    pub channel_type: ChannelType,
+    pub source: Source,
+    /// The message is a direct reply to one of the agent's messages.
+    pub is_reply_to_agent: bool,
+    /// The message was posted in a thread the agent started.
+    pub thread_started_by_agent: bool,
+    pub channel_settings: Option<ChannelSettings>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AttentionConfig {
+    pub bot_names: Vec<String>,
+    pub reply_threshold: f32,
+    pub max_history_messages: i64,
+    pub cooldown_messages: i64,
+    /// Stop phrases keyed by language code. Every list is checked and phrases
+    /// only match on whole words, so "stop" doesn't fire on "unstoppable".
+    pub stop_phrases: HashMap<String, Vec<String>>,
+    /// Ask the classifier to confirm a matched stop phrase is aimed at the bot
+    /// before muting.
+    pub confirm_stop_with_llm: bool,
+    /// Only reply to direct messages, mentions and replies, never classify.
+    pub mention_only: bool,
+    /// Reply to an edited message when the edit newly mentions the bot.
+    pub reconsider_edits: bool,
+    /// Unprompted engagement in community channels.
+    pub proactive: ProactiveConfig,
+    /// How long a classification is reused for messages with the same content.
+    /// Zero disables the cache.
+    pub decision_cache_ttl_secs: u64,
+    /// Gather the messages of a channel for this long and classify them with a
+    /// single call, replying only to the most relevant one. Zero disables batching.
+    pub batch_window_ms: u64,
+}
+
+impl Default for AttentionConfig {
+    fn default() -> Self {
+        Self {
+            bot_names: vec!["shinobai".to_string(), "shinobi".to_string()],
+            reply_threshold: 0.6,
+            max_history_messages: 10,
+            cooldown_messages: 3,
+            stop_phrases: HashMap::from([(
+                "en".to_string(),
+                [
+                    "shut up",
+                    "stop",
+                    "dont talk",
+                    "silence",
+                    "be quiet",
+                    "hush",
+                    "stfu",
+                    "stupid bot",
+                    "dumb bot",
+                    "can you not",
+                ]
+                .map(String::from)
+                .to_vec(),
+            )]),
+            confirm_stop_with_llm: false,
+            mention_only: false,
+            reconsider_edits: false,
+            proactive: ProactiveConfig::default(),
+            decision_cache_ttl_secs: 300,
+            batch_window_ms: 0,
+        }
+    }
+}
+
+impl AttentionConfig {
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        info!(path = path, "Loading attention configuration");
+        let content = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&content)?)
+    }
+
+    /// Returns this configuration with a channel's overrides applied.
+    pub fn with_overrides(&self, overrides: &AttentionOverrides) -> Self {
+        let mut config = self.clone();
+
+        if let Some(bot_names) = &overrides.bot_names {
+            config.bot_names = bot_names.clone();
+        }
+        if let Some(stop_phrases) = &overrides.stop_phrases {
+            config.stop_phrases = stop_phrases.clone();
+        }
+        if let Some(confirm_stop_with_llm) = overrides.confirm_stop_with_llm {
+            config.confirm_stop_with_llm = confirm_stop_with_llm;
+        }
+        if let Some(mention_only) = overrides.mention_only {
+            config.mention_only = mention_only;
+        }
+
+        config
+    }
+
+    /// Whether one of the bot's names is among `mentioned_names` or appears in
+    /// `content`.
+    pub fn mentions_bot(&self, content: &str, mentioned_names: &HashSet<String>) -> bool {
+        let content = content.to_lowercase();
+
+        self.bot_names
+            .iter()
+            .any(|name| mentioned_names.contains(name) || content.contains(&name.to_lowercase()))
+    }
+
+    /// Returns the first configured stop phrase found in `content`, if any.
+    pub fn matching_stop_phrase(&self, content: &str) -> Option<&str> {
+        let words = normalized_words(content);
+
+        self.stop_phrases
+            .values()
+            .flatten()
+            .find(|phrase| {
+                let phrase_words = normalized_words(phrase);
+                !phrase_words.is_empty()
+                    && words
+                        .windows(phrase_words.len())
+                        .any(|window| window == phrase_words.as_slice())
+            })
+            .map(String::as_str)
+    }
+}
+
+/// Splits text into lowercase words, dropping apostrophes so "don't" and
+/// "dont" compare equal.
+fn normalized_words(text: &str) -> Vec<String> {
+    text.to_lowercase()
+        .replace(['\'', '\u{2019}'], "")
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+#[derive(Clone)]
+pub struct Attention<M: CompletionModel> {
+    config: AttentionConfig,
+    completion_model: M,
+    rate_limiter: RateLimiter,
+    decision_cache: DecisionCache,
+    batcher: Batcher,
+    topic_decisions: TopicDecisions,
+}
+
+impl<M: CompletionModel + 'static> Attention<M> {
+    pub fn new(config: AttentionConfig, completion_model: M) -> Self {
+        let decision_cache = DecisionCache::new(config.decision_cache_ttl_secs);
+
+        Self {
+            config,
+            completion_model,
+            rate_limiter: RateLimiter::default(),
+            decision_cache,
+            batcher: Batcher::default(),
+            topic_decisions: TopicDecisions::default(),
+        }
+    }
+
+    pub fn config(&self) -> &AttentionConfig {
+        &self.config
+    }
+
+    /// Decides whether to reply to a message that was edited from
+    /// `previous_content`. Only edits that newly mention the bot are
+    /// reconsidered, and only when `reconsider_edits` is set.
+    pub fn should_reply_to_edit(
+        &self,
+        context: &AttentionContext,
+        previous_content: &str,
+        previously_mentioned: &HashSet<String>,
+    ) -> bool {
+        if !self.config.reconsider_edits {
+            return false;
+        }
+
+        let config = match &context.channel_settings {
+            Some(settings) if !settings.enabled => return false,
+            Some(settings) => Cow::Owned(self.config.with_overrides(&settings.attention)),
+            None => Cow::Borrowed(&self.config),
+        };
+
+        config.mentions_bot(&context.message_content, &context.mentioned_names)
+            && !config.mentions_bot(previous_content, previously_mentioned)
+    }
+
+    pub async fn should_reply(&self, context: &AttentionContext) -> AttentionCommand {
+        let content = context.message_content.to_lowercase();
+
+        let config = match &context.channel_settings {
+            Some(settings) if !settings.enabled => {
+                debug!(channel_id = context.channel_id, "Channel is disabled");
+                return AttentionCommand::Ignore;
+            }
+            Some(settings) => Cow::Owned(self.config.with_overrides(&settings.attention)),
+            None => Cow::Borrowed(&self.config),
+        };
+
+        // Always reply to DMs
+        if context.channel_type == ChannelType::DirectMessage {
+            return AttentionCommand::Respond;
+        }
+
+        // Check for mentions or name references
+        if config.mentions_bot(&content, &context.mentioned_names) {
+            debug!("Bot name was mentioned, will reply");
+            return AttentionCommand::Respond;
+        }
+
+        // Check for stop/disengage phrases
+        let stop_phrase = config.matching_stop_phrase(&content);
+        if let Some(phrase) = stop_phrase {
+            debug!(phrase = phrase, "Message contains a stop phrase");
+
+            // Mention-only channels are never classified
+            if !config.confirm_stop_with_llm || config.mention_only {
+                return AttentionCommand::Stop;
+            }
+        }
+
+        // Replies to the agent are addressed to it, no need to classify them
+        if stop_phrase.is_none() && (context.is_reply_to_agent || context.thread_started_by_agent) {
+            debug!(
+                is_reply_to_agent = context.is_reply_to_agent,
+                thread_started_by_agent = context.thread_started_by_agent,
+                "Message is addressed to the bot, will reply"
+            );
+            return AttentionCommand::Respond;
+        }
+
+        // Ignore very short messages, and anything else in mention-only channels
+        if stop_phrase.is_none() && (config.mention_only || content.len() < 4) {
+            return AttentionCommand::Ignore;
+        }
+
+        // Confirming a stop phrase depends on the message itself, so it is
+        // neither batched nor cached
+        if stop_phrase.is_some() {
+            return self.classify(context, true).await;
+        }
+
+        if let Some(command) = self.decision_cache.get(&context.channel_id, &content) {
+            debug!(?command, "Reusing cached attention decision");
+            return command;
+        }
+
+        if self.config.batch_window_ms > 0 {
+            return self.classify_batched(context).await;
+        }
+
+        let command = self.classify(context, false).await;
+        self.decision_cache
+            .insert(&context.channel_id, content, command);
+        command
+    }
+
+    /// Uses the LLM to decide if we should respond to a single message.
+    async fn classify(
+        &self,
+        context: &AttentionContext,
+        contains_stop_phrase: bool,
+    ) -> AttentionCommand {
+        let prompt = format!(
+            "You are in a room with other users. You should only respond when addressed or when the conversation is relevant to you.\n\n\
+            Response options:\n\
+            {RESPOND_COMMAND} - Message is directed at you or conversation is relevant\n\
+            {IGNORE_COMMAND} - Message is not interesting or not directed at you\n\
+            {STOP_COMMAND} - User wants you to stop or conversation has concluded\n\n\
+            Recent messages:\n{}\n\nLatest message from {}: {}\n\n{}\
+            Choose one response option:",
+            HistoryEntry::transcript(&context.history, AGENT_SPEAKER),
+            context.author_name,
+            context.message_content,
+            if contains_stop_phrase {
+                format!(
+                    "The latest message contains a phrase that can mean \"stop\". \
+                    Only choose {STOP_COMMAND} if it is aimed at you.\n\n"
+                )
+            } else {
+                String::new()
+            }
+        );
+
+        match self.complete(&prompt).await {
+            Some(text) => {
+                if text.contains(RESPOND_COMMAND) {
+                    AttentionCommand::Respond
+                } else if text.contains(STOP_COMMAND) {
+                    AttentionCommand::Stop
+                } else {
+                    AttentionCommand::Ignore
+                }
+            }
+            None => AttentionCommand::Ignore,
+        }
+    }
+
+    async fn complete(&self, prompt: &str) -> Option<String> {
+        let builder = self.completion_model.completion_request(prompt);
+
+        match self.completion_model.completion(builder.build()).await {
+            Ok(response) => match response.choice {
+                ModelChoice::Message(text) => Some(text),
+                ModelChoice::ToolCall(_, _) => None,
+            },
+            Err(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rig::completion::{CompletionError, CompletionRequest, CompletionResponse};
+
+    use super::*;
+
+    /// Replies to everything, so a classification shows up in the outcome.
+    #[derive(Clone)]
+    struct RespondingModel;
+
+    impl CompletionModel for RespondingModel {
+        type Response = ();
+
+        async fn completion(
+            &self,
+            _request: CompletionRequest,
+        ) -> Result<CompletionResponse<()>, CompletionError> {
+            Ok(CompletionResponse {
+                choice: ModelChoice::Message(RESPOND_COMMAND.to_string()),
+                raw_response: (),
+            })
+        }
+    }
+
+    #[test]
+    fn test_stop_phrase_matches_whole_words() {
+        let config = AttentionConfig::default();
+        assert_eq!(config.matching_stop_phrase("please STOP"), Some("stop"));
+        assert_eq!(
+            config.matching_stop_phrase("this team is unstoppable"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_stop_phrase_matches_multi_word_phrases() {
+        let config = AttentionConfig::default();
+        assert_eq!(
+            config.matching_stop_phrase("ok bot, don't talk for a bit"),
+            Some("dont talk")
+        );
+        assert_eq!(config.matching_stop_phrase("shutup"), None);
+    }
+
+    #[test]
+    fn test_stop_phrase_checks_every_language() {
+        let mut config = AttentionConfig::default();
+        config
+            .stop_phrases
+            .insert("fr".to_string(), vec!["tais-toi".to_string()]);
+        assert_eq!(config.matching_stop_phrase("Tais-toi !"), Some("tais-toi"));
+    }
+
+    #[test]
+    fn test_channel_overrides_replace_set_fields_only() {
+        let config = AttentionConfig::default();
+        let overrides = AttentionOverrides {
+            stop_phrases: Some(HashMap::new()),
+            mention_only: Some(true),
+            ..Default::default()
+        };
+
+        let channel_config = config.with_overrides(&overrides);
+        assert!(channel_config.mention_only);
+        assert_eq!(channel_config.matching_stop_phrase("please stop"), None);
+        assert_eq!(channel_config.bot_names, config.bot_names);
+    }
+
+    #[tokio::test]
+    async fn test_mention_only_channel_does_not_confirm_stop_phrases() {
+        let attention = Attention::new(
+            AttentionConfig {
+                confirm_stop_with_llm: true,
+                ..Default::default()
+            },
+            RespondingModel,
+        );
+        let context = AttentionContext {
+            channel_id: "general".to_string(),
+            author_name: "ada".to_string(),
+            message_content: "please stop".to_string(),
+            mentioned_names: HashSet::new(),
+            history: Vec::new(),
+            channel_type: ChannelType::Text,
+            source: Source::Discord,
+            is_reply_to_agent: false,
+            thread_started_by_agent: false,
+            channel_settings: Some(ChannelSettings {
+                attention: AttentionOverrides {
+                    mention_only: Some(true),
+                    ..Default::default()
+                },
+                ..ChannelSettings::new("general")
+            }),
+        };
+
+        assert_eq!(
+            attention.should_reply(&context).await,
+            AttentionCommand::Stop
+        );
+    }
+
+    #[test]
+    fn test_mentions_bot_checks_names_and_content() {
+        let config = AttentionConfig::default();
+        let mentioned = HashSet::from(["shinobi".to_string()]);
+        assert!(config.mentions_bot("<@123> any news?", &mentioned));
+        assert!(config.mentions_bot("hey Shinobi, any news?", &HashSet::new()));
+        assert!(!config.mentions_bot("any news?", &HashSet::new()));
+    }
+}
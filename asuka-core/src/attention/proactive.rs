@@ -0,0 +1,299 @@
+use chrono::{DateTime, Duration, Utc};
+use rig::completion::CompletionModel;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tracing::debug;
+
+use super::{Attention, IGNORE_COMMAND, RESPOND_COMMAND};
+use crate::knowledge::HistoryEntry;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProactiveConfig {
+    /// Proactive engagement is opt-in.
+    pub enabled: bool,
+    /// Channels the agent may post in without being addressed.
+    pub channels: Vec<String>,
+    /// Topics an unanswered question has to relate to. Empty matches any question.
+    pub topics: Vec<String>,
+    /// How long a question has to go unanswered before the agent picks it up.
+    pub unanswered_after_minutes: i64,
+    /// Greet members joining the server in its system channel.
+    pub welcome_new_members: bool,
+    /// Post in a channel once it has been quiet this long. Disabled when unset.
+    pub revive_after_minutes: Option<i64>,
+    /// Maximum number of unprompted messages per channel per hour.
+    pub max_messages_per_hour: usize,
+    /// How often watched channels are checked.
+    pub check_interval_secs: u64,
+}
+
+impl Default for ProactiveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            channels: Vec::new(),
+            topics: Vec::new(),
+            unanswered_after_minutes: 10,
+            welcome_new_members: false,
+            revive_after_minutes: None,
+            max_messages_per_hour: 2,
+            check_interval_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ProactiveAction {
+    /// Answer a question nobody picked up.
+    AnswerQuestion { message_id: String, content: String },
+    /// Restart the conversation in a quiet channel.
+    Revive,
+}
+
+/// Sliding one hour window of unprompted messages sent per channel.
+#[derive(Clone, Debug, Default)]
+pub struct RateLimiter {
+    sent: Arc<Mutex<HashMap<String, VecDeque<DateTime<Utc>>>>>,
+}
+
+impl RateLimiter {
+    /// Records a message for `channel_id` if it still has room in the window.
+    pub fn try_acquire(&self, channel_id: &str, max_per_hour: usize, now: DateTime<Utc>) -> bool {
+        let mut sent = self.sent.lock().unwrap();
+        let window = sent.entry(channel_id.to_string()).or_default();
+
+        while window
+            .front()
+            .is_some_and(|sent_at| now - *sent_at >= Duration::hours(1))
+        {
+            window.pop_front();
+        }
+
+        if window.len() >= max_per_hour {
+            return false;
+        }
+
+        window.push_back(now);
+        true
+    }
+}
+
+/// Whether the last unanswered question of each channel is about the agent's
+/// topics, so a question is only classified once however long it stays
+/// unanswered.
+#[derive(Clone, Debug, Default)]
+pub(super) struct TopicDecisions {
+    decisions: Arc<Mutex<HashMap<String, (String, bool)>>>,
+}
+
+impl TopicDecisions {
+    fn get(&self, channel_id: &str, message_id: &str) -> Option<bool> {
+        self.decisions
+            .lock()
+            .unwrap()
+            .get(channel_id)
+            .filter(|(decided_id, _)| decided_id == message_id)
+            .map(|(_, matches)| *matches)
+    }
+
+    fn insert(&self, channel_id: &str, message_id: &str, matches: bool) {
+        self.decisions
+            .lock()
+            .unwrap()
+            .insert(channel_id.to_string(), (message_id.to_string(), matches));
+    }
+}
+
+impl<M: CompletionModel> Attention<M> {
+    pub fn is_proactive_channel(&self, channel_id: &str) -> bool {
+        let proactive = &self.config.proactive;
+        proactive.enabled && proactive.channels.iter().any(|id| id == channel_id)
+    }
+
+    /// Decides whether the agent should post unprompted in a watched channel.
+    /// `history` holds the channel's most recent messages, oldest first.
+    pub async fn proactive_action(
+        &self,
+        channel_id: &str,
+        history: &[HistoryEntry],
+        now: DateTime<Utc>,
+    ) -> Option<ProactiveAction> {
+        if !self.is_proactive_channel(channel_id) {
+            return None;
+        }
+
+        let proactive = &self.config.proactive;
+        let last = history.last()?;
+        let idle = now - last.created_at;
+
+        // The agent already had the last word
+        if last.is_from_agent() {
+            return None;
+        }
+
+        let action = if last.content.trim_end().ends_with('?')
+            && idle >= Duration::minutes(proactive.unanswered_after_minutes)
+            && self.question_matches_topics(channel_id, last).await
+        {
+            ProactiveAction::AnswerQuestion {
+                message_id: last.message_id.clone(),
+                content: last.content.clone(),
+            }
+        } else if proactive
+            .revive_after_minutes
+            .is_some_and(|minutes| idle >= Duration::minutes(minutes))
+        {
+            ProactiveAction::Revive
+        } else {
+            return None;
+        };
+
+        if !self.take_proactive_slot(channel_id, now) {
+            return None;
+        }
+
+        debug!(channel_id = channel_id, ?action, "Engaging proactively");
+        Some(action)
+    }
+
+    /// Whether the agent should greet a member who joined and posts in `channel_id`.
+    pub fn should_welcome(&self, channel_id: &str) -> bool {
+        self.config.proactive.welcome_new_members
+            && self.is_proactive_channel(channel_id)
+            && self.take_proactive_slot(channel_id, Utc::now())
+    }
+
+    fn take_proactive_slot(&self, channel_id: &str, now: DateTime<Utc>) -> bool {
+        let allowed = self.rate_limiter.try_acquire(
+            channel_id,
+            self.config.proactive.max_messages_per_hour,
+            now,
+        );
+
+        if !allowed {
+            debug!(channel_id = channel_id, "Proactive rate limit reached");
+        }
+
+        allowed
+    }
+
+    async fn question_matches_topics(&self, channel_id: &str, question: &HistoryEntry) -> bool {
+        if let Some(matches) = self.topic_decisions.get(channel_id, &question.message_id) {
+            return matches;
+        }
+
+        let matches = self.matches_topics(&question.content).await;
+        self.topic_decisions
+            .insert(channel_id, &question.message_id, matches);
+        matches
+    }
+
+    async fn matches_topics(&self, question: &str) -> bool {
+        let topics = &self.config.proactive.topics;
+        if topics.is_empty() {
+            return true;
+        }
+
+        let prompt = format!(
+            "Your topics of expertise are:\n{}\n\n\
+            Question: {question}\n\n\
+            Response options:\n\
+            {RESPOND_COMMAND} - The question is about one of your topics\n\
+            {IGNORE_COMMAND} - The question is about something else\n\n\
+            Choose one response option:",
+            topics
+                .iter()
+                .map(|topic| format!("- {}", topic))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        );
+
+        self.complete(&prompt)
+            .await
+            .is_some_and(|text| text.contains(RESPOND_COMMAND))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use rig::completion::{CompletionError, CompletionRequest, CompletionResponse, ModelChoice};
+
+    use super::*;
+    use crate::attention::AttentionConfig;
+
+    /// Finds every question on topic and counts how often it is asked.
+    #[derive(Clone, Default)]
+    struct CountingModel {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl CompletionModel for CountingModel {
+        type Response = ();
+
+        async fn completion(
+            &self,
+            _request: CompletionRequest,
+        ) -> Result<CompletionResponse<()>, CompletionError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+
+            Ok(CompletionResponse {
+                choice: ModelChoice::Message(RESPOND_COMMAND.to_string()),
+                raw_response: (),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_question_is_classified_once() {
+        let model = CountingModel::default();
+        let attention = Attention::new(
+            AttentionConfig {
+                proactive: ProactiveConfig {
+                    enabled: true,
+                    channels: vec!["general".to_string()],
+                    topics: vec!["starknet".to_string()],
+                    max_messages_per_hour: 1,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            model.clone(),
+        );
+        let now = Utc::now();
+        let history = vec![HistoryEntry {
+            message_id: "1".to_string(),
+            account_id: "ada".to_string(),
+            speaker: "ada".to_string(),
+            role: "user".to_string(),
+            content: "how do I deploy a contract?".to_string(),
+            created_at: now - Duration::minutes(30),
+        }];
+
+        assert!(attention
+            .proactive_action("general", &history, now)
+            .await
+            .is_some());
+        // Checked again while the rate limit holds the answer back
+        assert!(attention
+            .proactive_action("general", &history, now + Duration::minutes(1))
+            .await
+            .is_none());
+        assert_eq!(model.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_rate_limiter_caps_messages_per_hour() {
+        let limiter = RateLimiter::default();
+        let now = Utc::now();
+
+        assert!(limiter.try_acquire("general", 2, now));
+        assert!(limiter.try_acquire("general", 2, now + Duration::minutes(1)));
+        assert!(!limiter.try_acquire("general", 2, now + Duration::minutes(2)));
+        assert!(limiter.try_acquire("random", 2, now + Duration::minutes(2)));
+        assert!(limiter.try_acquire("general", 2, now + Duration::minutes(61)));
+    }
+}
@@ -0,0 +1,465 @@
+//! Queries and inspects a knowledge base database, e.g. to debug retrieval:
+//!
+//! ```text
+//! asuka-kb --db-path asuka.db query "How do I request randomness?"
+//! asuka-kb --db-path asuka.db history general --limit 20
+//! asuka-kb --db-path asuka.db stats
+//! ```
+
+use std::path::PathBuf;
+
+use anyhow::{bail, Context};
+use clap::{Parser, Subcommand, ValueEnum};
+use rig::embeddings::EmbeddingModel;
+use rig::providers::openai;
+use sqlite_vec::sqlite3_vec_init;
+use tokio_rusqlite::ffi::sqlite3_auto_extension;
+use tokio_rusqlite::Connection;
+
+use asuka_core::knowledge::{
+    delete_cached_embeddings, delete_documents, fts_query, migrations, EmbeddingModelRecord,
+    HashingEmbeddingModel, RelationalStore, Source, SqliteStorage, VectorStore,
+};
+
+/// Characters of content shown per row in listings.
+const PREVIEW_CHARS: usize = 100;
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Path to the knowledge base database
+    #[arg(long, env = "ASUKA_DB_PATH")]
+    db_path: PathBuf,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List stored documents with their number of chunks
+    Documents {
+        #[arg(long)]
+        collection: Option<String>,
+        #[arg(long)]
+        source_id: Option<String>,
+        #[arg(long, default_value_t = 50)]
+        limit: i64,
+    },
+    /// List the latest messages, newest first
+    Messages {
+        #[arg(long)]
+        channel: Option<String>,
+        #[arg(long, default_value_t = 50)]
+        limit: i64,
+    },
+    /// Full-text search of document chunks or messages
+    Search {
+        query: String,
+        #[arg(long, value_enum, default_value_t = Table::Documents)]
+        table: Table,
+        #[arg(long, default_value_t = 10)]
+        limit: i64,
+    },
+    /// Nearest neighbours of a text by vector distance, embedded with the
+    /// model recorded for the table
+    Query {
+        text: String,
+        #[arg(long, value_enum, default_value_t = Table::Documents)]
+        table: Table,
+        #[arg(long, default_value_t = 10)]
+        limit: usize,
+        /// Needed when the table was embedded with an OpenAI model
+        #[arg(long, env = "OPENAI_API_KEY")]
+        openai_api_key: Option<String>,
+    },
+    /// Show the history of a channel, oldest first
+    History {
+        channel_id: String,
+        #[arg(long, default_value_t = 50)]
+        limit: i64,
+    },
+    /// Delete an entry along with its embeddings
+    Delete {
+        #[command(subcommand)]
+        entry: Entry,
+    },
+    /// Print the schema version and table definitions
+    Schema,
+    /// Print row counts, embedding models and the database size
+    Stats,
+}
+
+#[derive(Subcommand)]
+enum Entry {
+    /// Every chunk of a document
+    Document { id: String },
+    /// A message and its revisions
+    Message { source: String, id: String },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Table {
+    Documents,
+    Messages,
+    Summaries,
+}
+
+impl Table {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Table::Documents => "documents",
+            Table::Messages => "messages",
+            Table::Summaries => "summaries",
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    dotenv::dotenv().ok();
+    let args = Args::parse();
+
+    // Opening a missing path would create an empty database
+    if !args.db_path.exists() {
+        bail!("No database at {}", args.db_path.display());
+    }
+
+    unsafe {
+        sqlite3_auto_extension(Some(std::mem::transmute(sqlite3_vec_init as *const ())));
+    }
+    let conn = Connection::open(&args.db_path).await?;
+
+    match args.command {
+        Command::Documents {
+            collection,
+            source_id,
+            limit,
+        } => {
+            let rows = query_rows(
+                &conn,
+                "SELECT parent_id, collection, source_id, COUNT(*)
+                 FROM documents
+                 WHERE (?1 IS NULL OR collection = ?1) AND (?2 IS NULL OR source_id = ?2)
+                 GROUP BY parent_id
+                 ORDER BY parent_id
+                 LIMIT ?3",
+                vec![collection.into(), source_id.into(), limit.into()],
+            )
+            .await?;
+            for row in rows {
+                println!("{}\t{}\t{}\t{} chunks", row[0], row[1], row[2], row[3]);
+            }
+        }
+        Command::Messages { channel, limit } => {
+            let rows = query_rows(
+                &conn,
+                "SELECT created_at, source, channel_id, id, account_id, content
+                 FROM messages
+                 WHERE ?1 IS NULL OR channel_id = ?1
+                 ORDER BY created_at DESC
+                 LIMIT ?2",
+                vec![channel.into(), limit.into()],
+            )
+            .await?;
+            for row in rows {
+                println!(
+                    "{}\t{}:{}\t{}\t{}\t{}",
+                    row[0],
+                    row[1],
+                    row[2],
+                    row[3],
+                    row[4],
+                    preview(&row[5])
+                );
+            }
+        }
+        Command::Search {
+            query,
+            table,
+            limit,
+        } => {
+            if let Table::Summaries = table {
+                bail!("Summaries have no full-text index, use `query` instead");
+            }
+            let Some(query) = fts_query(&query) else {
+                bail!("Nothing to search for in {query:?}");
+            };
+            let table = table.as_str();
+            let rows = query_rows(
+                &conn,
+                &format!(
+                    "SELECT t.id, bm25({table}_fts), t.content
+                     FROM {table}_fts
+                     JOIN {table} t ON t.rowid = {table}_fts.rowid
+                     WHERE {table}_fts MATCH ?1
+                     ORDER BY bm25({table}_fts)
+                     LIMIT ?2"
+                ),
+                vec![query.into(), limit.into()],
+            )
+            .await?;
+            for row in rows {
+                println!("{}\t{}\t{}", row[0], row[1], preview(&row[2]));
+            }
+        }
+        Command::Query {
+            text,
+            table,
+            limit,
+            openai_api_key,
+        } => {
+            let table = table.as_str();
+            let record = embedding_model(&conn, table)
+                .await?
+                .with_context(|| format!("No embedding model recorded for {table}"))?;
+            let vector = embed_query(&record, &text, openai_api_key.as_deref()).await?;
+
+            let storage = SqliteStorage::new(conn.clone());
+            for (key, distance) in storage.nearest(table, vector, limit, None).await? {
+                let row = query_rows(
+                    &conn,
+                    &format!("SELECT id, content FROM {table} WHERE rowid = CAST(?1 AS INTEGER)"),
+                    vec![key.as_str().to_string().into()],
+                )
+                .await?;
+                if let Some(row) = row.first() {
+                    println!("{distance:.4}\t{}\t{}", row[0], preview(&row[1]));
+                }
+            }
+        }
+        Command::History { channel_id, limit } => {
+            let storage = SqliteStorage::new(conn);
+            for entry in storage.channel_history(&channel_id, limit).await? {
+                println!(
+                    "{}\t{} ({})\t{}",
+                    entry.created_at.to_rfc3339(),
+                    entry.speaker,
+                    entry.role,
+                    entry.content
+                );
+            }
+        }
+        Command::Delete { entry } => {
+            let deleted = delete(&conn, entry).await?;
+            println!("Deleted {deleted} rows");
+        }
+        Command::Schema => {
+            let version = conn
+                .call(|conn| {
+                    migrations::current_version(conn)
+                        .map_err(|e| tokio_rusqlite::Error::Other(Box::new(e)))
+                })
+                .await?;
+            println!(
+                "-- Schema version {version} (latest {})",
+                migrations::latest_version()
+            );
+
+            let rows = query_rows(
+                &conn,
+                "SELECT sql FROM sqlite_master
+                 WHERE sql IS NOT NULL AND name NOT LIKE 'sqlite_%'
+                 ORDER BY tbl_name, type DESC, name",
+                vec![],
+            )
+            .await?;
+            for row in rows {
+                println!("{};\n", row[0]);
+            }
+        }
+        Command::Stats => stats(&conn).await?,
+    }
+
+    Ok(())
+}
+
+/// Runs a query, returning its rows with every value rendered as text.
+async fn query_rows(
+    conn: &Connection,
+    sql: &str,
+    params: Vec<rusqlite::types::Value>,
+) -> anyhow::Result<Vec<Vec<String>>> {
+    let sql = sql.to_string();
+
+    Ok(conn
+        .call(move |conn| {
+            let mut stmt = conn.prepare(&sql)?;
+            let columns = stmt.column_count();
+
+            let rows = stmt
+                .query_map(rusqlite::params_from_iter(params), |row| {
+                    (0..columns)
+                        .map(|index| Ok(display_value(row.get_ref(index)?)))
+                        .collect::<Result<Vec<_>, _>>()
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Ok(rows)
+        })
+        .await?)
+}
+
+fn display_value(value: rusqlite::types::ValueRef) -> String {
+    use rusqlite::types::ValueRef;
+
+    match value {
+        ValueRef::Null => "NULL".to_string(),
+        ValueRef::Integer(i) => i.to_string(),
+        ValueRef::Real(f) => format!("{f:.4}"),
+        ValueRef::Text(text) => String::from_utf8_lossy(text).to_string(),
+        ValueRef::Blob(blob) => format!("<{} bytes>", blob.len()),
+    }
+}
+
+/// First line of `content`, cut to `PREVIEW_CHARS`.
+fn preview(content: &str) -> String {
+    let line = content
+        .lines()
+        .find(|line| !line.trim().is_empty())
+        .unwrap_or("");
+    let mut preview: String = line.chars().take(PREVIEW_CHARS).collect();
+    if preview.len() < content.trim().len() {
+        preview.push('…');
+    }
+    preview
+}
+
+async fn embedding_model(
+    conn: &Connection,
+    table: &'static str,
+) -> anyhow::Result<Option<EmbeddingModelRecord>> {
+    Ok(conn
+        .call(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT table_name, model, dimensions FROM embedding_models WHERE table_name = ?1",
+            )?;
+            let mut rows = stmt.query_map([table], |row| EmbeddingModelRecord::try_from(row))?;
+
+            Ok(rows.next().transpose()?)
+        })
+        .await?)
+}
+
+/// Embeds the query with the model recorded for the table, local or OpenAI.
+async fn embed_query(
+    record: &EmbeddingModelRecord,
+    text: &str,
+    openai_api_key: Option<&str>,
+) -> anyhow::Result<Vec<f64>> {
+    let texts = vec![text.to_string()];
+    let embeddings = if record.model == HashingEmbeddingModel::NAME {
+        HashingEmbeddingModel::new(record.dimensions as usize)
+            .embed_texts(texts)
+            .await?
+    } else {
+        let Some(api_key) = openai_api_key else {
+            bail!(
+                "{} is embedded with {}, set OPENAI_API_KEY to query it",
+                record.table_name,
+                record.model
+            );
+        };
+        openai::Client::new(api_key)
+            .embedding_model(&record.model)
+            .embed_texts(texts)
+            .await?
+    };
+
+    embeddings
+        .into_iter()
+        .next()
+        .map(|embedding| embedding.vec)
+        .context("No embedding returned for the query")
+}
+
+async fn delete(conn: &Connection, entry: Entry) -> anyhow::Result<usize> {
+    let storage = SqliteStorage::new(conn.clone());
+    let deleted = match entry {
+        Entry::Document { id } => {
+            conn.call(move |conn| {
+                let tx = conn.transaction()?;
+                let deleted = delete_documents(&tx, "parent_id = ?1", rusqlite::params![id])?;
+                tx.commit()?;
+                Ok(deleted)
+            })
+            .await?
+        }
+        Entry::Message { source, id } => {
+            let source =
+                Source::from_str(&source).with_context(|| format!("Unknown source {source}"))?;
+            storage
+                .delete_message(source, &id)
+                .await?
+                .into_iter()
+                .map(|row| ("messages".to_string(), row))
+                .collect()
+        }
+    };
+
+    let count = deleted.len();
+    for (table, row) in deleted {
+        storage.remove_vectors(&table, vec![row.key]).await?;
+        conn.call(move |conn| Ok(delete_cached_embeddings(conn, &row.contents)?))
+            .await?;
+    }
+
+    Ok(count)
+}
+
+async fn stats(conn: &Connection) -> anyhow::Result<()> {
+    // Virtual tables and their shadow tables are counted through the
+    // embeddings below
+    let tables = query_rows(
+        conn,
+        "SELECT name FROM pragma_table_list
+         WHERE schema = 'main' AND type = 'table'
+           AND name NOT LIKE 'sqlite\\_%' ESCAPE '\\'
+           AND name NOT LIKE '%\\_embeddings\\_%' ESCAPE '\\'
+         ORDER BY name",
+        vec![],
+    )
+    .await?;
+
+    println!("Rows");
+    for table in tables {
+        let count = query_rows(
+            conn,
+            &format!("SELECT COUNT(*) FROM \"{}\"", table[0]),
+            vec![],
+        )
+        .await?;
+        println!("  {:<24}{}", table[0], count[0][0]);
+    }
+
+    println!("Embeddings");
+    for table in [Table::Documents, Table::Messages, Table::Summaries] {
+        let table = table.as_str();
+        let count = query_rows(
+            conn,
+            &format!("SELECT COUNT(*) FROM {table}_embeddings"),
+            vec![],
+        )
+        .await
+        .map(|rows| rows[0][0].clone())
+        .unwrap_or_else(|_| "-".to_string());
+        let model = match embedding_model(conn, table).await? {
+            Some(record) => format!("{} ({} dimensions)", record.model, record.dimensions),
+            None => "no model recorded".to_string(),
+        };
+        println!("  {table:<24}{count}\t{model}");
+    }
+
+    let size = query_rows(
+        conn,
+        "SELECT page_count * page_size, freelist_count * page_size
+         FROM pragma_page_count, pragma_page_size, pragma_freelist_count",
+        vec![],
+    )
+    .await?;
+    println!("Database size");
+    println!("  {:<24}{} bytes", "total", size[0][0]);
+    println!("  {:<24}{} bytes", "free", size[0][1]);
+
+    Ok(())
+}
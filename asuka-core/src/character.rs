@@ -8,7 +8,8 @@ pub struct Character {
     // pub lore: Vec<String>,
     // pub message_examples: Vec<Vec<Message>>,
     // pub post_examples: Vec<String>,
-    // pub topics: Vec<String>,
+    #[serde(default)]
+    pub topics: Vec<String>,
     // pub style: Style,
     // pub adjectives: Vec<String>,
 }
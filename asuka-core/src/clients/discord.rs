@@ -3,39 +3,67 @@ use rig::{
     embeddings::EmbeddingModel,
 };
 use serenity::async_trait;
-use serenity::model::channel::Message;
+use serenity::http::Http;
+use serenity::model::channel::{Channel, Message};
+use serenity::model::event::MessageUpdateEvent;
 use serenity::model::gateway::GatewayIntents;
 use serenity::model::gateway::Ready;
+use serenity::model::guild::Member;
+use serenity::model::id::{ChannelId, GuildId, MessageId};
 use serenity::prelude::*;
 use std::collections::HashSet;
-use tracing::{debug, error, info};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tracing::{debug, error, info, warn};
 
-use crate::{agent::Agent, attention::AttentionCommand};
+use crate::{
+    agent::Agent,
+    attention::{AttentionCommand, ProactiveAction},
+    clients::handle_link_command,
+};
 use crate::{
     attention::{Attention, AttentionContext},
-    knowledge,
+    knowledge::{self, ChannelMetadata, ChannelSettings, ConversationHints, HistoryEntry},
 };
 
 const MIN_CHUNK_LENGTH: usize = 100;
 const MAX_MESSAGE_LENGTH: usize = 1500;
 const MAX_HISTORY_MESSAGES: i64 = 10;
 
+/// The message a reply answers.
+struct ReplyTo<'a> {
+    message_id: &'a str,
+    account_id: Option<i64>,
+}
+
 #[derive(Clone)]
 pub struct DiscordClient<M: CompletionModel, E: EmbeddingModel + 'static> {
     agent: Agent<M, E>,
     attention: Attention<M>,
+    proactive_started: Arc<AtomicBool>,
 }
 
 impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> DiscordClient<M, E> {
     pub fn new(agent: Agent<M, E>, attention: Attention<M>) -> Self {
-        Self { agent, attention }
+        Self {
+            agent,
+            attention,
+            proactive_started: Arc::new(AtomicBool::new(false)),
+        }
     }
 
     pub async fn start(&self, token: &str) -> Result<(), serenity::Error> {
-        let intents = GatewayIntents::GUILD_MESSAGES
+        // Guilds fill the cache that resolves channel and guild names
+        let mut intents = GatewayIntents::GUILDS
+            | GatewayIntents::GUILD_MESSAGES
             | GatewayIntents::DIRECT_MESSAGES
             | GatewayIntents::MESSAGE_CONTENT;
 
+        // Welcoming members needs the privileged members intent
+        if self.attention.config().proactive.welcome_new_members {
+            intents |= GatewayIntents::GUILD_MEMBERS;
+        }
+
         let mut client = Client::builder(token, intents)
             .event_handler(self.clone())
             .await?;
@@ -43,6 +71,241 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> DiscordClient<M,
         info!("Starting discord bot");
         client.start().await
     }
+
+    /// Sends `response` in chunks and stores the sent messages as the agent's,
+    /// in the conversation of the message they answer if any.
+    async fn send_response(
+        &self,
+        http: &Http,
+        channel_id: ChannelId,
+        response: &str,
+        in_reply_to: Option<&str>,
+    ) {
+        let chunks = chunk_message(response, MAX_MESSAGE_LENGTH, MIN_CHUNK_LENGTH);
+
+        for chunk in chunks {
+            match channel_id.say(http, chunk).await {
+                Ok(sent) => {
+                    let knowledge = self.agent.knowledge();
+                    let author_name = sent.author.display_name().to_string();
+                    let mut knowledge_msg = knowledge::Message::from(sent);
+                    knowledge_msg.role = "assistant".to_string();
+
+                    if let Err(err) = knowledge
+                        .upsert_account(
+                            knowledge::Source::Discord,
+                            &knowledge_msg.account_id,
+                            &author_name,
+                        )
+                        .await
+                    {
+                        error!(?err, "Failed to register agent account");
+                    }
+
+                    if let Err(err) = knowledge.create_message(knowledge_msg.clone()).await {
+                        error!(?err, "Failed to store sent message");
+                        continue;
+                    }
+
+                    let hints = ConversationHints {
+                        reply_to: in_reply_to.map(String::from),
+                        thread_id: None,
+                    };
+                    if let Err(err) = knowledge.assign_conversation(&knowledge_msg, hints).await {
+                        error!(?err, "Failed to assign sent message to a conversation");
+                    }
+                }
+                Err(why) => error!(?why, "Failed to send message"),
+            }
+        }
+    }
+
+    async fn prompt_and_send(
+        &self,
+        http: &Http,
+        channel_id: ChannelId,
+        prompt: &str,
+        history: &[HistoryEntry],
+        settings: Option<&ChannelSettings>,
+        reply_to: Option<ReplyTo<'_>>,
+    ) {
+        let mut builder = self
+            .agent
+            .builder_for_channel(settings)
+            .context(&format!(
+                "Current time: {}",
+                chrono::Local::now().format("%I:%M:%S %p, %Y-%m-%d")
+            ))
+            .context("Please keep your responses concise and under 2000 characters when possible.");
+
+        if !history.is_empty() {
+            builder = builder.context(&format!(
+                "Recent conversation:\n{}",
+                HistoryEntry::transcript(history, &self.agent.character.name)
+            ));
+        }
+
+        let builder = self
+            .agent
+            .with_memory(
+                builder,
+                &channel_id.to_string(),
+                reply_to.as_ref().and_then(|reply_to| reply_to.account_id),
+            )
+            .await;
+        let agent = builder.build();
+
+        let response = match agent.prompt(prompt).await {
+            Ok(response) => response,
+            Err(err) => {
+                error!(?err, "Failed to generate response");
+                return;
+            }
+        };
+
+        debug!(response = %response, "Generated response");
+
+        self.send_response(
+            http,
+            channel_id,
+            &response,
+            reply_to.map(|reply_to| reply_to.message_id),
+        )
+        .await;
+    }
+
+    async fn channel_settings(&self, channel_id: &str) -> Option<ChannelSettings> {
+        match self.agent.knowledge().channel_settings(channel_id).await {
+            Ok(settings) => settings,
+            Err(err) => {
+                error!(?err, "Failed to fetch channel settings");
+                None
+            }
+        }
+    }
+
+    /// Removes messages deleted on Discord from the knowledge base.
+    async fn forget_messages(&self, message_ids: &[MessageId]) {
+        for message_id in message_ids {
+            if let Err(err) = self
+                .agent
+                .knowledge()
+                .delete_message(knowledge::Source::Discord, &message_id.to_string())
+                .await
+            {
+                error!(?err, %message_id, "Failed to delete message");
+            }
+        }
+    }
+
+    /// Periodically checks the watched channels for unanswered questions and
+    /// quiet periods.
+    async fn run_proactive(&self, http: Arc<Http>) {
+        let config = self.attention.config().clone();
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(
+            config.proactive.check_interval_secs,
+        ));
+
+        loop {
+            interval.tick().await;
+
+            for channel in &config.proactive.channels {
+                let Ok(channel_id) = channel.parse::<u64>() else {
+                    warn!(channel = channel, "Invalid proactive channel id");
+                    continue;
+                };
+
+                let settings = self.channel_settings(channel).await;
+                if settings.as_ref().is_some_and(|settings| !settings.enabled) {
+                    continue;
+                }
+
+                let history = match self
+                    .agent
+                    .knowledge()
+                    .channel_messages(channel, config.max_history_messages)
+                    .await
+                {
+                    Ok(messages) => messages,
+                    Err(err) => {
+                        error!(?err, "Failed to fetch recent messages");
+                        continue;
+                    }
+                };
+
+                let prompt = match self
+                    .attention
+                    .proactive_action(channel, &history, chrono::Utc::now())
+                    .await
+                {
+                    Some(ProactiveAction::AnswerQuestion { content, .. }) => format!(
+                        "This question was asked in the channel a while ago and nobody \
+                        has answered it yet. Answer it if you can help:\n\n{content}"
+                    ),
+                    Some(ProactiveAction::Revive) => format!(
+                        "The channel has been quiet for a while. Post one short message \
+                        to get a conversation going about one of your topics: {}",
+                        config.proactive.topics.join(", ")
+                    ),
+                    None => continue,
+                };
+
+                self.prompt_and_send(
+                    &http,
+                    ChannelId::new(channel_id),
+                    &prompt,
+                    &history,
+                    settings.as_ref(),
+                    None,
+                )
+                .await;
+            }
+        }
+    }
+}
+
+/// Describes the channel of a message, `channel` being `None` when it couldn't
+/// be fetched.
+fn channel_metadata(
+    ctx: &Context,
+    msg: &knowledge::Message,
+    channel: Option<&Channel>,
+) -> ChannelMetadata {
+    let mut metadata = ChannelMetadata {
+        channel_id: msg.channel_id.clone(),
+        channel_type: msg.channel_type.clone(),
+        source: knowledge::Source::Discord,
+        name: None,
+        guild_id: None,
+        guild_name: None,
+        topic: None,
+    };
+
+    match channel {
+        Some(Channel::Guild(channel)) => {
+            metadata.channel_type = if channel.thread_metadata.is_some() {
+                knowledge::ChannelType::Thread
+            } else if matches!(
+                channel.kind,
+                serenity::model::channel::ChannelType::Voice
+                    | serenity::model::channel::ChannelType::Stage
+            ) {
+                knowledge::ChannelType::Voice
+            } else {
+                knowledge::ChannelType::Text
+            };
+            metadata.name = Some(channel.name.clone());
+            metadata.guild_id = Some(channel.guild_id.to_string());
+            metadata.guild_name = channel.guild_id.name(&ctx.cache);
+            metadata.topic = channel.topic.clone();
+        }
+        Some(Channel::Private(channel)) => {
+            metadata.name = Some(channel.recipient.display_name().to_string());
+        }
+        _ => {}
+    }
+
+    metadata
 }
 
 impl From<Message> for knowledge::Message {
@@ -77,6 +340,34 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> EventHandler
         let knowledge = self.agent.knowledge();
         let knowledge_msg = knowledge::Message::from(msg.clone());
 
+        let account = match knowledge
+            .upsert_account(
+                knowledge::Source::Discord,
+                &knowledge_msg.account_id,
+                msg.author.display_name(),
+            )
+            .await
+        {
+            Ok(account) => Some(account),
+            Err(err) => {
+                error!(?err, "Failed to register message author");
+                None
+            }
+        };
+
+        // Link codes are kept out of the knowledge base
+        if let Some(account) = account
+            .as_ref()
+            .filter(|_| knowledge_msg.channel_type == knowledge::ChannelType::DirectMessage)
+        {
+            if let Some(reply) = handle_link_command(knowledge, account, &msg.content).await {
+                if let Err(why) = msg.channel_id.say(&ctx.http, reply).await {
+                    error!(?why, "Failed to send link reply");
+                }
+                return;
+            }
+        }
+
         if let Err(err) = knowledge
             .clone()
             .create_message(knowledge_msg.clone())
@@ -108,12 +399,69 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> EventHandler
             "Mentioned names in message"
         );
 
+        let channel = msg.channel(&ctx).await.ok();
+        if let Err(err) = knowledge
+            .upsert_channel(channel_metadata(&ctx, &knowledge_msg, channel.as_ref()))
+            .await
+        {
+            error!(?err, "Failed to record channel");
+        }
+
+        // Discord threads are channels of their own, so they already group
+        // their messages
+        let hints = ConversationHints {
+            reply_to: msg
+                .referenced_message
+                .as_ref()
+                .map(|parent| parent.id.to_string()),
+            thread_id: match &channel {
+                Some(Channel::Guild(channel)) if channel.thread_metadata.is_some() => {
+                    Some(channel.id.to_string())
+                }
+                _ => None,
+            },
+        };
+        let conversation = match knowledge.assign_conversation(&knowledge_msg, hints).await {
+            Ok(conversation) => {
+                let agent = self.agent.clone();
+                let titled = conversation.clone();
+                tokio::spawn(async move { agent.title_conversation(&titled).await });
+                Some(conversation)
+            }
+            Err(err) => {
+                error!(?err, "Failed to assign message to a conversation");
+                None
+            }
+        };
+
+        let agent = self.agent.clone();
+        let channel_id = knowledge_msg.channel_id.clone();
+        let account_id = account.as_ref().map(|account| account.id);
+        tokio::spawn(async move { agent.update_memory(&channel_id, account_id).await });
+
+        let bot_id = ctx.cache.current_user().id;
+        let is_reply_to_agent = msg
+            .referenced_message
+            .as_ref()
+            .is_some_and(|parent| parent.author.id == bot_id);
+        let thread_started_by_agent = match &channel {
+            Some(Channel::Guild(channel)) => {
+                channel.thread_metadata.is_some() && channel.owner_id == Some(bot_id)
+            }
+            _ => false,
+        };
+
         let context = AttentionContext {
+            channel_id: msg.channel_id.to_string(),
+            author_name: msg.author.display_name().to_string(),
             message_content: msg.content.clone(),
             mentioned_names,
             history,
             channel_type: knowledge_msg.channel_type,
             source: knowledge_msg.source,
+            is_reply_to_agent,
+            thread_started_by_agent,
+            channel_settings: self.channel_settings(&msg.channel_id.to_string()).await,
         };
 
         debug!(?context, "Attention context");
@@ -126,38 +474,206 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> EventHandler
             }
         }
 
-        let agent = self
-            .agent
-            .builder()
-            .context(&format!(
-                "Current time: {}",
-                chrono::Local::now().format("%I:%M:%S %p, %Y-%m-%d")
-            ))
-            .context("Please keep your responses concise and under 2000 characters when possible.")
-            .build();
+        // Reply within the current conversation rather than the whole channel
+        let reply_history = match &conversation {
+            Some(conversation) => match knowledge
+                .conversation_messages(conversation.id, MAX_HISTORY_MESSAGES)
+                .await
+            {
+                Ok(messages) => messages,
+                Err(err) => {
+                    error!(?err, "Failed to fetch conversation messages");
+                    context.history.clone()
+                }
+            },
+            None => context.history.clone(),
+        };
 
-        let response = match agent.prompt(&msg.content).await {
-            Ok(response) => response,
+        self.prompt_and_send(
+            &ctx.http,
+            msg.channel_id,
+            &msg.content,
+            &reply_history,
+            context.channel_settings.as_ref(),
+            Some(ReplyTo {
+                message_id: &knowledge_msg.id,
+                account_id: account.as_ref().map(|account| account.id),
+            }),
+        )
+        .await;
+    }
+
+    async fn message_update(
+        &self,
+        ctx: Context,
+        _old_if_available: Option<Message>,
+        _new: Option<Message>,
+        event: MessageUpdateEvent,
+    ) {
+        // Updates without content only touch embeds, pins and the like
+        let (Some(content), Some(author)) = (event.content.as_deref(), event.author.as_ref())
+        else {
+            return;
+        };
+        if author.bot {
+            return;
+        }
+
+        let knowledge = self.agent.knowledge();
+        let revision = match knowledge
+            .edit_message(knowledge::Source::Discord, &event.id.to_string(), content)
+            .await
+        {
+            Ok(Some(revision)) => revision,
+            Ok(None) => return,
             Err(err) => {
-                error!(?err, "Failed to generate response");
+                error!(?err, "Failed to update edited message");
                 return;
             }
         };
 
-        debug!(response = %response, "Generated response");
+        if !self.attention.config().reconsider_edits {
+            return;
+        }
 
-        let chunks = chunk_message(&response, MAX_MESSAGE_LENGTH, MIN_CHUNK_LENGTH);
+        let channel_id = event.channel_id.to_string();
+        let history = match knowledge
+            .channel_messages(&channel_id, MAX_HISTORY_MESSAGES)
+            .await
+        {
+            Ok(messages) => messages,
+            Err(err) => {
+                error!(?err, "Failed to fetch recent messages");
+                return;
+            }
+        };
 
-        for chunk in chunks {
-            if let Err(why) = msg.channel_id.say(&ctx.http, chunk).await {
-                error!(?why, "Failed to send message");
+        // Discord only lists the mentions of the current content, an earlier
+        // mention of the bot shows up as `<@id>` in the previous one
+        let (bot_id, bot_name) = {
+            let bot = ctx.cache.current_user();
+            (bot.id, bot.name.clone())
+        };
+        let previously_mentioned: HashSet<String> =
+            [format!("<@{bot_id}>"), format!("<@!{bot_id}>")]
+                .iter()
+                .any(|mention| revision.content.contains(mention))
+                .then_some(bot_name)
+                .into_iter()
+                .collect();
+
+        let context = AttentionContext {
+            channel_id: channel_id.clone(),
+            author_name: author.display_name().to_string(),
+            message_content: content.to_string(),
+            mentioned_names: event
+                .mentions
+                .iter()
+                .flatten()
+                .map(|user| user.name.clone())
+                .collect(),
+            history,
+            channel_type: if event.guild_id.is_none() {
+                knowledge::ChannelType::DirectMessage
+            } else {
+                knowledge::ChannelType::Text
+            },
+            source: knowledge::Source::Discord,
+            is_reply_to_agent: false,
+            thread_started_by_agent: false,
+            channel_settings: self.channel_settings(&channel_id).await,
+        };
+
+        if !self
+            .attention
+            .should_reply_to_edit(&context, &revision.content, &previously_mentioned)
+        {
+            return;
+        }
+
+        let account_id = match knowledge
+            .get_account(knowledge::Source::Discord, &author.id.to_string())
+            .await
+        {
+            Ok(account) => account.map(|account| account.id),
+            Err(err) => {
+                error!(?err, "Failed to fetch message author");
+                None
             }
+        };
+
+        self.prompt_and_send(
+            &ctx.http,
+            event.channel_id,
+            content,
+            &context.history,
+            context.channel_settings.as_ref(),
+            Some(ReplyTo {
+                message_id: &revision.message_id,
+                account_id,
+            }),
+        )
+        .await;
+    }
+
+    async fn message_delete(
+        &self,
+        _ctx: Context,
+        _channel_id: ChannelId,
+        deleted_message_id: MessageId,
+        _guild_id: Option<GuildId>,
+    ) {
+        self.forget_messages(&[deleted_message_id]).await;
+    }
+
+    async fn message_delete_bulk(
+        &self,
+        _ctx: Context,
+        _channel_id: ChannelId,
+        multiple_deleted_messages_ids: Vec<MessageId>,
+        _guild_id: Option<GuildId>,
+    ) {
+        self.forget_messages(&multiple_deleted_messages_ids).await;
+    }
+
+    async fn guild_member_addition(&self, ctx: Context, new_member: Member) {
+        let Some(channel_id) = ctx
+            .cache
+            .guild(new_member.guild_id)
+            .and_then(|guild| guild.system_channel_id)
+        else {
+            return;
+        };
+
+        if !self.attention.should_welcome(&channel_id.to_string()) {
+            return;
         }
+
+        let settings = self.channel_settings(&channel_id.to_string()).await;
+        if settings.as_ref().is_some_and(|settings| !settings.enabled) {
+            return;
+        }
+
+        let prompt = format!(
+            "{} just joined the server. Welcome them in one or two sentences.",
+            new_member.display_name()
+        );
+
+        self.prompt_and_send(&ctx.http, channel_id, &prompt, &[], settings.as_ref(), None)
+            .await;
     }
 
-    async fn ready(&self, _: Context, ready: Ready) {
+    async fn ready(&self, ctx: Context, ready: Ready) {
         info!(name = self.agent.character.name, "Bot connected");
         info!(guild_count = ready.guilds.len(), "Serving guilds");
+
+        // `ready` fires again on reconnects, only start watching once
+        if self.attention.config().proactive.enabled
+            && !self.proactive_started.swap(true, Ordering::SeqCst)
+        {
+            let client = self.clone();
+            tokio::spawn(async move { client.run_proactive(ctx.http).await });
+        }
     }
 }
 
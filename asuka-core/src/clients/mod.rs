@@ -1,3 +1,73 @@
 pub mod discord;
 pub mod telegram;
 pub mod twitter;
+
+use rig::embeddings::EmbeddingModel;
+use std::time::Duration;
+use tracing::error;
+
+use crate::knowledge::{Account, KnowledgeBase};
+
+const LINK_COMMAND: &str = "!link";
+const LINK_CODE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Handles the account linking command: `!link` issues a code to the author,
+/// `!link <code>` confirms one issued to their account on another platform.
+///
+/// Returns the reply to send, or `None` when `content` is not a link command.
+/// Codes must only be exchanged in direct messages.
+pub(crate) async fn handle_link_command<E: EmbeddingModel + 'static>(
+    knowledge: &KnowledgeBase<E>,
+    account: &Account,
+    content: &str,
+) -> Option<String> {
+    let reply = match parse_link_command(content)? {
+        None => match knowledge.create_link_code(account.id, LINK_CODE_TTL).await {
+            Ok(code) => format!(
+                "Send `{} {}` to me from your other account within {} minutes to link them.",
+                LINK_COMMAND,
+                code,
+                LINK_CODE_TTL.as_secs() / 60
+            ),
+            Err(err) => {
+                error!(?err, "Failed to create link code");
+                "Sorry, I couldn't create a link code right now.".to_string()
+            }
+        },
+        Some(code) => match knowledge.confirm_link_code(code, account.id).await {
+            Ok(Some(_)) => "Your accounts are now linked.".to_string(),
+            Ok(None) => "That code is invalid or has expired.".to_string(),
+            Err(err) => {
+                error!(?err, "Failed to confirm link code");
+                "Sorry, I couldn't link your accounts right now.".to_string()
+            }
+        },
+    };
+
+    Some(reply)
+}
+
+/// Returns `None` when `content` is not a link command, and the code given
+/// to confirm otherwise.
+fn parse_link_command(content: &str) -> Option<Option<&str>> {
+    let mut words = content.split_whitespace();
+
+    if words.next() != Some(LINK_COMMAND) {
+        return None;
+    }
+
+    Some(words.next())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_link_command() {
+        assert_eq!(parse_link_command("!link"), Some(None));
+        assert_eq!(parse_link_command("  !link  K7Q2XP "), Some(Some("K7Q2XP")));
+        assert_eq!(parse_link_command("!linked"), None);
+        assert_eq!(parse_link_command("please !link"), None);
+    }
+}
@@ -8,13 +8,14 @@ use teloxide::{
     dispatching::UpdateFilterExt,
     dptree,
     prelude::{LoggingErrorHandler, Requester},
+    types::{ChatId, MessageId},
 };
 use tracing::{debug, error, info};
 
-use crate::{agent::Agent, attention::AttentionCommand};
+use crate::{agent::Agent, attention::AttentionCommand, clients::handle_link_command};
 use crate::{
     attention::{Attention, AttentionContext},
-    knowledge,
+    knowledge::{self, ChannelSettings, HistoryEntry},
 };
 
 const MAX_HISTORY_MESSAGES: i64 = 10;
@@ -49,7 +50,7 @@ impl From<teloxide::types::Message> for knowledge::Message {
         let user_id_num = msg.from.clone().map(|u| u.id.0).unwrap_or_default();
 
         Self {
-            id: msg.id.to_string(),
+            id: message_id(msg.chat.id, msg.id),
             source: knowledge::Source::Telegram,
             source_id: user_id.clone(),
             channel_type: if msg.chat.id.0 == user_id_num as i64 {
@@ -71,6 +72,9 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> TelegramClient<M
         let knowledge = self.agent.knowledge().clone();
         let attention = self.attention.clone();
         let agent = self.agent.clone();
+        let edit_attention = self.attention.clone();
+        let edit_agent = self.agent.clone();
+        let bot_id = bot.get_me().await?.id;
 
         let handler = dptree::entry()
             .branch(teloxide::types::Update::filter_message().endpoint(move |bot: teloxide::Bot, msg: teloxide::types::Message| {
@@ -81,11 +85,81 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> TelegramClient<M
                 async move {
                     let knowledge_msg = knowledge::Message::from(msg.clone());
 
+                    let account = match &msg.from {
+                        Some(user) => match knowledge
+                            .upsert_account(knowledge::Source::Telegram, &knowledge_msg.account_id, &user.full_name())
+                            .await
+                        {
+                            Ok(account) => Some(account),
+                            Err(err) => {
+                                error!(?err, "Failed to register message author");
+                                None
+                            }
+                        },
+                        None => None,
+                    };
+
+                    // Link codes are kept out of the knowledge base
+                    if let Some(account) = account
+                        .as_ref()
+                        .filter(|_| knowledge_msg.channel_type == knowledge::ChannelType::DirectMessage)
+                    {
+                        if let Some(reply) = handle_link_command(&knowledge, account, msg.text().unwrap_or_default()).await {
+                            if let Err(why) = bot.send_message(msg.chat.id, reply).await {
+                                error!(?why, "Failed to send link reply");
+                            }
+                            return Ok(());
+                        }
+                    }
+
                     if let Err(err) = knowledge.create_message(knowledge_msg.clone()).await {
                         error!(?err, "Failed to store message");
                         return Err(anyhow::anyhow!(err));
                     }
 
+                    let hints = knowledge::ConversationHints {
+                        reply_to: msg.reply_to_message().map(|reply| message_id(reply.chat.id, reply.id)),
+                        thread_id: msg.thread_id.map(|thread| message_id(msg.chat.id, thread.0)),
+                    };
+                    let conversation = match knowledge.assign_conversation(&knowledge_msg, hints).await {
+                        Ok(conversation) => {
+                            let agent = agent.clone();
+                            let titled = conversation.clone();
+                            tokio::spawn(async move { agent.title_conversation(&titled).await });
+                            Some(conversation)
+                        }
+                        Err(err) => {
+                            error!(?err, "Failed to assign message to a conversation");
+                            None
+                        }
+                    };
+
+                    let account_id = account.as_ref().map(|account| account.id);
+                    let memory_agent = agent.clone();
+                    let channel_id = knowledge_msg.channel_id.clone();
+                    tokio::spawn(async move { memory_agent.update_memory(&channel_id, account_id).await });
+
+                    let channel = knowledge::ChannelMetadata {
+                        channel_id: knowledge_msg.channel_id.clone(),
+                        channel_type: knowledge_msg.channel_type.clone(),
+                        source: knowledge::Source::Telegram,
+                        name: msg.chat.title().or(msg.chat.username()).map(String::from),
+                        guild_id: None,
+                        guild_name: None,
+                        topic: None,
+                    };
+                    if let Err(err) = knowledge.upsert_channel(channel).await {
+                        error!(?err, "Failed to record channel");
+                    }
+
+                    let channel_settings = match knowledge.channel_settings(&knowledge_msg.channel_id).await {
+                        Ok(settings) => settings,
+                        Err(err) => {
+                            error!(?err, "Failed to fetch channel settings");
+                            None
+                        }
+                    };
+
                     debug!("Fetching message history for channel {}", msg.chat.id);
                     let history = match knowledge
                         .channel_messages(&msg.chat.id.to_string(), MAX_HISTORY_MESSAGES)
@@ -101,31 +175,38 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> TelegramClient<M
                         }
                     };
 
-                    let mentioned_names: HashSet<String> = msg.text()
-                        .map(|text| {
-                            text.split_whitespace()
-                                .filter_map(|word| {
-                                    if word.starts_with('@') {
-                                        Some(word[1..].to_string())
-                                    } else {
-                                        None
-                                    }
-                                })
-                                .collect()
-                        })
-                        .unwrap_or_default();
+                    let mentioned_names = mentioned_names(msg.text().unwrap_or_default());
 
                     debug!(
                         mentioned_names = ?mentioned_names,
                         "Mentioned names in message"
                     );
 
+                    // Messages in a forum topic reply to the topic's creation message
+                    // unless they explicitly reply to something else
+                    let (is_reply_to_agent, thread_started_by_agent) = match msg.reply_to_message() {
+                        Some(reply) => {
+                            let from_agent = reply.from.as_ref().is_some_and(|user| user.id == bot_id);
+                            if reply.forum_topic_created().is_some() {
+                                (false, from_agent)
+                            } else {
+                                (from_agent, false)
+                            }
+                        }
+                        None => (false, false),
+                    };
+
                     let context = AttentionContext {
+                        channel_id: msg.chat.id.to_string(),
+                        author_name: msg.from.as_ref().map(|user| user.full_name()).unwrap_or_default(),
                         message_content: msg.text().unwrap_or_default().to_string(),
                         mentioned_names,
                         history,
                         channel_type: knowledge_msg.channel_type,
                         source: knowledge_msg.source,
+                        is_reply_to_agent,
+                        thread_started_by_agent,
+                        channel_settings,
                     };
 
                     debug!(?context, "Attention context");
@@ -138,44 +219,225 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> TelegramClient<M
                         }
                     }
 
-                    let agent = agent
-                        .builder()
-                        .context(&format!(
-                            "Current time: {}",
-                            chrono::Local::now().format("%I:%M:%S %p, %Y-%m-%d")
-                        ))
-                        .context("Please keep your responses concise and under 2000 characters when possible.")
-                        .build();
-
-                    let response = match agent.prompt(msg.text().unwrap_or_default()).await {
-                        Ok(response) => response,
+                    // Reply within the current conversation rather than the whole chat
+                    let reply_history = match &conversation {
+                        Some(conversation) => match knowledge
+                            .conversation_messages(conversation.id, MAX_HISTORY_MESSAGES)
+                            .await
+                        {
+                            Ok(messages) => messages,
+                            Err(err) => {
+                                error!(?err, "Failed to fetch conversation messages");
+                                context.history.clone()
+                            }
+                        },
+                        None => context.history.clone(),
+                    };
+
+                    respond(
+                        &agent,
+                        &bot,
+                        &msg,
+                        &reply_history,
+                        context.channel_settings.as_ref(),
+                        account_id,
+                    )
+                    .await
+                }
+            }))
+            .branch(teloxide::types::Update::filter_edited_message().endpoint(move |bot: teloxide::Bot, msg: teloxide::types::Message| {
+                let attention = edit_attention.clone();
+                let agent = edit_agent.clone();
+
+                async move {
+                    let Some(content) = msg.text() else {
+                        return Ok(());
+                    };
+
+                    let knowledge = agent.knowledge();
+                    let revision = match knowledge
+                        .edit_message(knowledge::Source::Telegram, &message_id(msg.chat.id, msg.id), content)
+                        .await
+                    {
+                        Ok(Some(revision)) => revision,
+                        Ok(None) => return Ok(()),
+                        Err(err) => {
+                            error!(?err, "Failed to update edited message");
+                            return Err(err);
+                        }
+                    };
+
+                    if !attention.config().reconsider_edits {
+                        return Ok(());
+                    }
+
+                    let knowledge_msg = knowledge::Message::from(msg.clone());
+                    let channel_settings = match knowledge.channel_settings(&knowledge_msg.channel_id).await {
+                        Ok(settings) => settings,
                         Err(err) => {
-                            error!(?err, "Failed to generate response");
+                            error!(?err, "Failed to fetch channel settings");
+                            None
+                        }
+                    };
+                    let history = match knowledge
+                        .channel_messages(&knowledge_msg.channel_id, MAX_HISTORY_MESSAGES)
+                        .await
+                    {
+                        Ok(messages) => messages,
+                        Err(err) => {
+                            error!(?err, "Failed to fetch recent messages");
                             return Err(anyhow::anyhow!(err));
                         }
                     };
 
-                    debug!(response = %response, "Generated response");
+                    let context = AttentionContext {
+                        channel_id: knowledge_msg.channel_id.clone(),
+                        author_name: msg.from.as_ref().map(|user| user.full_name()).unwrap_or_default(),
+                        message_content: content.to_string(),
+                        mentioned_names: mentioned_names(content),
+                        history,
+                        channel_type: knowledge_msg.channel_type,
+                        source: knowledge_msg.source,
+                        is_reply_to_agent: false,
+                        thread_started_by_agent: false,
+                        channel_settings,
+                    };
 
-                    if let Err(why) = bot.send_message(msg.chat.id, response).await {
-                        error!(?why, "Failed to send message");
-                        return Err(anyhow::anyhow!(why));
+                    if !attention.should_reply_to_edit(&context, &revision.content, &mentioned_names(&revision.content)) {
+                        return Ok(());
                     }
 
-                    Ok(())
+                    let account_id = match knowledge
+                        .get_account(knowledge::Source::Telegram, &knowledge_msg.account_id)
+                        .await
+                    {
+                        Ok(account) => account.map(|account| account.id),
+                        Err(err) => {
+                            error!(?err, "Failed to fetch message author");
+                            None
+                        }
+                    };
+
+                    respond(
+                        &agent,
+                        &bot,
+                        &msg,
+                        &context.history,
+                        context.channel_settings.as_ref(),
+                        account_id,
+                    )
+                    .await
                 }
             }));
 
         let listener = teloxide::update_listeners::polling_default(bot.clone()).await;
+        let error_handler =
+            LoggingErrorHandler::with_custom_text("Failed to process Telegram update");
 
-        teloxide::dispatching::Dispatcher::builder(bot, handler)
-            .build()
-            .dispatch_with_listener(
-                listener,
-                LoggingErrorHandler::with_custom_text("Failed to process Telegram update"),
-            )
-            .await;
+        // Updates of a chat are handled one at a time by default, which would
+        // keep attention batches from ever holding more than one message
+        if self.attention.config().batch_window_ms > 0 {
+            teloxide::dispatching::Dispatcher::builder(bot, handler)
+                .distribution_function(|_| None::<std::convert::Infallible>)
+                .build()
+                .dispatch_with_listener(listener, error_handler)
+                .await;
+        } else {
+            teloxide::dispatching::Dispatcher::builder(bot, handler)
+                .build()
+                .dispatch_with_listener(listener, error_handler)
+                .await;
+        }
 
         Ok(())
     }
 }
+
+/// Telegram message IDs are only unique within a chat, stored messages and
+/// the threads they start are identified by both.
+fn message_id(chat_id: ChatId, id: MessageId) -> String {
+    format!("{chat_id}:{id}")
+}
+
+/// Usernames mentioned with `@` in a message.
+fn mentioned_names(text: &str) -> HashSet<String> {
+    text.split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(String::from)
+        .collect()
+}
+
+/// Prompts the agent with `msg` and sends the response to its chat, keeping
+/// the response in the same conversation.
+async fn respond<M: CompletionModel + 'static, E: EmbeddingModel + 'static>(
+    agent: &Agent<M, E>,
+    bot: &teloxide::Bot,
+    msg: &teloxide::types::Message,
+    history: &[HistoryEntry],
+    settings: Option<&ChannelSettings>,
+    account_id: Option<i64>,
+) -> Result<()> {
+    let knowledge = agent.knowledge();
+    let agent_name = agent.character.name.clone();
+    let builder = agent
+        .builder_for_channel(settings)
+        .context(&format!(
+            "Current time: {}",
+            chrono::Local::now().format("%I:%M:%S %p, %Y-%m-%d")
+        ))
+        .context("Please keep your responses concise and under 2000 characters when possible.")
+        .context(&format!(
+            "Recent conversation:\n{}",
+            HistoryEntry::transcript(history, &agent_name)
+        ));
+    let agent = agent
+        .with_memory(builder, &msg.chat.id.to_string(), account_id)
+        .await
+        .build();
+
+    let response = match agent.prompt(msg.text().unwrap_or_default()).await {
+        Ok(response) => response,
+        Err(err) => {
+            error!(?err, "Failed to generate response");
+            return Err(anyhow::anyhow!(err));
+        }
+    };
+
+    debug!(response = %response, "Generated response");
+
+    let sent = match bot.send_message(msg.chat.id, response).await {
+        Ok(sent) => sent,
+        Err(why) => {
+            error!(?why, "Failed to send message");
+            return Err(anyhow::anyhow!(why));
+        }
+    };
+
+    let agent_account = sent.from.as_ref().map(|user| user.full_name());
+    let mut sent_msg = knowledge::Message::from(sent);
+    sent_msg.role = "assistant".to_string();
+
+    if let Some(name) = agent_account {
+        if let Err(err) = knowledge
+            .upsert_account(knowledge::Source::Telegram, &sent_msg.account_id, &name)
+            .await
+        {
+            error!(?err, "Failed to register agent account");
+        }
+    }
+
+    if let Err(err) = knowledge.create_message(sent_msg.clone()).await {
+        error!(?err, "Failed to store sent message");
+        return Ok(());
+    }
+
+    let hints = knowledge::ConversationHints {
+        reply_to: Some(message_id(msg.chat.id, msg.id)),
+        thread_id: None,
+    };
+    if let Err(err) = knowledge.assign_conversation(&sent_msg, hints).await {
+        error!(?err, "Failed to assign sent message to a conversation");
+    }
+
+    Ok(())
+}
@@ -1,21 +1,31 @@
 use crate::{
     agent::Agent,
     attention::{Attention, AttentionCommand, AttentionContext},
-    knowledge::{ChannelType, Message, Source},
+    knowledge::{ChannelType, ConversationHints, HistoryEntry, Message, Source},
 };
 
 use rig::{
     completion::{CompletionModel, Prompt},
     embeddings::EmbeddingModel,
 };
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use tracing::{debug, error, info};
 use twitter::{authorization::Authorization, TwitterApi};
 use twitter_v2::{self as twitter, authorization::{BearerToken, Oauth1aToken}};
-use twitter_v2::data::ReferencedTweetKind;
+use twitter_v2::data::{Expansions, ReferencedTweetKind};
+use twitter_v2::{
+    id::NumericId,
+    query::{TweetExpansion, TweetField},
+};
 
 const MAX_TWEET_LENGTH: usize = 280;
 const MAX_HISTORY_TWEETS: i64 = 10;
+const TWEET_FIELDS: [TweetField; 4] = [
+    TweetField::AuthorId,
+    TweetField::ConversationId,
+    TweetField::CreatedAt,
+    TweetField::ReferencedTweets,
+];
 
 #[derive(Clone)]
 pub struct TwitterClient<M: CompletionModel, E: EmbeddingModel + 'static, A: Authorization> {
@@ -24,12 +34,29 @@ pub struct TwitterClient<M: CompletionModel, E: EmbeddingModel + 'static, A: Aut
     api: TwitterApi<A>,
 }
 
+fn tweet_created_at(tweet: &twitter::Tweet) -> chrono::DateTime<chrono::Utc> {
+    tweet
+        .created_at
+        .map(|t| chrono::DateTime::from_timestamp(t.unix_timestamp(), 0).unwrap_or_default())
+        .unwrap_or_default()
+}
+
+/// Collects the usernames of the users expanded in an API response.
+fn expanded_usernames(includes: Option<&Expansions>) -> HashMap<NumericId, String> {
+    includes
+        .and_then(|includes| includes.users.as_ref())
+        .map(|users| {
+            users
+                .iter()
+                .map(|user| (user.id, user.username.clone()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 impl From<twitter::Tweet> for Message {
     fn from(tweet: twitter::Tweet) -> Self {
-        let created_at = tweet
-            .created_at
-            .map(|t| chrono::DateTime::from_timestamp(t.unix_timestamp(), 0).unwrap_or_default())
-            .unwrap_or_default();
+        let created_at = tweet_created_at(&tweet);
 
         Self {
             id: tweet.id.to_string(),
@@ -91,11 +118,14 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static, A: Authorization
                 .api
                 .get_user_mentions(user_id)
                 .max_results(5)
+                .tweet_fields(TWEET_FIELDS)
+                .expansions([TweetExpansion::AuthorId])
                 .send()
                 .await?;
+            let authors = expanded_usernames(mentions.includes.as_ref());
 
             for tweet in mentions.data.clone().unwrap_or_default() {
-                self.handle_mention(tweet).await?;
+                self.handle_mention(tweet, user_id, authors.clone()).await?;
             }
 
             tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
@@ -105,6 +135,8 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static, A: Authorization
     async fn handle_mention(
         &self,
         tweet: twitter::Tweet,
+        user_id: NumericId,
+        mut usernames: HashMap<NumericId, String>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let knowledge = self.agent.knowledge();
         let knowledge_msg = Message::from(tweet.clone());
@@ -114,7 +146,46 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static, A: Authorization
             return Ok(());
         }
 
-        let thread = self.build_conversation_thread(&tweet).await?;
+        // Tweets of a conversation already share their channel, only reply
+        // chains need a hint
+        let hints = ConversationHints {
+            reply_to: tweet.referenced_tweets.as_ref().and_then(|referenced| {
+                referenced
+                    .iter()
+                    .find(|t| matches!(t.kind, ReferencedTweetKind::RepliedTo))
+                    .map(|t| t.id.to_string())
+            }),
+            thread_id: None,
+        };
+        match knowledge.assign_conversation(&knowledge_msg, hints).await {
+            Ok(conversation) => {
+                let agent = self.agent.clone();
+                tokio::spawn(async move { agent.title_conversation(&conversation).await });
+            }
+            Err(err) => error!(?err, "Failed to assign tweet to a conversation"),
+        }
+
+        let thread = self
+            .build_conversation_thread(&tweet, &mut usernames)
+            .await?;
+
+        let mut author_account_id = None;
+        for (author_id, username) in &usernames {
+            match knowledge
+                .upsert_account(Source::Twitter, &author_id.to_string(), username)
+                .await
+            {
+                Ok(account) if tweet.author_id == Some(*author_id) => {
+                    author_account_id = Some(account.id)
+                }
+                Ok(_) => {}
+                Err(err) => error!(?err, "Failed to register tweet author"),
+            }
+        }
+
+        let agent = self.agent.clone();
+        let channel_id = knowledge_msg.channel_id.clone();
+        tokio::spawn(async move { agent.update_memory(&channel_id, author_account_id).await });
 
         let mentioned_names: HashSet<String> = tweet
             .text
@@ -128,17 +199,59 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static, A: Authorization
             "Mentioned names in tweet"
         );
 
-        let history = thread
+        let history: Vec<HistoryEntry> = thread
             .iter()
-            .map(|t| (t.id.to_string(), t.text.clone()))
+            .map(|t| {
+                let account_id = t.author_id.map(|id| id.to_string()).unwrap_or_default();
+
+                HistoryEntry {
+                    message_id: t.id.to_string(),
+                    speaker: t
+                        .author_id
+                        .and_then(|id| usernames.get(&id))
+                        .map(|username| format!("@{}", username))
+                        .unwrap_or_else(|| account_id.clone()),
+                    account_id,
+                    role: if t.author_id == Some(user_id) {
+                        "assistant".to_string()
+                    } else {
+                        "user".to_string()
+                    },
+                    content: t.text.clone(),
+                    created_at: tweet_created_at(t),
+                }
+            })
             .collect();
 
+        // The thread is ordered oldest to newest and ends with the mention itself
+        let is_reply_to_agent =
+            thread.len() > 1 && thread[thread.len() - 2].author_id == Some(user_id);
+        let thread_started_by_agent = thread.first().is_some_and(|root| {
+            root.conversation_id == Some(root.id) && root.author_id == Some(user_id)
+        });
+
+        let channel_settings = match knowledge.channel_settings(&knowledge_msg.channel_id).await {
+            Ok(settings) => settings,
+            Err(err) => {
+                error!(?err, "Failed to fetch channel settings");
+                None
+            }
+        };
+
         let context = AttentionContext {
+            channel_id: knowledge_msg.channel_id.clone(),
+            author_name: history
+                .last()
+                .map(|entry| entry.speaker.clone())
+                .unwrap_or_default(),
             message_content: tweet.text.clone(),
             mentioned_names,
             history,
             channel_type: knowledge_msg.channel_type,
             source: knowledge_msg.source,
+            is_reply_to_agent,
+            thread_started_by_agent,
+            channel_settings,
         };
 
         debug!(?context, "Attention context");
@@ -151,14 +264,22 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static, A: Authorization
             }
         }
 
-        let agent = self
+        let builder = self
             .agent
-            .builder()
+            .builder_for_channel(context.channel_settings.as_ref())
             .context(&format!(
                 "Current time: {}",
                 chrono::Local::now().format("%I:%M:%S %p, %Y-%m-%d")
             ))
             .context("Please keep your responses concise and under 280 characters.")
+            .context(&format!(
+                "Conversation thread:\n{}",
+                HistoryEntry::transcript(&context.history, &self.agent.character.name)
+            ));
+        let agent = self
+            .agent
+            .with_memory(builder, &context.channel_id, author_account_id)
+            .await
             .build();
 
         let response = match agent.prompt(&tweet.text).await {
@@ -196,9 +317,11 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static, A: Authorization
         Ok(())
     }
 
+    /// Walks up the reply chain of `tweet`, recording the authors' usernames.
     async fn build_conversation_thread(
         &self,
         tweet: &twitter::Tweet,
+        usernames: &mut HashMap<NumericId, String>,
     ) -> Result<Vec<twitter::Tweet>, Box<dyn std::error::Error>> {
         let mut thread = Vec::new();
         let mut current_tweet = Some(tweet.clone());
@@ -216,8 +339,16 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static, A: Authorization
                     .iter()
                     .find(|t| matches!(t.kind, ReferencedTweetKind::RepliedTo))
                 {
-                    match self.api.get_tweet(replied_to.id).send().await {
+                    match self
+                        .api
+                        .get_tweet(replied_to.id)
+                        .tweet_fields(TWEET_FIELDS)
+                        .expansions([TweetExpansion::AuthorId])
+                        .send()
+                        .await
+                    {
                         Ok(response) => {
+                            usernames.extend(expanded_usernames(response.includes.as_ref()));
                             current_tweet = response.data.clone();
                         }
                         Err(err) => {
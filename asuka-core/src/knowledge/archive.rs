@@ -0,0 +1,515 @@
+//! Export of a knowledge base to a directory of JSON Lines files, and the
+//! matching import, to move an agent's memory between environments.
+//!
+//! Every table is written to `{table}.jsonl`, one JSON object per row keyed
+//! by column name, along with a `manifest.json`. Embeddings are optional and
+//! go to `{table}.embeddings.arrow`, an Arrow IPC file with an `id` column
+//! and an `embedding` column of fixed-size float32 lists.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use arrow_array::cast::AsArray;
+use arrow_array::types::Float32Type;
+use arrow_array::{FixedSizeListArray, Float32Array, RecordBatch, StringArray};
+use arrow_ipc::reader::FileReader;
+use arrow_ipc::writer::FileWriter;
+use arrow_schema::{DataType, Field, FieldRef, Schema, SchemaRef};
+use rusqlite::types::Value;
+use rusqlite::Connection;
+use tracing::info;
+
+use super::error::{ArchiveError, EmbeddingModelError};
+use super::migrations;
+use super::models::EmbeddingModelRecord;
+use super::reembed::{self, VECTOR_TABLES};
+
+/// Version of the archive layout, bumped when it changes incompatibly.
+pub const ARCHIVE_FORMAT_VERSION: i64 = 1;
+
+/// Tables exported, parents before the tables referring to them. Link codes
+/// are short-lived and left out.
+const TABLES: &[&str] = &[
+    "persons",
+    "accounts",
+    "channels",
+    "channel_settings",
+    "conversations",
+    "messages",
+    "message_revisions",
+    "summaries",
+    "facts",
+    "retention_policies",
+    "documents",
+];
+
+/// Vectors per record batch of an embeddings file.
+const EMBEDDING_BATCH_ROWS: usize = 1024;
+
+/// What `export` writes besides the rows.
+#[derive(Debug, Clone, Default)]
+pub struct ExportOptions {
+    /// Also writes the vectors, which spares re-embedding everything after
+    /// the import, as long as the same embedding model is used.
+    pub embeddings: bool,
+}
+
+/// Contents of `manifest.json`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ArchiveManifest {
+    pub format_version: i64,
+    /// Schema version of the exported database.
+    pub schema_version: i64,
+    pub exported_at: chrono::DateTime<chrono::Utc>,
+    /// Rows written per table.
+    pub tables: std::collections::BTreeMap<String, usize>,
+    pub embeddings: bool,
+    /// Models of the exported vectors, empty without embeddings.
+    #[serde(default)]
+    pub embedding_models: Vec<EmbeddingModelRecord>,
+}
+
+/// Writes every table to `dir`, created if missing, from a single read
+/// transaction so the export is consistent while the bot keeps writing.
+pub fn export(
+    conn: &mut Connection,
+    dir: &Path,
+    options: &ExportOptions,
+) -> Result<ArchiveManifest, ArchiveError> {
+    std::fs::create_dir_all(dir)?;
+    let tx = conn.transaction()?;
+
+    let mut manifest = ArchiveManifest {
+        format_version: ARCHIVE_FORMAT_VERSION,
+        schema_version: migrations::current_version(&tx)?,
+        exported_at: chrono::Utc::now(),
+        tables: Default::default(),
+        embeddings: options.embeddings,
+        embedding_models: Vec::new(),
+    };
+
+    for table in TABLES {
+        let mut stmt = tx.prepare(&format!("SELECT * FROM {table} ORDER BY rowid"))?;
+        let columns: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+        let mut rows = stmt.query([])?;
+        let mut out = BufWriter::new(File::create(dir.join(format!("{table}.jsonl")))?);
+        let mut count = 0;
+
+        while let Some(row) = rows.next()? {
+            let mut object = serde_json::Map::new();
+            for (index, column) in columns.iter().enumerate() {
+                object.insert(column.clone(), json_value(row.get_ref(index)?));
+            }
+            serde_json::to_writer(&mut out, &object)?;
+            out.write_all(b"\n")?;
+            count += 1;
+        }
+
+        out.flush()?;
+        manifest.tables.insert(table.to_string(), count);
+    }
+
+    if options.embeddings {
+        for table in VECTOR_TABLES {
+            // Opening a knowledge base records the model of every vector
+            // table, one without a record has no vectors
+            let Some(model) = reembed::embedding_model(&tx, table)? else {
+                continue;
+            };
+            let mut out = EmbeddingWriter::create(
+                &dir.join(format!("{table}.embeddings.arrow")),
+                model.dimensions,
+            )?;
+            manifest.embedding_models.push(model);
+
+            let mut stmt = tx.prepare(&format!(
+                "SELECT t.id, vec_to_json(e.embedding)
+                 FROM {table}_embeddings e
+                 JOIN {table} t ON t.rowid = e.rowid
+                 ORDER BY t.rowid"
+            ))?;
+            let mut rows = stmt.query([])?;
+
+            while let Some(row) = rows.next()? {
+                let embedding: String = row.get(1)?;
+                out.push(row.get(0)?, serde_json::from_str(&embedding)?)?;
+            }
+
+            out.finish()?;
+        }
+    }
+
+    std::fs::write(
+        dir.join("manifest.json"),
+        serde_json::to_string_pretty(&manifest)?,
+    )?;
+
+    info!(dir = %dir.display(), tables = ?manifest.tables, "Exported knowledge base");
+    Ok(manifest)
+}
+
+/// Loads an export into an empty, migrated database in one transaction.
+/// Embeddings must come from the model the database's vector tables were
+/// created for. Without them, imported rows are found by keyword search
+/// only until they are re-embedded.
+pub fn import(conn: &mut Connection, dir: &Path) -> Result<ArchiveManifest, ArchiveError> {
+    let manifest: ArchiveManifest =
+        serde_json::from_reader(BufReader::new(File::open(dir.join("manifest.json"))?))?;
+
+    if manifest.format_version != ARCHIVE_FORMAT_VERSION {
+        return Err(ArchiveError::UnsupportedFormat(manifest.format_version));
+    }
+    let supported = migrations::current_version(conn)?;
+    if manifest.schema_version > supported {
+        return Err(ArchiveError::SchemaTooNew {
+            found: manifest.schema_version,
+            supported,
+        });
+    }
+
+    let tx = conn.transaction()?;
+
+    for table in TABLES {
+        let has_rows: bool = tx.query_row(
+            &format!("SELECT EXISTS (SELECT 1 FROM {table})"),
+            [],
+            |row| row.get(0),
+        )?;
+        if has_rows {
+            return Err(ArchiveError::NotEmpty(table.to_string()));
+        }
+    }
+
+    for table in TABLES {
+        let path = dir.join(format!("{table}.jsonl"));
+        // Tables added after the export was made have no file
+        if !path.exists() {
+            continue;
+        }
+
+        let known = table_columns(&tx, table)?;
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let object: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&line)?;
+            if let Some(column) = object.keys().find(|column| !known.contains(*column)) {
+                return Err(ArchiveError::UnknownColumn {
+                    table: table.to_string(),
+                    column: column.clone(),
+                });
+            }
+
+            let columns: Vec<String> = object
+                .keys()
+                .map(|column| format!("\"{column}\""))
+                .collect();
+            let placeholders: Vec<String> = (1..=columns.len()).map(|i| format!("?{i}")).collect();
+            tx.execute(
+                &format!(
+                    "INSERT INTO {table} ({}) VALUES ({})",
+                    columns.join(", "),
+                    placeholders.join(", ")
+                ),
+                rusqlite::params_from_iter(object.into_values().map(sql_value)),
+            )?;
+        }
+    }
+
+    if manifest.embeddings {
+        for exported in &manifest.embedding_models {
+            let Some(stored) = reembed::embedding_model(&tx, &exported.table_name)? else {
+                // The imported vectors make the table the exported model's
+                reembed::record_embedding_model(
+                    &tx,
+                    &exported.table_name,
+                    &exported.model,
+                    exported.dimensions,
+                )?;
+                continue;
+            };
+            if (&stored.model, stored.dimensions) != (&exported.model, exported.dimensions) {
+                return Err(EmbeddingModelError::Mismatch {
+                    table: stored.table_name,
+                    stored_model: stored.model,
+                    stored_dimensions: stored.dimensions,
+                    model: exported.model.clone(),
+                    dimensions: exported.dimensions,
+                }
+                .into());
+            }
+        }
+
+        for table in VECTOR_TABLES {
+            let path = dir.join(format!("{table}.embeddings.arrow"));
+            if !path.exists() {
+                continue;
+            }
+
+            let invalid = || ArchiveError::InvalidEmbeddings(path.display().to_string());
+            for batch in FileReader::try_new(BufReader::new(File::open(&path)?), None)? {
+                let batch = batch?;
+                let ids = batch
+                    .column_by_name("id")
+                    .and_then(|column| column.as_string_opt::<i32>())
+                    .ok_or_else(invalid)?;
+                let embeddings = batch
+                    .column_by_name("embedding")
+                    .and_then(|column| column.as_fixed_size_list_opt())
+                    .ok_or_else(invalid)?;
+
+                for row in 0..batch.num_rows() {
+                    let embedding = embeddings.value(row);
+                    let vector = embedding
+                        .as_primitive_opt::<Float32Type>()
+                        .ok_or_else(invalid)?;
+                    // sqlite-vec reads vectors given as JSON arrays
+                    tx.execute(
+                        &format!(
+                            "INSERT INTO {table}_embeddings (rowid, embedding)
+                             SELECT rowid, ?2 FROM {table} WHERE id = ?1"
+                        ),
+                        rusqlite::params![
+                            ids.value(row),
+                            serde_json::to_string(&vector.values().to_vec())?
+                        ],
+                    )?;
+                }
+            }
+        }
+    }
+
+    tx.commit()?;
+
+    info!(dir = %dir.display(), tables = ?manifest.tables, "Imported knowledge base");
+    Ok(manifest)
+}
+
+/// Writes the vectors of a table to an Arrow IPC file, in record batches of
+/// `EMBEDDING_BATCH_ROWS`.
+struct EmbeddingWriter {
+    writer: FileWriter<BufWriter<File>>,
+    schema: SchemaRef,
+    dimensions: i32,
+    ids: Vec<String>,
+    values: Vec<f32>,
+}
+
+impl EmbeddingWriter {
+    fn create(path: &Path, dimensions: i64) -> Result<Self, ArchiveError> {
+        let dimensions = i32::try_from(dimensions)
+            .map_err(|_| ArchiveError::InvalidEmbeddings(path.display().to_string()))?;
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Utf8, false),
+            Field::new(
+                "embedding",
+                DataType::FixedSizeList(vector_field(), dimensions),
+                false,
+            ),
+        ]));
+        let writer = FileWriter::try_new(BufWriter::new(File::create(path)?), &schema)?;
+
+        Ok(Self {
+            writer,
+            schema,
+            dimensions,
+            ids: Vec::new(),
+            values: Vec::new(),
+        })
+    }
+
+    fn push(&mut self, id: String, vector: Vec<f32>) -> Result<(), ArchiveError> {
+        if vector.len() != self.dimensions as usize {
+            return Err(ArchiveError::InvalidEmbeddings(format!(
+                "{id} has {} dimensions, not {}",
+                vector.len(),
+                self.dimensions
+            )));
+        }
+
+        self.ids.push(id);
+        self.values.extend(vector);
+        if self.ids.len() >= EMBEDDING_BATCH_ROWS {
+            self.write_batch()?;
+        }
+
+        Ok(())
+    }
+
+    fn write_batch(&mut self) -> Result<(), ArchiveError> {
+        if self.ids.is_empty() {
+            return Ok(());
+        }
+
+        let ids = StringArray::from(std::mem::take(&mut self.ids));
+        let embeddings = FixedSizeListArray::try_new(
+            vector_field(),
+            self.dimensions,
+            Arc::new(Float32Array::from(std::mem::take(&mut self.values))),
+            None,
+        )?;
+        let batch = RecordBatch::try_new(
+            self.schema.clone(),
+            vec![Arc::new(ids), Arc::new(embeddings)],
+        )?;
+        self.writer.write(&batch)?;
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), ArchiveError> {
+        self.write_batch()?;
+        self.writer.into_inner()?.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Items of the `embedding` lists.
+fn vector_field() -> FieldRef {
+    Arc::new(Field::new("item", DataType::Float32, false))
+}
+
+fn table_columns(conn: &Connection, table: &str) -> Result<Vec<String>, rusqlite::Error> {
+    conn.prepare(&format!("SELECT name FROM pragma_table_info('{table}')"))?
+        .query_map([], |row| row.get(0))?
+        .collect()
+}
+
+fn json_value(value: rusqlite::types::ValueRef) -> serde_json::Value {
+    use rusqlite::types::ValueRef;
+
+    match value {
+        ValueRef::Null | ValueRef::Blob(_) => serde_json::Value::Null,
+        ValueRef::Integer(i) => i.into(),
+        ValueRef::Real(f) => f.into(),
+        ValueRef::Text(text) => String::from_utf8_lossy(text).into(),
+    }
+}
+
+fn sql_value(value: serde_json::Value) -> Value {
+    match value {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(b) => Value::Integer(b.into()),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Real(n.as_f64().unwrap_or_default()),
+        },
+        serde_json::Value::String(s) => Value::Text(s),
+        other => Value::Text(other.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export_import_round_trip() {
+        let mut source = Connection::open_in_memory().unwrap();
+        migrations::migrate(&mut source).unwrap();
+        source
+            .execute_batch(
+                "INSERT INTO accounts (source, source_id, name) VALUES ('discord', '42', 'ada');
+                 INSERT INTO facts (account_id, content, confidence) VALUES (1, 'Likes Rust', 0.9);
+                 INSERT INTO messages (id, source, source_id, channel_type, channel_id, account_id, role, content)
+                 VALUES ('discord:1', 'discord', '1', 'text', 'general', '42', 'user', 'gm');",
+            )
+            .unwrap();
+
+        let dir = std::env::temp_dir().join(format!("asuka-archive-{}", std::process::id()));
+        let manifest = export(&mut source, &dir, &ExportOptions::default()).unwrap();
+        assert_eq!(manifest.tables["facts"], 1);
+
+        let mut target = Connection::open_in_memory().unwrap();
+        migrations::migrate(&mut target).unwrap();
+        import(&mut target, &dir).unwrap();
+
+        let (name, confidence): (String, f64) = target
+            .query_row(
+                "SELECT a.name, f.confidence FROM facts f JOIN accounts a ON a.id = f.account_id",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((name.as_str(), confidence), ("ada", 0.9));
+
+        // Imported messages are searchable by keyword right away
+        let hits: i64 = target
+            .query_row(
+                "SELECT COUNT(*) FROM messages_fts WHERE messages_fts MATCH 'gm'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(hits, 1);
+
+        assert!(matches!(
+            import(&mut target, &dir),
+            Err(ArchiveError::NotEmpty(_))
+        ));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_embeddings_round_trip_through_arrow() {
+        unsafe {
+            rusqlite::ffi::sqlite3_auto_extension(Some(std::mem::transmute(
+                sqlite_vec::sqlite3_vec_init as *const (),
+            )));
+        }
+        let vector_tables = "
+            CREATE VIRTUAL TABLE documents_embeddings USING vec0(embedding float[3]);
+            CREATE VIRTUAL TABLE messages_embeddings USING vec0(embedding float[3]);
+            CREATE VIRTUAL TABLE summaries_embeddings USING vec0(embedding float[3]);";
+
+        let mut source = Connection::open_in_memory().unwrap();
+        migrations::migrate(&mut source).unwrap();
+        source.execute_batch(vector_tables).unwrap();
+        reembed::check_embedding_model(&source, "hashing", 3).unwrap();
+        source
+            .execute_batch(
+                "INSERT INTO documents (id, parent_id, content) VALUES ('vrf.md#0', 'vrf.md', 'VRF');
+                 INSERT INTO documents_embeddings (rowid, embedding)
+                 SELECT rowid, '[0.5, 0.25, 1.0]' FROM documents;",
+            )
+            .unwrap();
+
+        let dir = std::env::temp_dir().join(format!("asuka-embeddings-{}", std::process::id()));
+        let manifest = export(&mut source, &dir, &ExportOptions { embeddings: true }).unwrap();
+        assert_eq!(manifest.embedding_models.len(), VECTOR_TABLES.len());
+        assert!(dir.join("documents.embeddings.arrow").exists());
+
+        let mut target = Connection::open_in_memory().unwrap();
+        migrations::migrate(&mut target).unwrap();
+        target.execute_batch(vector_tables).unwrap();
+        import(&mut target, &dir).unwrap();
+
+        // The target had no model yet, it takes the exported one
+        let model = reembed::embedding_model(&target, "documents")
+            .unwrap()
+            .unwrap();
+        assert_eq!((model.model.as_str(), model.dimensions), ("hashing", 3));
+
+        let (id, embedding): (String, String) = target
+            .query_row(
+                "SELECT d.id, vec_to_json(e.embedding)
+                 FROM documents_embeddings e JOIN documents d ON d.rowid = e.rowid",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(id, "vrf.md#0");
+        assert_eq!(
+            serde_json::from_str::<Vec<f32>>(&embedding).unwrap(),
+            [0.5, 0.25, 1.0]
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,1912 @@
+//! Storage traits behind a `KnowledgeBase`, implemented by `SqliteStorage`
+//! over its database.
+//!
+//! `RelationalStore` holds what the bot learns from its platforms: accounts,
+//! channels, messages with their conversations, summaries, facts and
+//! retention policies. `VectorStore` holds the vectors of documents,
+//! messages and summaries. Both can be replaced with
+//! `KnowledgeBase::with_storage`, e.g. by one PostgreSQL store shared by
+//! several bot processes. Documents, the embedding cache and the record of
+//! embedding models stay in the SQLite database, as do exports, backups and
+//! `ReembedJob`, which work on its tables.
+
+use anyhow::Context;
+use async_trait::async_trait;
+use rusqlite::OptionalExtension;
+use tokio_rusqlite::Connection;
+
+use super::models::{
+    Account, Channel, ChannelMetadata, ChannelSettings, Conversation, ConversationHints, Fact,
+    HistoryEntry, Message, MessageRevision, NewFact, Person, RetentionPolicy, Summary,
+    SummaryScope,
+};
+use super::search::fts_query;
+use super::types::Source;
+
+/// Identifies a stored message, summary or document chunk, and its vector.
+/// Issued by the store that holds the row, opaque to everything else.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct StoreKey(String);
+
+impl StoreKey {
+    pub fn new(key: impl Into<String>) -> Self {
+        Self(key.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<i64> for StoreKey {
+    fn from(rowid: i64) -> Self {
+        Self(rowid.to_string())
+    }
+}
+
+/// A row removed from a store, with what has to be forgotten along with it.
+#[derive(Debug, Clone)]
+pub struct DeletedRow {
+    /// Key its vector is stored under.
+    pub key: StoreKey,
+    /// Texts embedded from the row: its content, and the earlier contents
+    /// of an edited message.
+    pub contents: Vec<String>,
+}
+
+/// What purging an account removed.
+#[derive(Debug, Clone, Default)]
+pub struct PurgedAccount {
+    pub messages: Vec<DeletedRow>,
+    pub summaries: Vec<DeletedRow>,
+    pub facts: usize,
+}
+
+/// Relational side of the knowledge base.
+#[async_trait]
+pub trait RelationalStore: Send + Sync {
+    /// Creates the account of a platform user, or refreshes its display name.
+    async fn upsert_account(
+        &self,
+        source: Source,
+        source_id: &str,
+        name: &str,
+    ) -> anyhow::Result<Account>;
+
+    async fn get_account(&self, source: Source, source_id: &str)
+        -> anyhow::Result<Option<Account>>;
+
+    async fn get_account_by_id(&self, id: i64) -> anyhow::Result<Option<Account>>;
+
+    /// Lists accounts ordered by name, optionally restricted to one platform.
+    async fn list_accounts(&self, source: Option<Source>) -> anyhow::Result<Vec<Account>>;
+
+    /// Returns the most recent messages written by an account, newest first.
+    async fn account_messages(&self, account_id: i64, limit: usize)
+        -> anyhow::Result<Vec<Message>>;
+
+    /// Stores a link code issued to an account, replacing any code
+    /// previously issued to it, valid for `ttl`.
+    async fn create_link_code(
+        &self,
+        account_id: i64,
+        code: &str,
+        ttl: std::time::Duration,
+    ) -> anyhow::Result<()>;
+
+    /// Consumes a link code from `account_id`, attaching both accounts to
+    /// the same person and merging their persons if both were already
+    /// linked. Returns `None` when the code is unknown, expired or was
+    /// issued to `account_id` itself.
+    async fn confirm_link_code(
+        &self,
+        code: &str,
+        account_id: i64,
+    ) -> anyhow::Result<Option<Person>>;
+
+    /// Detaches an account from its person.
+    async fn unlink_account(&self, account_id: i64) -> anyhow::Result<()>;
+
+    async fn get_person(&self, id: i64) -> anyhow::Result<Option<Person>>;
+
+    async fn person_accounts(&self, person_id: i64) -> anyhow::Result<Vec<Account>>;
+
+    /// Returns the most recent messages written from any of a person's
+    /// accounts, newest first.
+    async fn person_messages(&self, person_id: i64, limit: usize) -> anyhow::Result<Vec<Message>>;
+
+    /// Deletes an account with its messages, the facts and summaries about
+    /// it, and the summaries of the channels it wrote in. Returns `None` for
+    /// an unknown account.
+    async fn purge_account(&self, account_id: i64) -> anyhow::Result<Option<PurgedAccount>>;
+
+    /// Records a channel's metadata as last seen by a client.
+    async fn upsert_channel(&self, channel: ChannelMetadata) -> anyhow::Result<Channel>;
+
+    async fn get_channel(&self, id: i64) -> anyhow::Result<Option<Channel>>;
+
+    async fn get_channels_by_source(&self, source: &str) -> anyhow::Result<Vec<Channel>>;
+
+    async fn channel_settings(&self, channel_id: &str) -> anyhow::Result<Option<ChannelSettings>>;
+
+    async fn set_channel_settings(&self, settings: ChannelSettings) -> anyhow::Result<()>;
+
+    /// Stores a message, creating its channel if unknown. Returns its key,
+    /// or `None` when a message with its ID is stored already, e.g. from an
+    /// update delivered twice.
+    async fn insert_message(&self, msg: Message) -> anyhow::Result<Option<StoreKey>>;
+
+    async fn get_message(&self, id: i64) -> anyhow::Result<Option<Message>>;
+
+    /// Looks a message up by its platform and platform ID.
+    async fn find_message(
+        &self,
+        source: Source,
+        id: &str,
+    ) -> anyhow::Result<Option<(StoreKey, Message)>>;
+
+    /// Returns the messages stored under `keys`, skipping unknown keys.
+    async fn messages_by_key(
+        &self,
+        keys: Vec<StoreKey>,
+    ) -> anyhow::Result<Vec<(StoreKey, Message)>>;
+
+    /// Pages through every stored message as its key and content, up to
+    /// `limit` at a time: pass the last key of a page to get the next one.
+    async fn messages_after(
+        &self,
+        after: Option<StoreKey>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(StoreKey, String)>>;
+
+    /// Returns the keys of the messages matching the words of `query` best
+    /// by full-text search, best first.
+    async fn search_messages(&self, query: &str, n: usize) -> anyhow::Result<Vec<StoreKey>>;
+
+    /// Returns the last `limit` messages of a channel, newest first.
+    async fn recent_messages(&self, channel_id: &str, limit: usize)
+        -> anyhow::Result<Vec<Message>>;
+
+    /// Returns the last `limit` messages of a channel, oldest first, with each
+    /// speaker's display name resolved through the accounts.
+    async fn channel_history(
+        &self,
+        channel_id: &str,
+        limit: i64,
+    ) -> anyhow::Result<Vec<HistoryEntry>>;
+
+    /// Replaces the content of a message, keeping the previous content as a
+    /// revision. Returns the message's key and that revision, or `None` when
+    /// the message is unknown or its content didn't change.
+    async fn edit_message(
+        &self,
+        source: Source,
+        id: &str,
+        content: &str,
+    ) -> anyhow::Result<Option<(StoreKey, MessageRevision)>>;
+
+    /// Returns the earlier contents of a message, oldest first.
+    async fn message_revisions(
+        &self,
+        source: Source,
+        id: &str,
+    ) -> anyhow::Result<Vec<MessageRevision>>;
+
+    /// Deletes a message with its revisions.
+    async fn delete_message(&self, source: Source, id: &str) -> anyhow::Result<Option<DeletedRow>>;
+
+    /// Deletes the messages that fall outside of a retention policy as of
+    /// `now`, with their revisions.
+    async fn delete_expired_messages(
+        &self,
+        policy: RetentionPolicy,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<Vec<DeletedRow>>;
+
+    /// Files a stored message under a conversation: the one of the message
+    /// it replies to, the one of its thread, or the latest of its channel
+    /// when it was active within `gap`. Starts a new conversation otherwise.
+    async fn assign_conversation(
+        &self,
+        msg: &Message,
+        hints: ConversationHints,
+        gap: chrono::Duration,
+    ) -> anyhow::Result<Conversation>;
+
+    async fn get_conversation(&self, id: i64) -> anyhow::Result<Option<Conversation>>;
+
+    /// Returns the conversations of a channel, most recently active first.
+    async fn channel_conversations(
+        &self,
+        channel_id: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Conversation>>;
+
+    /// Returns the last `limit` messages of a conversation, oldest first.
+    async fn conversation_messages(
+        &self,
+        conversation_id: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<HistoryEntry>>;
+
+    async fn set_conversation_title(&self, id: i64, title: &str) -> anyhow::Result<()>;
+
+    /// Returns up to `limit` messages of `scope` created after `after`,
+    /// oldest first.
+    async fn scope_messages(
+        &self,
+        scope: &SummaryScope,
+        after: Option<chrono::DateTime<chrono::Utc>>,
+        limit: i64,
+    ) -> anyhow::Result<Vec<HistoryEntry>>;
+
+    /// Stores a summary. Returns its key.
+    async fn insert_summary(&self, summary: Summary) -> anyhow::Result<StoreKey>;
+
+    /// Returns the most recent summary of `scope`.
+    async fn latest_summary(&self, scope: &SummaryScope) -> anyhow::Result<Option<Summary>>;
+
+    /// Returns the summaries stored under `keys`, skipping unknown keys.
+    async fn summaries_by_key(
+        &self,
+        keys: Vec<StoreKey>,
+    ) -> anyhow::Result<Vec<(StoreKey, Summary)>>;
+
+    /// Stores a fact, or raises the confidence of an identical one already
+    /// known about the account.
+    async fn add_fact(&self, fact: NewFact) -> anyhow::Result<Fact>;
+
+    /// Lists the facts known about an account and the accounts linked to
+    /// it, most confident first.
+    async fn account_facts(
+        &self,
+        account_id: i64,
+        min_confidence: f64,
+    ) -> anyhow::Result<Vec<Fact>>;
+
+    async fn delete_fact(&self, id: i64) -> anyhow::Result<bool>;
+
+    /// Forgets every fact about an account, returning how many were deleted.
+    async fn delete_account_facts(&self, account_id: i64) -> anyhow::Result<usize>;
+
+    /// Returns the creation time of the last message of the account facts
+    /// were extracted from.
+    async fn facts_extracted_until(
+        &self,
+        account_id: i64,
+    ) -> anyhow::Result<Option<chrono::DateTime<chrono::Utc>>>;
+
+    async fn set_facts_extracted_until(
+        &self,
+        account_id: i64,
+        until: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<()>;
+
+    /// Adds a retention policy, replacing the one of the same source and
+    /// channel if any.
+    async fn set_retention_policy(&self, policy: RetentionPolicy) -> anyhow::Result<()>;
+
+    async fn remove_retention_policy(
+        &self,
+        source: Option<Source>,
+        channel_id: Option<&str>,
+    ) -> anyhow::Result<bool>;
+
+    async fn retention_policies(&self) -> anyhow::Result<Vec<RetentionPolicy>>;
+}
+
+/// Vector side of the knowledge base: one vector per stored row, by the
+/// row's key, in a table per kind of row, e.g. `messages`.
+#[async_trait]
+pub trait VectorStore: Send + Sync {
+    /// Creates a table for vectors of `dimensions`, unless it exists.
+    async fn create_table(&self, table: &str, dimensions: usize) -> anyhow::Result<()>;
+
+    /// Drops a table along with its vectors.
+    async fn drop_table(&self, table: &str) -> anyhow::Result<()>;
+
+    /// Returns the keys among `keys` without a vector in `table`.
+    async fn missing_vectors(
+        &self,
+        table: &str,
+        keys: Vec<StoreKey>,
+    ) -> anyhow::Result<Vec<StoreKey>>;
+
+    /// Adds vectors, skipping the keys that already have one, e.g. from an
+    /// edit that happened while the row was embedded. Returns how many were
+    /// added.
+    async fn add_vectors(
+        &self,
+        table: &str,
+        vectors: Vec<(StoreKey, Vec<f64>)>,
+    ) -> anyhow::Result<usize>;
+
+    /// Adds vectors, replacing those the keys already have.
+    async fn replace_vectors(
+        &self,
+        table: &str,
+        vectors: Vec<(StoreKey, Vec<f64>)>,
+    ) -> anyhow::Result<()>;
+
+    /// Returns the keys of the `n` vectors closest to `query` with their
+    /// distance, closest first. With `among`, only those keys are searched.
+    async fn nearest(
+        &self,
+        table: &str,
+        query: Vec<f64>,
+        n: usize,
+        among: Option<Vec<StoreKey>>,
+    ) -> anyhow::Result<Vec<(StoreKey, f64)>>;
+
+    /// Removes the vectors of rows. Returns how many were removed.
+    async fn remove_vectors(&self, table: &str, keys: Vec<StoreKey>) -> anyhow::Result<usize>;
+}
+
+/// Both storage traits over the SQLite database, with sqlite-vec tables
+/// named `{table}_embeddings`. Keys are rowids.
+#[derive(Clone)]
+pub struct SqliteStorage {
+    conn: Connection,
+}
+
+impl SqliteStorage {
+    pub fn new(conn: Connection) -> Self {
+        Self { conn }
+    }
+}
+
+/// Rowid behind a key issued by `SqliteStorage`.
+fn rowid(key: &StoreKey) -> anyhow::Result<i64> {
+    key.as_str()
+        .parse()
+        .with_context(|| format!("{} is not a SQLite store key", key.as_str()))
+}
+
+/// Rowids behind keys, as the JSON array `json_each` reads.
+fn rowids(keys: &[StoreKey]) -> anyhow::Result<String> {
+    let rowids = keys.iter().map(rowid).collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(serde_json::to_string(&rowids)?)
+}
+
+/// Deletes the messages matching `filter` along with their revisions.
+fn delete_messages(
+    conn: &rusqlite::Connection,
+    filter: &str,
+    params: &[&dyn rusqlite::ToSql],
+) -> rusqlite::Result<Vec<DeletedRow>> {
+    let messages = conn
+        .prepare(&format!(
+            "SELECT rowid, source, id, content FROM messages WHERE {filter}"
+        ))?
+        .query_map(params, |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, Option<String>>(3)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut revisions = conn.prepare(
+        "DELETE FROM message_revisions WHERE source = ?1 AND message_id = ?2 RETURNING content",
+    )?;
+    let mut delete = conn.prepare("DELETE FROM messages WHERE rowid = ?1")?;
+    let mut deleted = Vec::with_capacity(messages.len());
+
+    for (rowid, source, id, content) in messages {
+        let mut contents: Vec<String> = content.into_iter().collect();
+        for revision in revisions.query_map(rusqlite::params![source, id], |row| row.get(0))? {
+            contents.push(revision?);
+        }
+        delete.execute([rowid])?;
+
+        deleted.push(DeletedRow {
+            key: rowid.into(),
+            contents,
+        });
+    }
+
+    Ok(deleted)
+}
+
+/// Deletes the summaries matching `filter`.
+fn delete_summaries(
+    conn: &rusqlite::Connection,
+    filter: &str,
+    params: &[&dyn rusqlite::ToSql],
+) -> rusqlite::Result<Vec<DeletedRow>> {
+    conn.prepare(&format!(
+        "DELETE FROM summaries WHERE {filter} RETURNING rowid, content"
+    ))?
+    .query_map(params, |row| {
+        Ok(DeletedRow {
+            key: StoreKey::from(row.get::<_, i64>(0)?),
+            contents: vec![row.get(1)?],
+        })
+    })?
+    .collect()
+}
+
+#[async_trait]
+impl RelationalStore for SqliteStorage {
+    async fn upsert_account(
+        &self,
+        source: Source,
+        source_id: &str,
+        name: &str,
+    ) -> anyhow::Result<Account> {
+        let source_id = source_id.to_string();
+        let name = name.to_string();
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                conn.query_row(
+                    "INSERT INTO accounts (source, source_id, name, created_at, updated_at)
+                     VALUES (?1, ?2, ?3, CURRENT_TIMESTAMP, CURRENT_TIMESTAMP)
+                     ON CONFLICT (source, source_id) DO UPDATE SET
+                         name = excluded.name,
+                         updated_at = CURRENT_TIMESTAMP
+                     RETURNING id, source, source_id, name, created_at, updated_at, person_id",
+                    rusqlite::params![source.as_str(), source_id, name],
+                    |row| Account::try_from(row),
+                )
+                .map_err(tokio_rusqlite::Error::from)
+            })
+            .await?)
+    }
+
+    async fn get_account(
+        &self,
+        source: Source,
+        source_id: &str,
+    ) -> anyhow::Result<Option<Account>> {
+        let source_id = source_id.to_string();
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let account = conn
+                    .prepare(
+                        "SELECT id, source, source_id, name, created_at, updated_at, person_id
+                         FROM accounts
+                         WHERE source = ?1 AND source_id = ?2",
+                    )?
+                    .query_row(rusqlite::params![source.as_str(), source_id], |row| {
+                        Account::try_from(row)
+                    })
+                    .optional()?;
+
+                Ok(account)
+            })
+            .await?)
+    }
+
+    async fn get_account_by_id(&self, id: i64) -> anyhow::Result<Option<Account>> {
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let account = conn
+                    .prepare(
+                        "SELECT id, source, source_id, name, created_at, updated_at, person_id
+                         FROM accounts
+                         WHERE id = ?1",
+                    )?
+                    .query_row(rusqlite::params![id], |row| Account::try_from(row))
+                    .optional()?;
+
+                Ok(account)
+            })
+            .await?)
+    }
+
+    async fn list_accounts(&self, source: Option<Source>) -> anyhow::Result<Vec<Account>> {
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id, source, source_id, name, created_at, updated_at, person_id
+                     FROM accounts
+                     WHERE ?1 IS NULL OR source = ?1
+                     ORDER BY name",
+                )?;
+
+                let accounts = stmt
+                    .query_map(rusqlite::params![source.map(|s| s.as_str())], |row| {
+                        Account::try_from(row)
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(accounts)
+            })
+            .await?)
+    }
+
+    async fn account_messages(
+        &self,
+        account_id: i64,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Message>> {
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT m.id, m.source, m.source_id, m.channel_type, m.channel_id, m.account_id, m.role, m.content, m.created_at
+                     FROM messages m
+                     JOIN accounts a ON a.source = m.source AND a.source_id = m.account_id
+                     WHERE a.id = ?1
+                     ORDER BY m.created_at DESC
+                     LIMIT ?2",
+                )?;
+
+                let messages = stmt
+                    .query_map(rusqlite::params![account_id, limit], |row| {
+                        Message::try_from(row)
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(messages)
+            })
+            .await?)
+    }
+
+    async fn create_link_code(
+        &self,
+        account_id: i64,
+        code: &str,
+        ttl: std::time::Duration,
+    ) -> anyhow::Result<()> {
+        let code = code.to_string();
+        let expires_in = format!("+{} seconds", ttl.as_secs());
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+
+                tx.execute(
+                    "DELETE FROM link_codes WHERE account_id = ?1 OR expires_at <= CURRENT_TIMESTAMP",
+                    rusqlite::params![account_id],
+                )?;
+                tx.execute(
+                    "INSERT INTO link_codes (code, account_id, expires_at)
+                     VALUES (?1, ?2, datetime('now', ?3))",
+                    rusqlite::params![code, account_id, expires_in],
+                )?;
+
+                tx.commit()?;
+
+                Ok(())
+            })
+            .await?)
+    }
+
+    async fn confirm_link_code(
+        &self,
+        code: &str,
+        account_id: i64,
+    ) -> anyhow::Result<Option<Person>> {
+        let code = code.to_string();
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+
+                let issuer: Option<i64> = tx
+                    .query_row(
+                        "SELECT account_id FROM link_codes
+                         WHERE code = ?1 AND expires_at > CURRENT_TIMESTAMP",
+                        rusqlite::params![code],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+
+                let issuer = match issuer {
+                    Some(issuer) if issuer != account_id => issuer,
+                    _ => return Ok(None),
+                };
+
+                tx.execute(
+                    "DELETE FROM link_codes WHERE code = ?1",
+                    rusqlite::params![code],
+                )?;
+
+                let person_of = |id: i64| -> rusqlite::Result<Option<i64>> {
+                    tx.query_row(
+                        "SELECT person_id FROM accounts WHERE id = ?1",
+                        rusqlite::params![id],
+                        |row| row.get(0),
+                    )
+                };
+
+                let person_id = match (person_of(issuer)?, person_of(account_id)?) {
+                    (Some(kept), Some(merged)) if kept != merged => {
+                        tx.execute(
+                            "UPDATE accounts SET person_id = ?1 WHERE person_id = ?2",
+                            rusqlite::params![kept, merged],
+                        )?;
+                        tx.execute("DELETE FROM persons WHERE id = ?1", rusqlite::params![merged])?;
+                        kept
+                    }
+                    (Some(person_id), _) | (None, Some(person_id)) => person_id,
+                    (None, None) => tx.query_row(
+                        "INSERT INTO persons (name) SELECT name FROM accounts WHERE id = ?1 RETURNING id",
+                        rusqlite::params![issuer],
+                        |row| row.get(0),
+                    )?,
+                };
+
+                tx.execute(
+                    "UPDATE accounts SET person_id = ?1, updated_at = CURRENT_TIMESTAMP
+                     WHERE id IN (?2, ?3)",
+                    rusqlite::params![person_id, issuer, account_id],
+                )?;
+
+                let person = tx.query_row(
+                    "UPDATE persons SET updated_at = CURRENT_TIMESTAMP WHERE id = ?1
+                     RETURNING id, name, created_at, updated_at",
+                    rusqlite::params![person_id],
+                    |row| Person::try_from(row),
+                )?;
+
+                tx.commit()?;
+
+                Ok(Some(person))
+            })
+            .await?)
+    }
+
+    async fn unlink_account(&self, account_id: i64) -> anyhow::Result<()> {
+        Ok(self
+            .conn
+            .call(move |conn| {
+                conn.execute(
+                    "UPDATE accounts SET person_id = NULL, updated_at = CURRENT_TIMESTAMP WHERE id = ?1",
+                    rusqlite::params![account_id],
+                )?;
+
+                Ok(())
+            })
+            .await?)
+    }
+
+    async fn get_person(&self, id: i64) -> anyhow::Result<Option<Person>> {
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let person = conn
+                    .prepare("SELECT id, name, created_at, updated_at FROM persons WHERE id = ?1")?
+                    .query_row(rusqlite::params![id], |row| Person::try_from(row))
+                    .optional()?;
+
+                Ok(person)
+            })
+            .await?)
+    }
+
+    async fn person_accounts(&self, person_id: i64) -> anyhow::Result<Vec<Account>> {
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id, source, source_id, name, created_at, updated_at, person_id
+                     FROM accounts
+                     WHERE person_id = ?1
+                     ORDER BY source",
+                )?;
+
+                let accounts = stmt
+                    .query_map(rusqlite::params![person_id], |row| Account::try_from(row))?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(accounts)
+            })
+            .await?)
+    }
+
+    async fn person_messages(&self, person_id: i64, limit: usize) -> anyhow::Result<Vec<Message>> {
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT m.id, m.source, m.source_id, m.channel_type, m.channel_id, m.account_id, m.role, m.content, m.created_at
+                     FROM messages m
+                     JOIN accounts a ON a.source = m.source AND a.source_id = m.account_id
+                     WHERE a.person_id = ?1
+                     ORDER BY m.created_at DESC
+                     LIMIT ?2",
+                )?;
+
+                let messages = stmt
+                    .query_map(rusqlite::params![person_id, limit], |row| {
+                        Message::try_from(row)
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(messages)
+            })
+            .await?)
+    }
+
+    async fn purge_account(&self, account_id: i64) -> anyhow::Result<Option<PurgedAccount>> {
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+
+                let Some((source, source_id)) = tx
+                    .query_row(
+                        "SELECT source, source_id FROM accounts WHERE id = ?1",
+                        rusqlite::params![account_id],
+                        |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+                    )
+                    .optional()?
+                else {
+                    return Ok(None);
+                };
+
+                let channel_ids = tx
+                    .prepare(
+                        "SELECT DISTINCT channel_id FROM messages WHERE source = ?1 AND account_id = ?2",
+                    )?
+                    .query_map(rusqlite::params![source, source_id], |row| row.get(0))?
+                    .collect::<Result<Vec<String>, _>>()?;
+
+                let purged = PurgedAccount {
+                    messages: delete_messages(
+                        &tx,
+                        "source = ?1 AND account_id = ?2",
+                        rusqlite::params![source, source_id],
+                    )?,
+                    // Channel summaries are rebuilt from the remaining messages
+                    summaries: delete_summaries(
+                        &tx,
+                        "(scope = 'account' AND scope_id = ?1)
+                         OR (scope = 'channel' AND scope_id IN (SELECT value FROM json_each(?2)))",
+                        rusqlite::params![
+                            account_id.to_string(),
+                            serde_json::to_string(&channel_ids).unwrap_or_default()
+                        ],
+                    )?,
+                    facts: tx.execute(
+                        "DELETE FROM facts WHERE account_id = ?1",
+                        rusqlite::params![account_id],
+                    )?,
+                };
+
+                tx.execute(
+                    "DELETE FROM link_codes WHERE account_id = ?1",
+                    rusqlite::params![account_id],
+                )?;
+                tx.execute(
+                    "DELETE FROM accounts WHERE id = ?1",
+                    rusqlite::params![account_id],
+                )?;
+
+                tx.commit()?;
+
+                Ok(Some(purged))
+            })
+            .await?)
+    }
+
+    async fn upsert_channel(&self, channel: ChannelMetadata) -> anyhow::Result<Channel> {
+        Ok(self
+            .conn
+            .call(move |conn| {
+                conn.query_row(
+                    "INSERT INTO channels (channel_id, channel_type, source, name, guild_id, guild_name, topic, created_at, updated_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, CURRENT_TIMESTAMP, CURRENT_TIMESTAMP)
+                     ON CONFLICT (channel_id) DO UPDATE SET
+                         channel_type = excluded.channel_type,
+                         name = COALESCE(excluded.name, name),
+                         guild_id = COALESCE(excluded.guild_id, guild_id),
+                         guild_name = COALESCE(excluded.guild_name, guild_name),
+                         topic = COALESCE(excluded.topic, topic),
+                         updated_at = CURRENT_TIMESTAMP
+                     RETURNING id, channel_id, channel_type, source, name, guild_id, guild_name, topic, created_at, updated_at",
+                    rusqlite::params![
+                        channel.channel_id,
+                        channel.channel_type.as_str(),
+                        channel.source.as_str(),
+                        channel.name,
+                        channel.guild_id,
+                        channel.guild_name,
+                        channel.topic,
+                    ],
+                    |row| Channel::try_from(row),
+                )
+                .map_err(tokio_rusqlite::Error::from)
+            })
+            .await?)
+    }
+
+    async fn get_channel(&self, id: i64) -> anyhow::Result<Option<Channel>> {
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id, channel_id, channel_type, source, name, guild_id, guild_name, topic, created_at, updated_at FROM channels WHERE id = ?1",
+                )?;
+
+                let channel = stmt
+                    .query_row(rusqlite::params![id], |row| Channel::try_from(row))
+                    .optional()?;
+
+                Ok(channel)
+            })
+            .await?)
+    }
+
+    async fn get_channels_by_source(&self, source: &str) -> anyhow::Result<Vec<Channel>> {
+        let source = source.to_string();
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id, channel_id, channel_type, source, name, guild_id, guild_name, topic, created_at, updated_at FROM channels WHERE source = ?1"
+                )?;
+
+                let channels = stmt.query_map(rusqlite::params![source], |row| {
+                    Channel::try_from(row)
+                }).and_then(|mapped_rows| {
+                    mapped_rows.collect::<Result<Vec<Channel>, _>>()
+                })?;
+
+                Ok(channels)
+            })
+            .await?)
+    }
+
+    async fn channel_settings(&self, channel_id: &str) -> anyhow::Result<Option<ChannelSettings>> {
+        let channel_id = channel_id.to_string();
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let settings = conn
+                    .prepare(
+                        "SELECT channel_id, enabled, attention_overrides, allowed_tools, persona_override,
+                                document_filter
+                         FROM channel_settings
+                         WHERE channel_id = ?1",
+                    )?
+                    .query_row(rusqlite::params![channel_id], |row| {
+                        ChannelSettings::try_from(row)
+                    })
+                    .optional()?;
+
+                Ok(settings)
+            })
+            .await?)
+    }
+
+    async fn set_channel_settings(&self, settings: ChannelSettings) -> anyhow::Result<()> {
+        let attention_overrides = serde_json::to_string(&settings.attention)?;
+        let allowed_tools = settings
+            .allowed_tools
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()?;
+        let document_filter = settings
+            .document_filter
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()?;
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO channel_settings (channel_id, enabled, attention_overrides, allowed_tools, persona_override, document_filter)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                     ON CONFLICT (channel_id) DO UPDATE SET
+                         enabled = excluded.enabled,
+                         attention_overrides = excluded.attention_overrides,
+                         allowed_tools = excluded.allowed_tools,
+                         persona_override = excluded.persona_override,
+                         document_filter = excluded.document_filter,
+                         updated_at = CURRENT_TIMESTAMP",
+                    rusqlite::params![
+                        settings.channel_id,
+                        settings.enabled,
+                        attention_overrides,
+                        allowed_tools,
+                        settings.persona_override,
+                        document_filter,
+                    ],
+                )?;
+
+                Ok(())
+            })
+            .await?)
+    }
+
+    async fn insert_message(&self, msg: Message) -> anyhow::Result<Option<StoreKey>> {
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+
+                // First upsert the channel
+                tx.execute(
+                    "INSERT INTO channels (channel_id, channel_type, source, name, created_at, updated_at)
+                     VALUES (?1, ?2, ?3, NULL, CURRENT_TIMESTAMP, CURRENT_TIMESTAMP)
+                     ON CONFLICT (channel_id) DO UPDATE SET
+                     updated_at = CURRENT_TIMESTAMP",
+                    [
+                        &msg.channel_id,
+                        &msg.channel_type.as_str().to_string(),
+                        &msg.source.as_str().to_string(),
+                    ],
+                )?;
+
+                let inserted = tx.execute(
+                    "INSERT INTO messages (id, source, source_id, channel_type, channel_id, account_id, role, content, created_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+                     ON CONFLICT (id) DO NOTHING",
+                    rusqlite::params![
+                        msg.id,
+                        msg.source.as_str(),
+                        msg.source_id,
+                        msg.channel_type.as_str(),
+                        msg.channel_id,
+                        msg.account_id,
+                        msg.role,
+                        msg.content,
+                        msg.created_at.to_rfc3339(),
+                    ],
+                )?;
+                let key = (inserted > 0).then(|| StoreKey::from(tx.last_insert_rowid()));
+
+                tx.commit()?;
+
+                Ok(key)
+            })
+            .await?)
+    }
+
+    async fn get_message(&self, id: i64) -> anyhow::Result<Option<Message>> {
+        Ok(self
+            .conn
+            .call(move |conn| {
+                Ok(conn.prepare("SELECT id, source, source_id, channel_type, channel_id, account_id, role, content, created_at FROM messages WHERE id = ?1")?
+                    .query_row(rusqlite::params![id], |row| {
+                        Message::try_from(row)
+                    }).optional()?)
+            })
+            .await?)
+    }
+
+    async fn find_message(
+        &self,
+        source: Source,
+        id: &str,
+    ) -> anyhow::Result<Option<(StoreKey, Message)>> {
+        let id = id.to_string();
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let message = conn
+                    .query_row(
+                        "SELECT id, source, source_id, channel_type, channel_id, account_id, role, content, created_at, rowid
+                         FROM messages WHERE source = ?1 AND id = ?2",
+                        rusqlite::params![source.as_str(), id],
+                        |row| Ok((StoreKey::from(row.get::<_, i64>(9)?), Message::try_from(row)?)),
+                    )
+                    .optional()?;
+
+                Ok(message)
+            })
+            .await?)
+    }
+
+    async fn messages_by_key(
+        &self,
+        keys: Vec<StoreKey>,
+    ) -> anyhow::Result<Vec<(StoreKey, Message)>> {
+        let rowids = rowids(&keys)?;
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let messages = conn
+                    .prepare(
+                        "SELECT id, source, source_id, channel_type, channel_id, account_id, role, content, created_at, rowid
+                         FROM messages
+                         WHERE rowid IN (SELECT value FROM json_each(?1))",
+                    )?
+                    .query_map([rowids], |row| {
+                        Ok((StoreKey::from(row.get::<_, i64>(9)?), Message::try_from(row)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(messages)
+            })
+            .await?)
+    }
+
+    async fn messages_after(
+        &self,
+        after: Option<StoreKey>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(StoreKey, String)>> {
+        let after = after.as_ref().map(rowid).transpose()?.unwrap_or(0);
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let messages = conn
+                    .prepare(
+                        "SELECT rowid, content FROM messages WHERE rowid > ?1 ORDER BY rowid LIMIT ?2",
+                    )?
+                    .query_map(rusqlite::params![after, limit], |row| {
+                        Ok((StoreKey::from(row.get::<_, i64>(0)?), row.get(1)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(messages)
+            })
+            .await?)
+    }
+
+    async fn search_messages(&self, query: &str, n: usize) -> anyhow::Result<Vec<StoreKey>> {
+        let Some(query) = fts_query(query) else {
+            return Ok(Vec::new());
+        };
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let keys = conn
+                    .prepare(
+                        "SELECT rowid FROM messages_fts
+                         WHERE messages_fts MATCH ?1
+                         ORDER BY bm25(messages_fts)
+                         LIMIT ?2",
+                    )?
+                    .query_map(rusqlite::params![query, n], |row| {
+                        Ok(StoreKey::from(row.get::<_, i64>(0)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(keys)
+            })
+            .await?)
+    }
+
+    async fn recent_messages(
+        &self,
+        channel_id: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Message>> {
+        let channel_id = channel_id.to_string();
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id, source, source_id, channel_type, channel_id, account_id, role, content, created_at
+                     FROM messages
+                     WHERE channel_id = ?1
+                     ORDER BY created_at DESC
+                     LIMIT ?2",
+                )?;
+
+                let messages = stmt
+                    .query_map(rusqlite::params![channel_id, limit], |row| {
+                        Message::try_from(row)
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(messages)
+            })
+            .await?)
+    }
+
+    async fn channel_history(
+        &self,
+        channel_id: &str,
+        limit: i64,
+    ) -> anyhow::Result<Vec<HistoryEntry>> {
+        let channel_id = channel_id.to_string();
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT m.id, m.account_id, COALESCE(a.name, m.account_id), m.role, m.content, m.created_at
+                     FROM messages m
+                     LEFT JOIN accounts a ON a.source = m.source AND a.source_id = m.account_id
+                     WHERE m.channel_id = ?1
+                     ORDER BY m.created_at DESC
+                     LIMIT ?2",
+                )?;
+                let mut messages = stmt
+                    .query_map(rusqlite::params![channel_id, limit], |row| {
+                        HistoryEntry::try_from(row)
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                messages.reverse();
+                Ok(messages)
+            })
+            .await?)
+    }
+
+    async fn edit_message(
+        &self,
+        source: Source,
+        id: &str,
+        content: &str,
+    ) -> anyhow::Result<Option<(StoreKey, MessageRevision)>> {
+        let id = id.to_string();
+        let content = content.to_string();
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let source = source.as_str();
+
+                let Some((rowid, previous_content)) = tx
+                    .query_row(
+                        "SELECT rowid, content FROM messages WHERE source = ?1 AND id = ?2",
+                        rusqlite::params![source, id],
+                        |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+                    )
+                    .optional()?
+                else {
+                    return Ok(None);
+                };
+
+                if previous_content == content {
+                    return Ok(None);
+                }
+
+                tx.execute(
+                    "UPDATE messages SET content = ?2 WHERE rowid = ?1",
+                    rusqlite::params![rowid, content],
+                )?;
+                let revision = tx.query_row(
+                    "INSERT INTO message_revisions (source, message_id, content)
+                     VALUES (?1, ?2, ?3)
+                     RETURNING id, message_id, content, edited_at",
+                    rusqlite::params![source, id, previous_content],
+                    |row| MessageRevision::try_from(row),
+                )?;
+
+                tx.commit()?;
+
+                Ok(Some((StoreKey::from(rowid), revision)))
+            })
+            .await?)
+    }
+
+    async fn message_revisions(
+        &self,
+        source: Source,
+        id: &str,
+    ) -> anyhow::Result<Vec<MessageRevision>> {
+        let message_id = id.to_string();
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id, message_id, content, edited_at
+                     FROM message_revisions
+                     WHERE source = ?1 AND message_id = ?2
+                     ORDER BY id",
+                )?;
+
+                let revisions = stmt
+                    .query_map(rusqlite::params![source.as_str(), message_id], |row| {
+                        MessageRevision::try_from(row)
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(revisions)
+            })
+            .await?)
+    }
+
+    async fn delete_message(&self, source: Source, id: &str) -> anyhow::Result<Option<DeletedRow>> {
+        let id = id.to_string();
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let deleted = delete_messages(
+                    &tx,
+                    "source = ?1 AND id = ?2",
+                    rusqlite::params![source.as_str(), id],
+                )?;
+                tx.commit()?;
+
+                Ok(deleted.into_iter().next())
+            })
+            .await?)
+    }
+
+    async fn delete_expired_messages(
+        &self,
+        policy: RetentionPolicy,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<Vec<DeletedRow>> {
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let source = policy.source.as_ref().map(Source::as_str);
+                let scope = "(?1 IS NULL OR source = ?1) AND (?2 IS NULL OR channel_id = ?2)";
+                let mut deleted = Vec::new();
+
+                if let Some(max_age_days) = policy.max_age_days {
+                    let cutoff = (now - chrono::Duration::days(max_age_days)).to_rfc3339();
+                    deleted.extend(delete_messages(
+                        &tx,
+                        &format!("{scope} AND created_at < ?3"),
+                        rusqlite::params![source, policy.channel_id, cutoff],
+                    )?);
+                }
+
+                if let Some(max_messages) = policy.max_messages {
+                    let filter = format!(
+                        "rowid IN (
+                            SELECT message_rowid FROM (
+                                SELECT rowid AS message_rowid,
+                                    ROW_NUMBER() OVER (PARTITION BY channel_id ORDER BY created_at DESC) AS position
+                                FROM messages
+                                WHERE {scope}
+                            )
+                            WHERE position > ?3
+                        )"
+                    );
+                    deleted.extend(delete_messages(
+                        &tx,
+                        &filter,
+                        rusqlite::params![source, policy.channel_id, max_messages],
+                    )?);
+                }
+
+                tx.commit()?;
+
+                Ok(deleted)
+            })
+            .await?)
+    }
+
+    async fn assign_conversation(
+        &self,
+        msg: &Message,
+        hints: ConversationHints,
+        gap: chrono::Duration,
+    ) -> anyhow::Result<Conversation> {
+        let message_id = msg.id.clone();
+        let source = msg.source.as_str();
+        let channel_id = msg.channel_id.clone();
+        let sent_at = msg.created_at.to_rfc3339();
+        let active_since = (msg.created_at - gap).to_rfc3339();
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+
+                let mut conversation_id: Option<i64> = match &hints.reply_to {
+                    Some(reply_to) => tx
+                        .query_row(
+                            "SELECT conversation_id FROM messages
+                             WHERE id = ?1 AND source = ?2 AND conversation_id IS NOT NULL",
+                            rusqlite::params![reply_to, source],
+                            |row| row.get(0),
+                        )
+                        .optional()?,
+                    None => None,
+                };
+
+                if conversation_id.is_none() {
+                    conversation_id = match &hints.thread_id {
+                        Some(thread_id) => tx
+                            .query_row(
+                                "SELECT id FROM conversations
+                                 WHERE source = ?1 AND thread_id = ?2
+                                 ORDER BY updated_at DESC
+                                 LIMIT 1",
+                                rusqlite::params![source, thread_id],
+                                |row| row.get(0),
+                            )
+                            .optional()?,
+                        None => tx
+                            .query_row(
+                                "SELECT id FROM conversations
+                                 WHERE channel_id = ?1 AND thread_id IS NULL AND updated_at >= ?2
+                                 ORDER BY updated_at DESC
+                                 LIMIT 1",
+                                rusqlite::params![channel_id, active_since],
+                                |row| row.get(0),
+                            )
+                            .optional()?,
+                    };
+                }
+
+                let conversation_id = match conversation_id {
+                    Some(id) => id,
+                    None => tx.query_row(
+                        "INSERT INTO conversations (source, channel_id, thread_id, created_at, updated_at)
+                         VALUES (?1, ?2, ?3, ?4, ?4)
+                         RETURNING id",
+                        rusqlite::params![source, channel_id, hints.thread_id, sent_at],
+                        |row| row.get(0),
+                    )?,
+                };
+
+                tx.execute(
+                    "UPDATE messages SET conversation_id = ?1 WHERE id = ?2 AND source = ?3",
+                    rusqlite::params![conversation_id, message_id, source],
+                )?;
+
+                let conversation = tx.query_row(
+                    "UPDATE conversations SET updated_at = MAX(updated_at, ?2) WHERE id = ?1
+                     RETURNING id, source, channel_id, thread_id, title, created_at, updated_at",
+                    rusqlite::params![conversation_id, sent_at],
+                    |row| Conversation::try_from(row),
+                )?;
+
+                tx.commit()?;
+
+                Ok(conversation)
+            })
+            .await?)
+    }
+
+    async fn get_conversation(&self, id: i64) -> anyhow::Result<Option<Conversation>> {
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let conversation = conn
+                    .prepare(
+                        "SELECT id, source, channel_id, thread_id, title, created_at, updated_at
+                         FROM conversations
+                         WHERE id = ?1",
+                    )?
+                    .query_row(rusqlite::params![id], |row| Conversation::try_from(row))
+                    .optional()?;
+
+                Ok(conversation)
+            })
+            .await?)
+    }
+
+    async fn channel_conversations(
+        &self,
+        channel_id: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Conversation>> {
+        let channel_id = channel_id.to_string();
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id, source, channel_id, thread_id, title, created_at, updated_at
+                     FROM conversations
+                     WHERE channel_id = ?1
+                     ORDER BY updated_at DESC
+                     LIMIT ?2",
+                )?;
+
+                let conversations = stmt
+                    .query_map(rusqlite::params![channel_id, limit], |row| {
+                        Conversation::try_from(row)
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(conversations)
+            })
+            .await?)
+    }
+
+    async fn conversation_messages(
+        &self,
+        conversation_id: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<HistoryEntry>> {
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT m.id, m.account_id, COALESCE(a.name, m.account_id), m.role, m.content, m.created_at
+                     FROM messages m
+                     LEFT JOIN accounts a ON a.source = m.source AND a.source_id = m.account_id
+                     WHERE m.conversation_id = ?1
+                     ORDER BY m.created_at DESC
+                     LIMIT ?2",
+                )?;
+                let mut messages = stmt
+                    .query_map(rusqlite::params![conversation_id, limit], |row| {
+                        HistoryEntry::try_from(row)
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                messages.reverse();
+                Ok(messages)
+            })
+            .await?)
+    }
+
+    async fn set_conversation_title(&self, id: i64, title: &str) -> anyhow::Result<()> {
+        let title = title.to_string();
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                conn.execute(
+                    "UPDATE conversations SET title = ?2 WHERE id = ?1",
+                    rusqlite::params![id, title],
+                )?;
+
+                Ok(())
+            })
+            .await?)
+    }
+
+    async fn scope_messages(
+        &self,
+        scope: &SummaryScope,
+        after: Option<chrono::DateTime<chrono::Utc>>,
+        limit: i64,
+    ) -> anyhow::Result<Vec<HistoryEntry>> {
+        let scope_filter = match scope {
+            SummaryScope::Channel(_) => "m.channel_id = ?1",
+            SummaryScope::Account(_) => "a.id = ?1",
+        };
+        let scope_id = scope.id();
+        // Compared as text, in the format messages are stored with
+        let after = after.map(|after| after.to_rfc3339());
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT m.id, m.account_id, COALESCE(a.name, m.account_id), m.role, m.content, m.created_at
+                     FROM messages m
+                     LEFT JOIN accounts a ON a.source = m.source AND a.source_id = m.account_id
+                     WHERE {scope_filter} AND (?2 IS NULL OR m.created_at > ?2)
+                     ORDER BY m.created_at
+                     LIMIT ?3"
+                ))?;
+                let messages = stmt
+                    .query_map(rusqlite::params![scope_id, after, limit], |row| {
+                        HistoryEntry::try_from(row)
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(messages)
+            })
+            .await?)
+    }
+
+    async fn insert_summary(&self, summary: Summary) -> anyhow::Result<StoreKey> {
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let rowid: i64 = conn.query_row(
+                    "INSERT INTO summaries (id, scope, scope_id, content, message_count, covered_until, created_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                     RETURNING rowid",
+                    rusqlite::params![
+                        summary.id,
+                        summary.scope,
+                        summary.scope_id,
+                        summary.content,
+                        summary.message_count,
+                        summary.covered_until.to_rfc3339(),
+                        summary.created_at.to_rfc3339(),
+                    ],
+                    |row| row.get(0),
+                )?;
+
+                Ok(StoreKey::from(rowid))
+            })
+            .await?)
+    }
+
+    async fn latest_summary(&self, scope: &SummaryScope) -> anyhow::Result<Option<Summary>> {
+        let kind = scope.kind();
+        let scope_id = scope.id();
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let summary = conn
+                    .prepare(
+                        "SELECT id, scope, scope_id, content, message_count, covered_until, created_at
+                         FROM summaries
+                         WHERE scope = ?1 AND scope_id = ?2
+                         ORDER BY covered_until DESC
+                         LIMIT 1",
+                    )?
+                    .query_row(rusqlite::params![kind, scope_id], |row| {
+                        Summary::try_from(row)
+                    })
+                    .optional()?;
+
+                Ok(summary)
+            })
+            .await?)
+    }
+
+    async fn summaries_by_key(
+        &self,
+        keys: Vec<StoreKey>,
+    ) -> anyhow::Result<Vec<(StoreKey, Summary)>> {
+        let rowids = rowids(&keys)?;
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let summaries = conn
+                    .prepare(
+                        "SELECT id, scope, scope_id, content, message_count, covered_until, created_at, rowid
+                         FROM summaries
+                         WHERE rowid IN (SELECT value FROM json_each(?1))",
+                    )?
+                    .query_map([rowids], |row| {
+                        Ok((StoreKey::from(row.get::<_, i64>(7)?), Summary::try_from(row)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(summaries)
+            })
+            .await?)
+    }
+
+    async fn add_fact(&self, fact: NewFact) -> anyhow::Result<Fact> {
+        Ok(self
+            .conn
+            .call(move |conn| {
+                conn.query_row(
+                    "INSERT INTO facts (account_id, content, category, confidence, source_message_id)
+                     VALUES (?1, ?2, ?3, ?4, ?5)
+                     ON CONFLICT (account_id, content) DO UPDATE SET
+                         category = COALESCE(excluded.category, category),
+                         confidence = MAX(confidence, excluded.confidence),
+                         source_message_id = excluded.source_message_id,
+                         updated_at = CURRENT_TIMESTAMP
+                     RETURNING id, account_id, content, category, confidence, source_message_id, created_at, updated_at",
+                    rusqlite::params![
+                        fact.account_id,
+                        fact.content,
+                        fact.category,
+                        fact.confidence,
+                        fact.source_message_id,
+                    ],
+                    |row| Fact::try_from(row),
+                )
+                .map_err(tokio_rusqlite::Error::from)
+            })
+            .await?)
+    }
+
+    async fn account_facts(
+        &self,
+        account_id: i64,
+        min_confidence: f64,
+    ) -> anyhow::Result<Vec<Fact>> {
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id, account_id, content, category, confidence, source_message_id, created_at, updated_at
+                     FROM facts
+                     WHERE confidence >= ?2 AND account_id IN (
+                         SELECT linked.id FROM accounts linked, accounts account
+                         WHERE account.id = ?1
+                           AND (linked.id = account.id OR linked.person_id = account.person_id)
+                     )
+                     ORDER BY confidence DESC, updated_at DESC",
+                )?;
+
+                let facts = stmt
+                    .query_map(rusqlite::params![account_id, min_confidence], |row| {
+                        Fact::try_from(row)
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(facts)
+            })
+            .await?)
+    }
+
+    async fn delete_fact(&self, id: i64) -> anyhow::Result<bool> {
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let deleted =
+                    conn.execute("DELETE FROM facts WHERE id = ?1", rusqlite::params![id])?;
+                Ok(deleted > 0)
+            })
+            .await?)
+    }
+
+    async fn delete_account_facts(&self, account_id: i64) -> anyhow::Result<usize> {
+        Ok(self
+            .conn
+            .call(move |conn| {
+                Ok(conn.execute(
+                    "DELETE FROM facts WHERE account_id = ?1",
+                    rusqlite::params![account_id],
+                )?)
+            })
+            .await?)
+    }
+
+    async fn facts_extracted_until(
+        &self,
+        account_id: i64,
+    ) -> anyhow::Result<Option<chrono::DateTime<chrono::Utc>>> {
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let until: Option<Option<chrono::DateTime<chrono::Utc>>> = conn
+                    .query_row(
+                        "SELECT facts_extracted_until FROM accounts WHERE id = ?1",
+                        rusqlite::params![account_id],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+
+                Ok(until.flatten())
+            })
+            .await?)
+    }
+
+    async fn set_facts_extracted_until(
+        &self,
+        account_id: i64,
+        until: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<()> {
+        Ok(self
+            .conn
+            .call(move |conn| {
+                conn.execute(
+                    "UPDATE accounts SET facts_extracted_until = ?2 WHERE id = ?1",
+                    rusqlite::params![account_id, until.to_rfc3339()],
+                )?;
+
+                Ok(())
+            })
+            .await?)
+    }
+
+    async fn set_retention_policy(&self, policy: RetentionPolicy) -> anyhow::Result<()> {
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let source = policy.source.as_ref().map(Source::as_str);
+
+                tx.execute(
+                    "DELETE FROM retention_policies WHERE source IS ?1 AND channel_id IS ?2",
+                    rusqlite::params![source, policy.channel_id],
+                )?;
+                tx.execute(
+                    "INSERT INTO retention_policies (source, channel_id, max_age_days, max_messages)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    rusqlite::params![
+                        source,
+                        policy.channel_id,
+                        policy.max_age_days,
+                        policy.max_messages,
+                    ],
+                )?;
+
+                tx.commit()?;
+
+                Ok(())
+            })
+            .await?)
+    }
+
+    async fn remove_retention_policy(
+        &self,
+        source: Option<Source>,
+        channel_id: Option<&str>,
+    ) -> anyhow::Result<bool> {
+        let channel_id = channel_id.map(String::from);
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let deleted = conn.execute(
+                    "DELETE FROM retention_policies WHERE source IS ?1 AND channel_id IS ?2",
+                    rusqlite::params![source.as_ref().map(Source::as_str), channel_id],
+                )?;
+
+                Ok(deleted > 0)
+            })
+            .await?)
+    }
+
+    async fn retention_policies(&self) -> anyhow::Result<Vec<RetentionPolicy>> {
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT source, channel_id, max_age_days, max_messages FROM retention_policies",
+                )?;
+
+                let policies = stmt
+                    .query_map([], |row| RetentionPolicy::try_from(row))?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(policies)
+            })
+            .await?)
+    }
+}
+
+#[async_trait]
+impl VectorStore for SqliteStorage {
+    async fn create_table(&self, table: &str, dimensions: usize) -> anyhow::Result<()> {
+        let sql = format!(
+            "CREATE VIRTUAL TABLE IF NOT EXISTS {table}_embeddings USING vec0(embedding float[{dimensions}])"
+        );
+
+        Ok(self
+            .conn
+            .call(move |conn| Ok(conn.execute_batch(&sql)?))
+            .await?)
+    }
+
+    async fn drop_table(&self, table: &str) -> anyhow::Result<()> {
+        let sql = format!("DROP TABLE IF EXISTS {table}_embeddings");
+
+        Ok(self
+            .conn
+            .call(move |conn| Ok(conn.execute_batch(&sql)?))
+            .await?)
+    }
+
+    async fn missing_vectors(
+        &self,
+        table: &str,
+        keys: Vec<StoreKey>,
+    ) -> anyhow::Result<Vec<StoreKey>> {
+        let sql = format!(
+            "SELECT value FROM json_each(?1)
+             WHERE value NOT IN (SELECT rowid FROM {table}_embeddings)"
+        );
+        let rowids = rowids(&keys)?;
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let missing = conn
+                    .prepare(&sql)?
+                    .query_map([rowids], |row| Ok(StoreKey::from(row.get::<_, i64>(0)?)))?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(missing)
+            })
+            .await?)
+    }
+
+    async fn add_vectors(
+        &self,
+        table: &str,
+        vectors: Vec<(StoreKey, Vec<f64>)>,
+    ) -> anyhow::Result<usize> {
+        let sql = format!(
+            "INSERT INTO {table}_embeddings (rowid, embedding)
+             SELECT ?1, ?2 WHERE ?1 NOT IN (SELECT rowid FROM {table}_embeddings)"
+        );
+        let vectors = vector_rows(vectors)?;
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let mut added = 0;
+
+                for (rowid, vector) in &vectors {
+                    added += tx.execute(&sql, rusqlite::params![rowid, vector])?;
+                }
+
+                tx.commit()?;
+                Ok(added)
+            })
+            .await?)
+    }
+
+    async fn replace_vectors(
+        &self,
+        table: &str,
+        vectors: Vec<(StoreKey, Vec<f64>)>,
+    ) -> anyhow::Result<()> {
+        // vec0 tables don't support upserts
+        let delete = format!("DELETE FROM {table}_embeddings WHERE rowid = ?1");
+        let insert = format!("INSERT INTO {table}_embeddings (rowid, embedding) VALUES (?1, ?2)");
+        let vectors = vector_rows(vectors)?;
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+
+                for (rowid, vector) in &vectors {
+                    tx.execute(&delete, [rowid])?;
+                    tx.execute(&insert, rusqlite::params![rowid, vector])?;
+                }
+
+                tx.commit()?;
+                Ok(())
+            })
+            .await?)
+    }
+
+    async fn nearest(
+        &self,
+        table: &str,
+        query: Vec<f64>,
+        n: usize,
+        among: Option<Vec<StoreKey>>,
+    ) -> anyhow::Result<Vec<(StoreKey, f64)>> {
+        // The restriction applies inside the KNN query, so that a selective
+        // one still gets its `n` nearest vectors
+        let mut params: Vec<rusqlite::types::Value> =
+            vec![serde_json::to_string(&query)?.into(), (n as i64).into()];
+        let restriction = match among {
+            Some(keys) if keys.is_empty() => return Ok(Vec::new()),
+            Some(keys) => {
+                params.push(rowids(&keys)?.into());
+                "AND rowid IN (SELECT value FROM json_each(?3))"
+            }
+            None => "",
+        };
+        let sql = format!(
+            "SELECT rowid, distance FROM {table}_embeddings
+             WHERE embedding MATCH ?1 AND k = ?2 {restriction}
+             ORDER BY distance"
+        );
+
+        Ok(self
+            .conn
+            .call(move |conn| {
+                let nearest = conn
+                    .prepare(&sql)?
+                    .query_map(rusqlite::params_from_iter(params), |row| {
+                        Ok((StoreKey::from(row.get::<_, i64>(0)?), row.get(1)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(nearest)
+            })
+            .await?)
+    }
+
+    async fn remove_vectors(&self, table: &str, keys: Vec<StoreKey>) -> anyhow::Result<usize> {
+        let sql = format!(
+            "DELETE FROM {table}_embeddings WHERE rowid IN (SELECT value FROM json_each(?1))"
+        );
+        let rowids = rowids(&keys)?;
+
+        Ok(self
+            .conn
+            .call(move |conn| Ok(conn.execute(&sql, [rowids])?))
+            .await?)
+    }
+}
+
+/// Vectors by rowid, as the JSON arrays sqlite-vec reads.
+fn vector_rows(vectors: Vec<(StoreKey, Vec<f64>)>) -> anyhow::Result<Vec<(i64, String)>> {
+    vectors
+        .into_iter()
+        .map(|(key, vector)| Ok((rowid(&key)?, serde_json::to_string(&vector)?)))
+        .collect()
+}
@@ -0,0 +1,370 @@
+/// Limits of the chunks produced by a [`MarkdownChunker`].
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ChunkerConfig {
+    /// Upper bound on the tokens of a chunk.
+    pub max_tokens: usize,
+    /// Tokens of the previous chunk repeated at the start of the next one when
+    /// a section has to be split.
+    pub overlap_tokens: usize,
+}
+
+impl Default for ChunkerConfig {
+    fn default() -> Self {
+        Self {
+            max_tokens: 400,
+            overlap_tokens: 50,
+        }
+    }
+}
+
+/// A piece of a document, small enough to be embedded on its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub content: String,
+    /// Byte range of the chunk in the document.
+    pub start: usize,
+    pub end: usize,
+    /// Headings the chunk is nested under, outermost first.
+    pub heading_path: Vec<String>,
+}
+
+/// Splits markdown along its structure: every heading starts a new chunk,
+/// paragraphs are kept whole when they fit and fenced code blocks are never
+/// cut unless a single one exceeds the limit.
+#[derive(Clone)]
+pub struct MarkdownChunker {
+    config: ChunkerConfig,
+    count_tokens: fn(&str) -> usize,
+}
+
+impl Default for MarkdownChunker {
+    fn default() -> Self {
+        Self::new(ChunkerConfig::default())
+    }
+}
+
+impl MarkdownChunker {
+    pub fn new(config: ChunkerConfig) -> Self {
+        Self {
+            config,
+            count_tokens: estimate_tokens,
+        }
+    }
+
+    /// Counts tokens with the embedding model's tokenizer instead of the
+    /// default estimate.
+    pub fn with_token_counter(mut self, count_tokens: fn(&str) -> usize) -> Self {
+        self.count_tokens = count_tokens;
+        self
+    }
+
+    pub fn config(&self) -> &ChunkerConfig {
+        &self.config
+    }
+
+    /// A chunker with other limits and the same token counter.
+    pub fn with_config(&self, config: ChunkerConfig) -> Self {
+        Self {
+            config,
+            count_tokens: self.count_tokens,
+        }
+    }
+
+    pub fn chunk(&self, text: &str) -> Vec<Chunk> {
+        let max_tokens = self.config.max_tokens.max(1);
+        let tokens = |block: &Block| (self.count_tokens)(&text[block.start..block.end]);
+
+        let mut chunks = Vec::new();
+        let mut current: Vec<(Block, usize)> = Vec::new();
+        let mut current_tokens = 0;
+
+        for block in blocks(text)
+            .into_iter()
+            .flat_map(|block| self.split_oversized(text, block, max_tokens))
+        {
+            let block_tokens = tokens(&block);
+
+            if !current.is_empty() && (block.heading || current_tokens + block_tokens > max_tokens)
+            {
+                chunks.push(chunk_of(text, &current));
+
+                if block.heading {
+                    current.clear();
+                } else {
+                    current = self.overlap(current);
+                }
+                current_tokens = current.iter().map(|(_, tokens)| tokens).sum();
+            }
+
+            while !current.is_empty() && current_tokens + block_tokens > max_tokens {
+                current_tokens -= current.remove(0).1;
+            }
+
+            current.push((block, block_tokens));
+            current_tokens += block_tokens;
+        }
+
+        if !current.is_empty() {
+            chunks.push(chunk_of(text, &current));
+        }
+
+        chunks
+    }
+
+    /// The trailing blocks of a full chunk that fit in the overlap, never the
+    /// whole chunk.
+    fn overlap(&self, mut blocks: Vec<(Block, usize)>) -> Vec<(Block, usize)> {
+        let mut tokens = 0;
+        let mut keep = 0;
+
+        for (_, block_tokens) in blocks.iter().skip(1).rev() {
+            if tokens + block_tokens > self.config.overlap_tokens {
+                break;
+            }
+            tokens += block_tokens;
+            keep += 1;
+        }
+
+        blocks.split_off(blocks.len() - keep)
+    }
+
+    /// Splits a block over the limit, at the last line break that fits or
+    /// else the last whitespace. A single word over the limit is kept whole.
+    fn split_oversized(&self, text: &str, block: Block, max_tokens: usize) -> Vec<Block> {
+        let fits = |start: usize, end: usize| (self.count_tokens)(&text[start..end]) <= max_tokens;
+
+        if fits(block.start, block.end) {
+            return vec![block];
+        }
+
+        let mut pieces: Vec<Block> = Vec::new();
+        let mut start = block.start;
+
+        while start < block.end {
+            let mut line_cut = None;
+            let mut word_cut = None;
+            let mut first_cut = None;
+
+            for (offset, c) in text[start..block.end].char_indices() {
+                if !c.is_whitespace() {
+                    continue;
+                }
+
+                let cut = start + offset + c.len_utf8();
+                first_cut.get_or_insert(cut);
+                if !fits(start, cut) {
+                    break;
+                }
+                word_cut = Some(cut);
+                if c == '\n' {
+                    line_cut = Some(cut);
+                }
+            }
+
+            let cut = if fits(start, block.end) {
+                block.end
+            } else {
+                line_cut.or(word_cut).or(first_cut).unwrap_or(block.end)
+            };
+            pieces.push(block.piece(start, cut, pieces.is_empty()));
+
+            start = cut + text[cut..block.end].len() - text[cut..block.end].trim_start().len();
+        }
+
+        pieces
+    }
+}
+
+/// Roughly four characters per token, close enough for English prose and
+/// code with the usual BPE tokenizers.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// A heading, paragraph or fenced code block.
+#[derive(Debug, Clone)]
+struct Block {
+    start: usize,
+    end: usize,
+    /// Whether the block is a heading, which starts a new chunk.
+    heading: bool,
+    heading_path: Vec<String>,
+}
+
+impl Block {
+    fn piece(&self, start: usize, end: usize, first: bool) -> Block {
+        Block {
+            start,
+            end,
+            heading: self.heading && first,
+            heading_path: self.heading_path.clone(),
+        }
+    }
+}
+
+fn blocks(text: &str) -> Vec<Block> {
+    let mut blocks = Vec::new();
+    let mut headings: Vec<(usize, String)> = Vec::new();
+    let mut current: Option<(usize, usize)> = None;
+    let mut fence: Option<&str> = None;
+    let mut offset = 0;
+
+    let path = |headings: &[(usize, String)]| -> Vec<String> {
+        headings.iter().map(|(_, title)| title.clone()).collect()
+    };
+
+    for line in text.split_inclusive('\n') {
+        let start = offset;
+        offset += line.len();
+        let trimmed = line.trim();
+
+        if let Some(marker) = fence {
+            current = current.map(|(start, _)| (start, offset));
+            if trimmed.starts_with(marker) {
+                fence = None;
+                if let Some((start, end)) = current.take() {
+                    blocks.push(Block {
+                        start,
+                        end,
+                        heading: false,
+                        heading_path: path(&headings),
+                    });
+                }
+            }
+            continue;
+        }
+
+        let heading = heading_level(trimmed);
+        let fence_marker = ["```", "~~~"]
+            .into_iter()
+            .find(|marker| trimmed.starts_with(marker));
+
+        if trimmed.is_empty() || heading.is_some() || fence_marker.is_some() {
+            if let Some((start, end)) = current.take() {
+                blocks.push(Block {
+                    start,
+                    end,
+                    heading: false,
+                    heading_path: path(&headings),
+                });
+            }
+        }
+
+        if let Some(level) = heading {
+            while headings.last().is_some_and(|(last, _)| *last >= level) {
+                headings.pop();
+            }
+            headings.push((level, trimmed[level..].trim().to_string()));
+
+            blocks.push(Block {
+                start,
+                end: offset,
+                heading: true,
+                heading_path: path(&headings),
+            });
+        } else if let Some(marker) = fence_marker {
+            fence = Some(marker);
+            current = Some((start, offset));
+        } else if !trimmed.is_empty() {
+            current = Some(current.map_or((start, offset), |(start, _)| (start, offset)));
+        }
+    }
+
+    // An unterminated fence runs to the end of the document
+    if let Some((start, end)) = current {
+        blocks.push(Block {
+            start,
+            end,
+            heading: false,
+            heading_path: path(&headings),
+        });
+    }
+
+    blocks
+}
+
+/// Level of an ATX heading such as `## Usage`.
+fn heading_level(line: &str) -> Option<usize> {
+    let level = line.chars().take_while(|c| *c == '#').count();
+
+    ((1..=6).contains(&level) && line[level..].starts_with(' ')).then_some(level)
+}
+
+fn chunk_of(text: &str, blocks: &[(Block, usize)]) -> Chunk {
+    let (first, _) = &blocks[0];
+    let (last, _) = &blocks[blocks.len() - 1];
+    let content = text[first.start..last.end].trim_end();
+
+    Chunk {
+        content: content.to_string(),
+        start: first.start,
+        end: first.start + content.len(),
+        heading_path: first.heading_path.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunker(max_tokens: usize, overlap_tokens: usize) -> MarkdownChunker {
+        MarkdownChunker::new(ChunkerConfig {
+            max_tokens,
+            overlap_tokens,
+        })
+        // One token per word keeps the expectations readable
+        .with_token_counter(|text| text.split_whitespace().count())
+    }
+
+    #[test]
+    fn test_headings_start_chunks_with_their_path() {
+        let text = "# Guide\nIntro.\n\n## Install\nRun it.\n\n## Usage\nCall it.\n";
+        let chunks = chunker(100, 0).chunk(text);
+
+        let paths: Vec<_> = chunks.iter().map(|c| c.heading_path.join(" > ")).collect();
+        assert_eq!(paths, ["Guide", "Guide > Install", "Guide > Usage"]);
+        assert_eq!(chunks[1].content, "## Install\nRun it.");
+    }
+
+    #[test]
+    fn test_code_blocks_stay_whole() {
+        let text = "Setup:\n\n```rust\nlet a = 1;\n\nlet b = 2;\n```\n\nDone.\n";
+        let chunks = chunker(10, 0).chunk(text);
+
+        let contents: Vec<_> = chunks.iter().map(|c| c.content.as_str()).collect();
+        assert_eq!(
+            contents,
+            ["Setup:", "```rust\nlet a = 1;\n\nlet b = 2;\n```", "Done."]
+        );
+    }
+
+    #[test]
+    fn test_long_sections_split_with_overlap() {
+        let text = "one two three\n\nfour five\n\nsix seven eight\n";
+        let chunks = chunker(5, 2).chunk(text);
+
+        let contents: Vec<_> = chunks.iter().map(|c| c.content.as_str()).collect();
+        assert_eq!(
+            contents,
+            ["one two three\n\nfour five", "four five\n\nsix seven eight"]
+        );
+    }
+
+    #[test]
+    fn test_oversized_paragraphs_split_on_whitespace() {
+        let text = "a b c d e f g";
+        let chunks = chunker(3, 0).chunk(text);
+
+        let contents: Vec<_> = chunks.iter().map(|c| c.content.as_str()).collect();
+        assert_eq!(contents, ["a b c", "d e f", "g"]);
+    }
+
+    #[test]
+    fn test_byte_ranges_point_into_the_document() {
+        let text = "# Título\n\nCafé ☕ au lait.\n\n## Más\n\nOtro párrafo.\n";
+        for chunk in chunker(4, 1).chunk(text) {
+            assert_eq!(&text[chunk.start..chunk.end], chunk.content);
+        }
+    }
+}
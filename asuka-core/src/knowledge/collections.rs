@@ -0,0 +1,79 @@
+use rusqlite::Connection;
+
+use super::backend::StoreKey;
+use super::chunking::ChunkerConfig;
+use super::reembed;
+use super::search::HybridConfig;
+
+/// Collection of the documents added without naming one.
+pub const DEFAULT_COLLECTION: &str = "default";
+
+/// Settings of a named collection of documents, such as "docs", "faq" or
+/// "changelog". Collections share the knowledge base's embedding model and
+/// vector table unless given their own with
+/// `KnowledgeBase::with_collection_model`.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct CollectionConfig {
+    /// How documents are split before they are embedded. The knowledge
+    /// base's token counter applies.
+    pub chunker: ChunkerConfig,
+    /// How keyword and vector search are combined.
+    pub retrieval: HybridConfig,
+}
+
+/// Name the embedding model of a collection is recorded under in
+/// `embedding_models`, and its vector table.
+pub(super) fn vector_table_name(collection: &str) -> String {
+    format!("collection_{collection}")
+}
+
+/// Collection names end up in table names, so they are kept to letters,
+/// digits and underscores.
+pub(super) fn is_valid_name(collection: &str) -> bool {
+    !collection.is_empty()
+        && collection
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Records the embedding model of a collection with its own. Returns
+/// whether its vector table holds vectors of another model, to be dropped.
+pub(super) fn record_model(
+    conn: &mut Connection,
+    collection: &str,
+    model: &str,
+    dimensions: i64,
+) -> Result<bool, rusqlite::Error> {
+    let tx = conn.transaction()?;
+    let table = vector_table_name(collection);
+
+    let stale = match reembed::embedding_model(&tx, &table)? {
+        Some(stored) if stored.model == model && stored.dimensions == dimensions => false,
+        stored => {
+            reembed::record_embedding_model(&tx, &table, model, dimensions)?;
+            stored.is_some()
+        }
+    };
+    // Deleted documents lost their vector through a trigger before vectors
+    // moved behind `VectorStore`
+    tx.execute_batch(&format!("DROP TRIGGER IF EXISTS {table}_delete"))?;
+
+    tx.commit()?;
+    Ok(stale)
+}
+
+/// Keys and contents of the documents of a collection.
+pub(super) fn documents(
+    conn: &Connection,
+    collection: &str,
+) -> Result<Vec<(StoreKey, String)>, rusqlite::Error> {
+    conn.prepare("SELECT rowid, content FROM documents WHERE collection = ?1 ORDER BY rowid")?
+        .query_map([collection], |row| {
+            Ok((
+                StoreKey::from(row.get::<_, i64>(0)?),
+                row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+            ))
+        })?
+        .collect()
+}
@@ -0,0 +1,519 @@
+//! Embedding model wrappers cutting down on embedding API calls. They wrap
+//! any `EmbeddingModel` and can be stacked, e.g. a cache in front of a
+//! batcher. Also home to a local embedder needing no API at all.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rig::embeddings::{Embedding, EmbeddingError, EmbeddingModel};
+use rig_sqlite::SqliteError;
+use sha2::{Digest, Sha256};
+use tokio::sync::oneshot;
+use tokio_rusqlite::Connection;
+use tracing::{debug, warn};
+
+struct PendingTexts {
+    texts: Vec<String>,
+    embeddings: oneshot::Sender<Result<Vec<Embedding>, String>>,
+}
+
+/// Groups the texts embedded concurrently within a short window into as few
+/// requests as the model allows, e.g. messages arriving in several channels
+/// at once.
+#[derive(Clone)]
+pub struct BatchingEmbeddingModel<E: EmbeddingModel> {
+    inner: E,
+    window: Duration,
+    pending: Arc<Mutex<Vec<PendingTexts>>>,
+}
+
+impl<E: EmbeddingModel> BatchingEmbeddingModel<E> {
+    pub fn new(inner: E, window: Duration) -> Self {
+        Self {
+            inner,
+            window,
+            pending: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Queues texts. Returns whether they opened a batch, which then has to
+    /// be flushed once the window closes.
+    fn push(
+        &self,
+        texts: Vec<String>,
+    ) -> (bool, oneshot::Receiver<Result<Vec<Embedding>, String>>) {
+        let (embeddings, receiver) = oneshot::channel();
+        let mut pending = self.pending.lock().unwrap();
+        let opened = pending.is_empty();
+
+        pending.push(PendingTexts { texts, embeddings });
+
+        (opened, receiver)
+    }
+
+    async fn embed_batch(&self, batch: Vec<PendingTexts>) {
+        let texts: Vec<String> = batch
+            .iter()
+            .flat_map(|pending| pending.texts.iter().cloned())
+            .collect();
+        debug!(
+            requests = batch.len(),
+            texts = texts.len(),
+            "Embedding text batch"
+        );
+
+        let mut embeddings = Vec::with_capacity(texts.len());
+        let mut failure = None;
+        for chunk in texts.chunks(E::MAX_DOCUMENTS.max(1)) {
+            match self.inner.embed_texts(chunk.to_vec()).await {
+                Ok(chunk) => embeddings.extend(chunk),
+                Err(err) => {
+                    failure = Some(err.to_string());
+                    break;
+                }
+            }
+        }
+
+        let mut embeddings = embeddings.into_iter();
+        for pending in batch {
+            let result = match &failure {
+                Some(err) => Err(err.clone()),
+                None => Ok(embeddings.by_ref().take(pending.texts.len()).collect()),
+            };
+            let _ = pending.embeddings.send(result);
+        }
+    }
+}
+
+impl<E: EmbeddingModel + 'static> EmbeddingModel for BatchingEmbeddingModel<E> {
+    const MAX_DOCUMENTS: usize = E::MAX_DOCUMENTS;
+
+    fn ndims(&self) -> usize {
+        self.inner.ndims()
+    }
+
+    async fn embed_texts(
+        &self,
+        texts: impl IntoIterator<Item = String> + Send,
+    ) -> Result<Vec<Embedding>, EmbeddingError> {
+        let texts: Vec<String> = texts.into_iter().collect();
+        if texts.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let (opened, embeddings) = self.push(texts);
+
+        // Flushed by a task of its own, as the caller that opened the batch
+        // may stop waiting for it
+        if opened {
+            let batcher = self.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(batcher.window).await;
+
+                let batch = std::mem::take(&mut *batcher.pending.lock().unwrap());
+                batcher.embed_batch(batch).await;
+            });
+        }
+
+        embeddings
+            .await
+            .map_err(|_| EmbeddingError::ProviderError("Embedding batch dropped".to_string()))?
+            .map_err(EmbeddingError::ProviderError)
+    }
+}
+
+/// Reuses the embeddings of texts embedded before with the same model,
+/// stored in the `embedding_cache` table by content hash. Spares the API
+/// calls for re-indexed documents and recurring messages.
+#[derive(Clone)]
+pub struct CachedEmbeddingModel<E: EmbeddingModel> {
+    inner: E,
+    conn: Connection,
+    model_name: String,
+}
+
+impl<E: EmbeddingModel> CachedEmbeddingModel<E> {
+    /// `model_name` keys the cache, embeddings of other models are never
+    /// returned.
+    pub fn new(inner: E, conn: Connection, model_name: impl Into<String>) -> Self {
+        Self {
+            inner,
+            conn,
+            model_name: model_name.into(),
+        }
+    }
+
+    /// Forgets the embeddings cached more than `max_age` ago. Returns how
+    /// many were removed.
+    pub async fn prune(&self, max_age: chrono::Duration) -> Result<usize, SqliteError> {
+        let cutoff = chrono::Utc::now() - max_age;
+
+        self.conn
+            .call(move |conn| {
+                Ok(conn.execute(
+                    "DELETE FROM embedding_cache WHERE datetime(created_at) < datetime(?1)",
+                    [cutoff.to_rfc3339()],
+                )?)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    async fn cached(&self, hashes: Vec<String>) -> HashMap<String, Vec<f64>> {
+        let model = self.model_name.clone();
+        let hashes = serde_json::to_string(&hashes).unwrap_or_default();
+
+        let cached = self
+            .conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT content_hash, embedding FROM embedding_cache
+                     WHERE model = ?1 AND content_hash IN (SELECT value FROM json_each(?2))",
+                )?;
+
+                let cached = stmt
+                    .query_map(rusqlite::params![model, hashes], |row| {
+                        Ok((row.get(0)?, decode_vector(&row.get::<_, Vec<u8>>(1)?)))
+                    })?
+                    .collect::<Result<HashMap<_, _>, _>>()?;
+
+                Ok(cached)
+            })
+            .await;
+
+        // The cache only saves calls, a broken one falls back to the model
+        cached.unwrap_or_else(|err| {
+            warn!(?err, "Failed to read the embedding cache");
+            HashMap::new()
+        })
+    }
+
+    async fn store(&self, embeddings: Vec<(String, Vec<f64>)>) {
+        let model = self.model_name.clone();
+
+        let stored = self
+            .conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                for (hash, vector) in &embeddings {
+                    tx.execute(
+                        "INSERT OR REPLACE INTO embedding_cache (model, content_hash, embedding)
+                         VALUES (?1, ?2, ?3)",
+                        rusqlite::params![model, hash, encode_vector(vector)],
+                    )?;
+                }
+                tx.commit()?;
+
+                Ok(())
+            })
+            .await;
+
+        if let Err(err) = stored {
+            warn!(?err, "Failed to update the embedding cache");
+        }
+    }
+}
+
+impl<E: EmbeddingModel> EmbeddingModel for CachedEmbeddingModel<E> {
+    const MAX_DOCUMENTS: usize = E::MAX_DOCUMENTS;
+
+    fn ndims(&self) -> usize {
+        self.inner.ndims()
+    }
+
+    async fn embed_texts(
+        &self,
+        texts: impl IntoIterator<Item = String> + Send,
+    ) -> Result<Vec<Embedding>, EmbeddingError> {
+        let texts: Vec<String> = texts.into_iter().collect();
+        let hashes: Vec<String> = texts.iter().map(|text| content_hash(text)).collect();
+
+        let mut cached = self.cached(hashes.clone()).await;
+
+        // Each distinct missing text is embedded once
+        let mut missing = Vec::new();
+        for (text, hash) in texts.iter().zip(&hashes) {
+            if !cached.contains_key(hash) && !missing.iter().any(|(h, _)| h == hash) {
+                missing.push((hash.clone(), text.clone()));
+            }
+        }
+
+        if !missing.is_empty() {
+            debug!(
+                cached = texts.len() - missing.len(),
+                missing = missing.len(),
+                "Embedding cache misses"
+            );
+
+            let (missing_hashes, missing_texts): (Vec<String>, Vec<String>) =
+                missing.into_iter().unzip();
+            let fresh: Vec<(String, Vec<f64>)> = missing_hashes
+                .into_iter()
+                .zip(self.inner.embed_texts(missing_texts).await?)
+                .map(|(hash, embedding)| (hash, embedding.vec))
+                .collect();
+
+            self.store(fresh.clone()).await;
+            cached.extend(fresh);
+        }
+
+        Ok(texts
+            .into_iter()
+            .zip(hashes)
+            .map(|(document, hash)| Embedding {
+                document,
+                vec: cached.get(&hash).cloned().unwrap_or_default(),
+            })
+            .collect())
+    }
+}
+
+/// Embeds texts locally by hashing their words and character trigrams into
+/// a fixed number of dimensions. Deterministic and free, but only captures
+/// lexical overlap: meant for tests and running without network access,
+/// not as a replacement for a real model.
+#[derive(Debug, Clone, Copy)]
+pub struct HashingEmbeddingModel {
+    ndims: usize,
+}
+
+impl HashingEmbeddingModel {
+    /// Name to open a `KnowledgeBase` with. Changing how texts are hashed
+    /// must bump it, as stored vectors would no longer match.
+    pub const NAME: &'static str = "hashing-v1";
+
+    pub fn new(ndims: usize) -> Self {
+        Self {
+            ndims: ndims.max(1),
+        }
+    }
+
+    /// Unit vector of the text's hashed features, each word and trigram
+    /// adding ±1 to the dimension it falls in.
+    pub fn embed(&self, text: &str) -> Vec<f64> {
+        let mut vector = vec![0.0; self.ndims];
+
+        for word in text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+        {
+            let word = word.to_lowercase();
+            self.add_feature(&mut vector, word.as_bytes());
+
+            // Trigrams of the padded word relate inflections, e.g. "embed"
+            // and "embedding"
+            let chars: Vec<char> = format!(" {word} ").chars().collect();
+            for trigram in chars.windows(3) {
+                let trigram: String = trigram.iter().collect();
+                self.add_feature(&mut vector, trigram.as_bytes());
+            }
+        }
+
+        let norm = vector.iter().map(|value| value * value).sum::<f64>().sqrt();
+        if norm > 0.0 {
+            vector.iter_mut().for_each(|value| *value /= norm);
+        }
+
+        vector
+    }
+
+    fn add_feature(&self, vector: &mut [f64], feature: &[u8]) {
+        let hash = fnv1a(feature);
+        let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+        vector[(hash % self.ndims as u64) as usize] += sign;
+    }
+}
+
+impl Default for HashingEmbeddingModel {
+    fn default() -> Self {
+        Self::new(384)
+    }
+}
+
+impl EmbeddingModel for HashingEmbeddingModel {
+    const MAX_DOCUMENTS: usize = 1024;
+
+    fn ndims(&self) -> usize {
+        self.ndims
+    }
+
+    async fn embed_texts(
+        &self,
+        texts: impl IntoIterator<Item = String> + Send,
+    ) -> Result<Vec<Embedding>, EmbeddingError> {
+        Ok(texts
+            .into_iter()
+            .map(|document| Embedding {
+                vec: self.embed(&document),
+                document,
+            })
+            .collect())
+    }
+}
+
+/// 64-bit FNV-1a, stable across platforms and Rust versions unlike the
+/// standard library's hasher.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Hex SHA-256 the cache is keyed by.
+pub(super) fn content_hash(text: &str) -> String {
+    hex::encode(Sha256::digest(text.as_bytes()))
+}
+
+/// Little-endian `f64`s, exact unlike the `f32`s of the vector tables.
+fn encode_vector(vector: &[f64]) -> Vec<u8> {
+    vector
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect()
+}
+
+fn decode_vector(bytes: &[u8]) -> Vec<f64> {
+    bytes
+        .chunks_exact(8)
+        .map(|chunk| f64::from_le_bytes(chunk.try_into().unwrap()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// Hashing embedder counting its calls and the texts it embedded.
+    #[derive(Clone, Default)]
+    struct CountingModel {
+        calls: Arc<AtomicUsize>,
+        texts: Arc<AtomicUsize>,
+    }
+
+    impl EmbeddingModel for CountingModel {
+        const MAX_DOCUMENTS: usize = 1024;
+
+        fn ndims(&self) -> usize {
+            16
+        }
+
+        async fn embed_texts(
+            &self,
+            texts: impl IntoIterator<Item = String> + Send,
+        ) -> Result<Vec<Embedding>, EmbeddingError> {
+            let texts: Vec<String> = texts.into_iter().collect();
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.texts.fetch_add(texts.len(), Ordering::SeqCst);
+
+            HashingEmbeddingModel::new(16).embed_texts(texts).await
+        }
+    }
+
+    async fn cache_conn() -> Connection {
+        let conn = Connection::open_in_memory().await.unwrap();
+        conn.call(|conn| {
+            super::super::migrations::migrate(conn)
+                .map_err(|e| tokio_rusqlite::Error::Other(Box::new(e)))
+        })
+        .await
+        .unwrap();
+        conn
+    }
+
+    #[tokio::test]
+    async fn test_batched_callers_get_their_own_embeddings() {
+        let inner = CountingModel::default();
+        let model = BatchingEmbeddingModel::new(inner.clone(), Duration::from_millis(20));
+        let texts =
+            |caller: usize| vec![format!("first of {caller}"), format!("second of {caller}")];
+
+        let results =
+            futures::future::join_all((0..4).map(|caller| model.embed_texts(texts(caller)))).await;
+
+        let hashing = HashingEmbeddingModel::new(16);
+        for (caller, embeddings) in results.into_iter().enumerate() {
+            let embeddings = embeddings.unwrap();
+            assert_eq!(embeddings.len(), 2);
+            for (embedding, text) in embeddings.iter().zip(texts(caller)) {
+                assert_eq!(embedding.vec, hashing.embed(&text));
+                assert_eq!(embedding.document, text);
+            }
+        }
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_batch_outlives_the_caller_that_opened_it() {
+        let model =
+            BatchingEmbeddingModel::new(CountingModel::default(), Duration::from_millis(20));
+
+        let opener = model.embed_texts(vec!["opener".to_string()]);
+        assert!(tokio::time::timeout(Duration::from_millis(1), opener)
+            .await
+            .is_err());
+
+        let embeddings = tokio::time::timeout(
+            Duration::from_secs(1),
+            model.embed_texts(vec!["joiner".to_string()]),
+        )
+        .await
+        .expect("batch was never flushed")
+        .unwrap();
+        assert_eq!(embeddings[0].document, "joiner");
+    }
+
+    #[tokio::test]
+    async fn test_cache_embeds_only_missing_texts() {
+        let inner = CountingModel::default();
+        let conn = cache_conn().await;
+        let model = CachedEmbeddingModel::new(inner.clone(), conn.clone(), "counting");
+
+        let texts = |texts: &[&str]| {
+            texts
+                .iter()
+                .map(|text| text.to_string())
+                .collect::<Vec<_>>()
+        };
+        let first = model
+            .embed_texts(texts(&["gm", "wagmi", "gm"]))
+            .await
+            .unwrap();
+        assert_eq!(inner.texts.load(Ordering::SeqCst), 2);
+        assert_eq!(first[0].vec, first[2].vec);
+
+        let second = model.embed_texts(texts(&["wagmi", "gn"])).await.unwrap();
+        assert_eq!(inner.texts.load(Ordering::SeqCst), 3);
+        assert_eq!(second[0].vec, first[1].vec);
+        assert_eq!(second[1].document, "gn");
+
+        // Embeddings of another model are never reused
+        let other = CachedEmbeddingModel::new(inner.clone(), conn, "other");
+        other.embed_texts(texts(&["gm"])).await.unwrap();
+        assert_eq!(inner.texts.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_vectors_round_trip_through_bytes() {
+        let vector = vec![0.1, -2.5, f64::MIN_POSITIVE, 1e300];
+        assert_eq!(decode_vector(&encode_vector(&vector)), vector);
+    }
+
+    #[test]
+    fn test_hashing_embeddings_are_deterministic_and_lexical() {
+        let model = HashingEmbeddingModel::new(256);
+        let cosine = |a: &[f64], b: &[f64]| a.iter().zip(b).map(|(x, y)| x * y).sum::<f64>();
+
+        let query = model.embed("How do I request VRF randomness?");
+        assert_eq!(query, model.embed("How do I request VRF randomness?"));
+        assert!((cosine(&query, &query) - 1.0).abs() < 1e-9);
+
+        let related = model.embed("Requesting randomness from the VRF provider");
+        let unrelated = model.embed("Paymaster sponsors transaction fees");
+        assert!(cosine(&query, &related) > cosine(&query, &unrelated));
+
+        assert!(model.embed("").iter().all(|value| *value == 0.0));
+    }
+}
@@ -7,4 +7,72 @@ impl std::fmt::Display for ConversionError {
     }
 }
 
-impl std::error::Error for ConversionError {} 
\ No newline at end of file
+impl std::error::Error for ConversionError {}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MigrationError {
+    #[error("Database schema version {found} is newer than the latest version {supported} supported by this build")]
+    DatabaseTooNew { found: i64, supported: i64 },
+
+    #[error("Migration {version} failed: {source}")]
+    Failed {
+        version: i64,
+        source: rusqlite::Error,
+    },
+
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ArchiveError {
+    #[error("Archive format version {0} is not supported")]
+    UnsupportedFormat(i64),
+
+    #[error(
+        "Archive schema version {found} is newer than the database schema version {supported}"
+    )]
+    SchemaTooNew { found: i64, supported: i64 },
+
+    #[error("Cannot import into a non-empty knowledge base, table {0} has rows")]
+    NotEmpty(String),
+
+    #[error("Unknown column {column} in table {table}")]
+    UnknownColumn { table: String, column: String },
+
+    #[error("Invalid embeddings file {0}")]
+    InvalidEmbeddings(String),
+
+    #[error("Arrow error: {0}")]
+    Arrow(#[from] arrow_schema::ArrowError),
+
+    #[error("{0}")]
+    EmbeddingModel(#[from] EmbeddingModelError),
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Migration error: {0}")]
+    Migration(#[from] MigrationError),
+
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum EmbeddingModelError {
+    #[error("Table {table} holds vectors of {stored_model} ({stored_dimensions} dimensions), not {model} ({dimensions} dimensions), re-embed it first")]
+    Mismatch {
+        table: String,
+        stored_model: String,
+        stored_dimensions: i64,
+        model: String,
+        dimensions: i64,
+    },
+
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+}
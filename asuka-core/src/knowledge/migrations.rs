@@ -0,0 +1,504 @@
+use rusqlite::{Connection, OptionalExtension};
+use tracing::{debug, info};
+
+use super::error::MigrationError;
+
+/// A schema change, applied once and recorded in `schema_version`.
+///
+/// Migrations are append-only: never edit one that has shipped, add a new one
+/// instead. Statements should still be idempotent (`IF NOT EXISTS`) so
+/// databases created before versioning existed can be brought under it.
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Initial schema",
+        sql: "
+        CREATE TABLE IF NOT EXISTS accounts (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            source_id TEXT NOT NULL UNIQUE,
+            source TEXT NOT NULL,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        );
+        CREATE INDEX IF NOT EXISTS idx_source_id_source ON accounts(source_id, source);
+
+        CREATE TABLE IF NOT EXISTS channels (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            channel_id TEXT NOT NULL UNIQUE,
+            channel_type TEXT NOT NULL,
+            source TEXT NOT NULL,
+            name TEXT,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        );
+        CREATE INDEX IF NOT EXISTS idx_channel_id_type ON channels(channel_id, channel_type);
+
+        -- Vector store tables, the `*_embeddings` virtual tables are created
+        -- by rig-sqlite since their shape depends on the embedding model
+        CREATE TABLE IF NOT EXISTS documents (
+            id TEXT PRIMARY KEY,
+            source_id TEXT,
+            content TEXT,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        );
+        CREATE INDEX IF NOT EXISTS idx_documents_source_id ON documents(source_id);
+
+        CREATE TABLE IF NOT EXISTS messages (
+            id TEXT PRIMARY KEY,
+            source TEXT,
+            source_id TEXT,
+            channel_type TEXT,
+            channel_id TEXT,
+            account_id TEXT,
+            role TEXT,
+            content TEXT,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        );
+        CREATE INDEX IF NOT EXISTS idx_messages_source_id ON messages(source_id);
+        CREATE INDEX IF NOT EXISTS idx_messages_channel_id ON messages(channel_id);
+        CREATE INDEX IF NOT EXISTS idx_messages_account_id ON messages(account_id);
+    ",
+    },
+    Migration {
+        version: 2,
+        description: "Key accounts by source and source ID",
+        // Platform IDs are only unique within a platform, and messages refer
+        // to their author by (source, account_id)
+        sql: "
+            CREATE TABLE accounts_v2 (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                source TEXT NOT NULL,
+                source_id TEXT NOT NULL,
+                name TEXT NOT NULL,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                UNIQUE (source, source_id)
+            );
+            INSERT INTO accounts_v2 (id, source, source_id, name, created_at, updated_at)
+                SELECT id, source, source_id, name, created_at, updated_at FROM accounts;
+            DROP TABLE accounts;
+            ALTER TABLE accounts_v2 RENAME TO accounts;
+
+            CREATE INDEX IF NOT EXISTS idx_messages_source_account_id ON messages(source, account_id);
+        ",
+    },
+    Migration {
+        version: 3,
+        description: "Link accounts of the same person across platforms",
+        sql: "
+            CREATE TABLE persons (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            );
+
+            ALTER TABLE accounts ADD COLUMN person_id INTEGER REFERENCES persons(id) ON DELETE SET NULL;
+            CREATE INDEX idx_accounts_person_id ON accounts(person_id);
+
+            CREATE TABLE link_codes (
+                code TEXT PRIMARY KEY,
+                account_id INTEGER NOT NULL REFERENCES accounts(id) ON DELETE CASCADE,
+                expires_at TIMESTAMP NOT NULL,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE INDEX idx_link_codes_account_id ON link_codes(account_id);
+        ",
+    },
+    Migration {
+        version: 4,
+        description: "Channel metadata and per-channel settings",
+        sql: "
+            ALTER TABLE channels ADD COLUMN guild_id TEXT;
+            ALTER TABLE channels ADD COLUMN guild_name TEXT;
+            ALTER TABLE channels ADD COLUMN topic TEXT;
+
+            -- JSON columns hold AttentionOverrides and the list of allowed tools
+            CREATE TABLE channel_settings (
+                channel_id TEXT PRIMARY KEY,
+                enabled INTEGER NOT NULL DEFAULT 1,
+                attention_overrides TEXT,
+                allowed_tools TEXT,
+                persona_override TEXT,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            );
+        ",
+    },
+    Migration {
+        version: 5,
+        description: "Group messages into conversations",
+        sql: "
+            CREATE TABLE conversations (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                source TEXT NOT NULL,
+                channel_id TEXT NOT NULL,
+                thread_id TEXT,
+                title TEXT,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                -- Time of the latest message, in the format of messages.created_at
+                updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE INDEX idx_conversations_channel_id ON conversations(channel_id, updated_at);
+            CREATE INDEX idx_conversations_thread_id ON conversations(source, thread_id);
+
+            ALTER TABLE messages ADD COLUMN conversation_id INTEGER REFERENCES conversations(id);
+            CREATE INDEX idx_messages_conversation_id ON messages(conversation_id);
+        ",
+    },
+    Migration {
+        version: 6,
+        description: "Rolling summaries of channels and accounts",
+        sql: "
+            -- Vector store table, rig-sqlite adds summaries_embeddings
+            CREATE TABLE summaries (
+                id TEXT PRIMARY KEY,
+                scope TEXT NOT NULL,
+                scope_id TEXT NOT NULL,
+                content TEXT NOT NULL,
+                message_count INTEGER NOT NULL,
+                covered_until TIMESTAMP NOT NULL,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE INDEX idx_summaries_scope ON summaries(scope, scope_id, covered_until);
+        ",
+    },
+    Migration {
+        version: 7,
+        description: "Facts remembered about accounts",
+        sql: "
+            CREATE TABLE facts (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                account_id INTEGER NOT NULL REFERENCES accounts(id) ON DELETE CASCADE,
+                content TEXT NOT NULL,
+                category TEXT,
+                confidence REAL NOT NULL,
+                source_message_id TEXT,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                UNIQUE (account_id, content)
+            );
+            CREATE INDEX idx_facts_account_id ON facts(account_id);
+
+            -- Creation time of the last message facts were extracted from
+            ALTER TABLE accounts ADD COLUMN facts_extracted_until TIMESTAMP;
+        ",
+    },
+    Migration {
+        version: 8,
+        description: "Message retention policies",
+        sql: "
+            -- A NULL source or channel_id matches every source or channel
+            CREATE TABLE retention_policies (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                source TEXT,
+                channel_id TEXT,
+                max_age_days INTEGER,
+                max_messages INTEGER,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE UNIQUE INDEX idx_retention_policies_scope
+                ON retention_policies(COALESCE(source, ''), COALESCE(channel_id, ''));
+        ",
+    },
+    Migration {
+        version: 9,
+        description: "Message revisions",
+        sql: "
+            -- Earlier contents of edited messages, the current one stays in messages
+            CREATE TABLE message_revisions (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                source TEXT NOT NULL,
+                message_id TEXT NOT NULL,
+                content TEXT NOT NULL,
+                edited_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE INDEX idx_message_revisions_message_id
+                ON message_revisions(source, message_id);
+        ",
+    },
+    Migration {
+        version: 10,
+        description: "Full-text indexes of documents and messages",
+        sql: "
+            -- External content tables, kept in sync with their rows by triggers.
+            -- Underscores are part of tokens so identifiers match as a whole
+            CREATE VIRTUAL TABLE documents_fts USING fts5(
+                content,
+                content = 'documents',
+                content_rowid = 'rowid',
+                tokenize = \"unicode61 tokenchars '_'\"
+            );
+            CREATE TRIGGER documents_fts_insert AFTER INSERT ON documents BEGIN
+                INSERT INTO documents_fts (rowid, content) VALUES (new.rowid, new.content);
+            END;
+            CREATE TRIGGER documents_fts_delete AFTER DELETE ON documents BEGIN
+                INSERT INTO documents_fts (documents_fts, rowid, content)
+                VALUES ('delete', old.rowid, old.content);
+            END;
+            CREATE TRIGGER documents_fts_update AFTER UPDATE OF content ON documents BEGIN
+                INSERT INTO documents_fts (documents_fts, rowid, content)
+                VALUES ('delete', old.rowid, old.content);
+                INSERT INTO documents_fts (rowid, content) VALUES (new.rowid, new.content);
+            END;
+            INSERT INTO documents_fts (documents_fts) VALUES ('rebuild');
+
+            CREATE VIRTUAL TABLE messages_fts USING fts5(
+                content,
+                content = 'messages',
+                content_rowid = 'rowid',
+                tokenize = \"unicode61 tokenchars '_'\"
+            );
+            CREATE TRIGGER messages_fts_insert AFTER INSERT ON messages BEGIN
+                INSERT INTO messages_fts (rowid, content) VALUES (new.rowid, new.content);
+            END;
+            CREATE TRIGGER messages_fts_delete AFTER DELETE ON messages BEGIN
+                INSERT INTO messages_fts (messages_fts, rowid, content)
+                VALUES ('delete', old.rowid, old.content);
+            END;
+            CREATE TRIGGER messages_fts_update AFTER UPDATE OF content ON messages BEGIN
+                INSERT INTO messages_fts (messages_fts, rowid, content)
+                VALUES ('delete', old.rowid, old.content);
+                INSERT INTO messages_fts (rowid, content) VALUES (new.rowid, new.content);
+            END;
+            INSERT INTO messages_fts (messages_fts) VALUES ('rebuild');
+        ",
+    },
+    Migration {
+        version: 11,
+        description: "Document chunks",
+        sql: "
+            -- Rows of documents are chunks of a source document
+            ALTER TABLE documents ADD COLUMN parent_id TEXT;
+            ALTER TABLE documents ADD COLUMN chunk_index INTEGER NOT NULL DEFAULT 0;
+            ALTER TABLE documents ADD COLUMN start_offset INTEGER NOT NULL DEFAULT 0;
+            ALTER TABLE documents ADD COLUMN end_offset INTEGER NOT NULL DEFAULT 0;
+            ALTER TABLE documents ADD COLUMN heading_path TEXT NOT NULL DEFAULT '';
+
+            -- Documents embedded whole so far are their own single chunk
+            UPDATE documents SET parent_id = id, end_offset = length(CAST(content AS BLOB));
+
+            CREATE INDEX idx_documents_parent_id ON documents(parent_id, chunk_index);
+        ",
+    },
+    Migration {
+        version: 12,
+        description: "Document content hashes",
+        sql: "
+            -- SHA-256 of the whole source document, the same on all its chunks.
+            -- Existing documents have none and get re-embedded once
+            ALTER TABLE documents ADD COLUMN content_hash TEXT NOT NULL DEFAULT '';
+        ",
+    },
+    Migration {
+        version: 13,
+        description: "Document metadata and per-channel document filters",
+        sql: "
+            -- JSON DocumentMetadata, the same on all chunks of a document
+            ALTER TABLE documents ADD COLUMN metadata TEXT NOT NULL DEFAULT '{}';
+            -- JSON DocumentFilter, NULL retrieves from every document
+            ALTER TABLE channel_settings ADD COLUMN document_filter TEXT;
+        ",
+    },
+    Migration {
+        version: 14,
+        description: "Document collections",
+        sql: "
+            ALTER TABLE documents ADD COLUMN collection TEXT NOT NULL DEFAULT 'default';
+
+            CREATE INDEX idx_documents_collection ON documents(collection, source_id);
+        ",
+    },
+    Migration {
+        version: 15,
+        description: "Embedding models and re-embedding jobs",
+        sql: "
+            -- Model that produced the vectors in each vector store table
+            CREATE TABLE embedding_models (
+                table_name TEXT PRIMARY KEY,
+                model TEXT NOT NULL,
+                dimensions INTEGER NOT NULL,
+                updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            );
+
+            -- Progress of re-embedding a table into {table}_embeddings_next,
+            -- rows up to last_rowid are done
+            CREATE TABLE reembed_jobs (
+                table_name TEXT PRIMARY KEY,
+                model TEXT NOT NULL,
+                dimensions INTEGER NOT NULL,
+                last_rowid INTEGER NOT NULL DEFAULT 0,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            );
+        ",
+    },
+    Migration {
+        version: 16,
+        description: "Embedding cache",
+        sql: "
+            -- Vectors as little-endian f64s, keyed by the SHA-256 of the text
+            CREATE TABLE embedding_cache (
+                model TEXT NOT NULL,
+                content_hash TEXT NOT NULL,
+                embedding BLOB NOT NULL,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                PRIMARY KEY (model, content_hash)
+            );
+        ",
+    },
+];
+
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+/// Returns the version recorded in `schema_version`, 0 for an unversioned database.
+pub fn current_version(conn: &Connection) -> Result<i64, MigrationError> {
+    let has_version_table = conn
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'schema_version'",
+            [],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+
+    if !has_version_table {
+        return Ok(0);
+    }
+
+    Ok(conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+        [],
+        |row| row.get(0),
+    )?)
+}
+
+/// Applies every pending migration, each in its own transaction, and returns
+/// the resulting schema version.
+pub fn migrate(conn: &mut Connection) -> Result<i64, MigrationError> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        );",
+    )?;
+
+    let current = current_version(conn)?;
+    let latest = latest_version();
+
+    if current > latest {
+        return Err(MigrationError::DatabaseTooNew {
+            found: current,
+            supported: latest,
+        });
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        info!(
+            version = migration.version,
+            description = migration.description,
+            "Applying database migration"
+        );
+
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration.sql)
+            .map_err(|source| MigrationError::Failed {
+                version: migration.version,
+                source,
+            })?;
+        tx.execute(
+            "INSERT INTO schema_version (version, description) VALUES (?1, ?2)",
+            rusqlite::params![migration.version, migration.description],
+        )?;
+        tx.commit()?;
+    }
+
+    debug!(version = latest, "Database schema is up to date");
+    Ok(latest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate_is_idempotent() {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        assert_eq!(migrate(&mut conn).unwrap(), latest_version());
+        assert_eq!(migrate(&mut conn).unwrap(), latest_version());
+
+        let applied: i64 = conn
+            .query_row("SELECT COUNT(*) FROM schema_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(applied, MIGRATIONS.len() as i64);
+    }
+
+    #[test]
+    fn test_migrate_adopts_unversioned_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE documents (id TEXT PRIMARY KEY, source_id TEXT, content TEXT, created_at TIMESTAMP);
+             INSERT INTO documents (id, source_id, content) VALUES ('readme', 'github', 'hello');",
+        )
+        .unwrap();
+
+        assert_eq!(current_version(&conn).unwrap(), 0);
+        migrate(&mut conn).unwrap();
+
+        let content: String = conn
+            .query_row(
+                "SELECT content FROM documents WHERE id = 'readme'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(content, "hello");
+    }
+
+    #[test]
+    fn test_accounts_are_unique_per_source() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+
+        conn.execute_batch(
+            "INSERT INTO accounts (source, source_id, name) VALUES ('discord', '42', 'alice');
+             INSERT INTO accounts (source, source_id, name) VALUES ('telegram', '42', 'bob');",
+        )
+        .unwrap();
+
+        assert!(conn
+            .execute(
+                "INSERT INTO accounts (source, source_id, name) VALUES ('discord', '42', 'carol')",
+                [],
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_migrate_rejects_newer_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        conn.execute(
+            "INSERT INTO schema_version (version, description) VALUES (?1, 'from the future')",
+            [latest_version() + 1],
+        )
+        .unwrap();
+
+        assert!(matches!(
+            migrate(&mut conn),
+            Err(MigrationError::DatabaseTooNew { .. })
+        ));
+    }
+}
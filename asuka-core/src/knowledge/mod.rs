@@ -1,9 +1,31 @@
 mod types;
+mod archive;
+mod backend;
+mod chunking;
+mod collections;
+mod embedding;
 mod store;
 mod models;
 mod error;
+mod search;
+mod reembed;
+pub mod migrations;
 
 pub use types::{Source, ChannelType, MessageMetadata, MessageContent};
-pub use store::KnowledgeBase;
-pub use models::{Document, Message, Account, Channel, Conversation};
-pub use error::ConversionError; 
\ No newline at end of file
+pub use store::{delete_cached_embeddings, delete_documents, KnowledgeBase};
+pub use backend::{
+    DeletedRow, PurgedAccount, RelationalStore, SqliteStorage, StoreKey, VectorStore,
+};
+pub use models::{
+    Account, AttentionOverrides, Channel, ChannelMetadata, ChannelSettings, Conversation,
+    ConversationHints, Document, DocumentChunk, DocumentFilter, DocumentMetadata,
+    EmbeddingModelRecord, Fact, HistoryEntry, IndexReport, Message, MessageRevision, NewFact,
+    Person, PurgeReport, RetentionPolicy, Summary, SummaryScope,
+};
+pub use error::{ArchiveError, ConversionError, EmbeddingModelError, MigrationError};
+pub use archive::{ArchiveManifest, ExportOptions, ARCHIVE_FORMAT_VERSION};
+pub use search::{fts_query, HybridConfig, HybridIndex};
+pub use reembed::{ReembedJob, ReembedReport};
+pub use embedding::{BatchingEmbeddingModel, CachedEmbeddingModel, HashingEmbeddingModel};
+pub use collections::{CollectionConfig, DEFAULT_COLLECTION};
+pub use chunking::{estimate_tokens, Chunk, ChunkerConfig, MarkdownChunker}; 
\ No newline at end of file
@@ -1,7 +1,9 @@
+use super::chunking::Chunk;
 use super::types::{ChannelType, Source};
 use rig_sqlite::{Column, ColumnValue, SqliteVectorStoreTable};
 use rig::Embed;
 use rusqlite::Row;
+use sha2::{Digest, Sha256};
 
 #[derive(Embed, Clone, Debug)]
 pub struct Document {
@@ -10,27 +12,165 @@ pub struct Document {
     #[embed]
     pub content: String,
     pub created_at: chrono::DateTime<chrono::Utc>,
+    pub metadata: DocumentMetadata,
 }
 
-#[derive(Debug, serde::Deserialize)]
+/// Descriptive fields of a document, used to filter retrieval. Changing
+/// them doesn't re-embed the document.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct DocumentMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Natural language, e.g. `en`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Repository the document was loaded from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repo: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit_sha: Option<String>,
+    /// Anything else, matched by `DocumentFilter::extra`.
+    #[serde(skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    pub extra: std::collections::BTreeMap<String, String>,
+}
+
+/// Restricts retrieval to the documents matching every set field.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct DocumentFilter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collection: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repo: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    /// Tags the document must all have.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    pub extra: std::collections::BTreeMap<String, String>,
+}
+
+impl Document {
+    /// Hex SHA-256 of the content, used to skip re-embedding unchanged
+    /// documents.
+    pub fn content_hash(&self) -> String {
+        hex::encode(Sha256::digest(self.content.as_bytes()))
+    }
+}
+
+impl DocumentMetadata {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|_| "{}".to_string())
+    }
+}
+
+/// A piece of a `Document`, the unit documents are embedded and retrieved
+/// by. Chunk IDs are the document ID followed by `#` and the chunk index.
+#[derive(Embed, Clone, Debug, serde::Deserialize)]
+pub struct DocumentChunk {
+    pub id: String,
+    /// ID of the document the chunk was cut from.
+    pub parent_id: String,
+    pub source_id: String,
+    /// Named collection the document was added to.
+    pub collection: String,
+    /// Position of the chunk in its document, from 0.
+    pub chunk_index: i64,
+    /// Byte range of the chunk in its document.
+    pub start_offset: i64,
+    pub end_offset: i64,
+    /// Headings the chunk is nested under, outermost first, joined by ` > `.
+    pub heading_path: String,
+    #[embed]
+    pub content: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    /// `Document::content_hash` of the document.
+    pub content_hash: String,
+    #[serde(deserialize_with = "from_json_text")]
+    pub metadata: DocumentMetadata,
+}
+
+impl DocumentChunk {
+    pub fn new(
+        document: &Document,
+        collection: &str,
+        content_hash: &str,
+        index: usize,
+        chunk: Chunk,
+    ) -> Self {
+        Self {
+            id: format!("{}#{}", document.id, index),
+            parent_id: document.id.clone(),
+            source_id: document.source_id.clone(),
+            collection: collection.to_string(),
+            chunk_index: index as i64,
+            start_offset: chunk.start as i64,
+            end_offset: chunk.end as i64,
+            heading_path: chunk.heading_path.join(" > "),
+            content: chunk.content,
+            created_at: document.created_at,
+            content_hash: content_hash.to_string(),
+            metadata: document.metadata.clone(),
+        }
+    }
+}
+
+/// A user on one platform. Messages refer to it by `(source, source_id)`
+/// through their `account_id`.
+#[derive(Debug, Clone, serde::Deserialize)]
 pub struct Account {
     pub id: i64,
-    pub name: String,
     pub source: String,
+    pub source_id: String,
+    pub name: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+    /// Set once the account has been linked to accounts on other platforms.
+    pub person_id: Option<i64>,
+}
+
+/// Someone known through one or more linked accounts.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Person {
+    pub id: i64,
+    pub name: Option<String>,
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub updated_at: chrono::DateTime<chrono::Utc>,
 }
 
-#[derive(Debug, serde::Deserialize)]
+/// A run of related messages in a channel: a reply chain, a platform thread
+/// or messages close enough in time.
+#[derive(Debug, Clone, serde::Deserialize)]
 pub struct Conversation {
-    pub id: String,
-    pub user_id: String,
-    pub title: String,
+    pub id: i64,
+    pub source: String,
+    pub channel_id: String,
+    pub thread_id: Option<String>,
+    /// Generated once the conversation has a few messages.
+    pub title: Option<String>,
     pub created_at: chrono::DateTime<chrono::Utc>,
+    /// Time of the latest message.
     pub updated_at: chrono::DateTime<chrono::Utc>,
 }
 
-#[derive(Embed, Clone, Debug, serde::Deserialize)]
+/// What a client knows about where a message belongs.
+#[derive(Debug, Clone, Default)]
+pub struct ConversationHints {
+    /// Platform ID of the message this one replies to.
+    pub reply_to: Option<String>,
+    /// Platform thread or topic the message was posted in.
+    pub thread_id: Option<String>,
+}
+
+#[derive(Embed, Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct Message {
     pub id: String,
     pub source: Source,
@@ -44,17 +184,235 @@ pub struct Message {
     pub created_at: chrono::DateTime<chrono::Utc>,
 }
 
+/// Content a message had before it was edited.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct MessageRevision {
+    pub id: i64,
+    /// Platform ID of the edited message.
+    pub message_id: String,
+    pub content: String,
+    /// When this content was replaced.
+    pub edited_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Limits how long messages are kept. Every policy matching a message
+/// applies, so the strictest one wins.
 #[derive(Debug, Clone, serde::Deserialize)]
-pub struct Channel {
+pub struct RetentionPolicy {
+    /// Platform the policy applies to, `None` for all of them.
+    pub source: Option<Source>,
+    /// Platform channel ID the policy applies to, `None` for all channels.
+    pub channel_id: Option<String>,
+    pub max_age_days: Option<i64>,
+    /// Messages kept per channel, newest first.
+    pub max_messages: Option<i64>,
+}
+
+/// Embedding model that produced the vectors of a vector store table.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct EmbeddingModelRecord {
+    pub table_name: String,
+    pub model: String,
+    pub dimensions: i64,
+}
+
+/// What indexing a batch of documents changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IndexReport {
+    pub added: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub removed: usize,
+}
+
+/// What was removed when purging an account.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PurgeReport {
+    pub messages: usize,
+    pub facts: usize,
+    pub summaries: usize,
+}
+
+/// Something durable learned about a user, e.g. "uses Firefox on Linux".
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Fact {
+    pub id: i64,
+    pub account_id: i64,
+    pub content: String,
+    /// Free-form, e.g. "preference", "project" or "setup".
+    pub category: Option<String>,
+    /// Between 0 and 1, as estimated by the extractor.
+    pub confidence: f64,
+    /// Platform ID of the message the fact was learned from.
+    pub source_message_id: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub struct NewFact {
+    pub account_id: i64,
+    pub content: String,
+    pub category: Option<String>,
+    pub confidence: f64,
+    pub source_message_id: Option<String>,
+}
+
+/// A condensed account of older messages, replaced by a newer summary each
+/// time more messages are condensed.
+#[derive(Embed, Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Summary {
     pub id: String,
-    pub name: String,
+    /// `channel` or `account`, see `SummaryScope`.
+    pub scope: String,
+    pub scope_id: String,
+    #[embed]
+    pub content: String,
+    /// Messages condensed into this summary and its predecessors.
+    pub message_count: i64,
+    /// Creation time of the last condensed message.
+    pub covered_until: chrono::DateTime<chrono::Utc>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// What a summary is about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SummaryScope {
+    /// A channel, by platform channel ID.
+    Channel(String),
+    /// Everything written by an account, by `accounts.id`.
+    Account(i64),
+}
+
+impl SummaryScope {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SummaryScope::Channel(_) => "channel",
+            SummaryScope::Account(_) => "account",
+        }
+    }
+
+    pub fn id(&self) -> String {
+        match self {
+            SummaryScope::Channel(channel_id) => channel_id.clone(),
+            SummaryScope::Account(account_id) => account_id.to_string(),
+        }
+    }
+}
+
+/// A message from a channel's history with its speaker resolved.
+#[derive(Clone, Debug)]
+pub struct HistoryEntry {
+    pub message_id: String,
+    pub account_id: String,
+    /// Display name from `accounts`, falling back to the account ID.
+    pub speaker: String,
+    pub role: String,
+    pub content: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl HistoryEntry {
+    pub fn is_from_agent(&self) -> bool {
+        self.role == "assistant"
+    }
+
+    /// Renders entries as "speaker: content" lines, labelling the agent's own
+    /// messages with `agent_name`.
+    pub fn transcript(entries: &[HistoryEntry], agent_name: &str) -> String {
+        entries
+            .iter()
+            .map(|entry| {
+                let speaker = if entry.is_from_agent() {
+                    agent_name
+                } else {
+                    &entry.speaker
+                };
+                format!("{}: {}", speaker, entry.content)
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Channel {
+    pub id: i64,
+    pub channel_id: String,
+    pub channel_type: String,
     pub source: String,
+    pub name: Option<String>,
+    pub guild_id: Option<String>,
+    pub guild_name: Option<String>,
+    pub topic: Option<String>,
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub updated_at: chrono::DateTime<chrono::Utc>,
 }
 
+/// Channel details as seen by a client. Missing values keep what was
+/// previously recorded.
+#[derive(Debug, Clone)]
+pub struct ChannelMetadata {
+    pub channel_id: String,
+    pub channel_type: ChannelType,
+    pub source: Source,
+    pub name: Option<String>,
+    pub guild_id: Option<String>,
+    pub guild_name: Option<String>,
+    pub topic: Option<String>,
+}
+
+/// Per-channel behaviour, consulted by `Attention` and `Agent`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ChannelSettings {
+    pub channel_id: String,
+    /// A disabled channel is still recorded but never answered.
+    pub enabled: bool,
+    pub attention: AttentionOverrides,
+    /// Names of the tools the agent may use here, `None` allows all of them.
+    pub allowed_tools: Option<Vec<String>>,
+    /// Replaces the character's preamble in this channel.
+    pub persona_override: Option<String>,
+    /// Limits the documents retrieved here, `None` searches all of them.
+    pub document_filter: Option<DocumentFilter>,
+}
+
+impl ChannelSettings {
+    pub fn new(channel_id: impl Into<String>) -> Self {
+        Self {
+            channel_id: channel_id.into(),
+            enabled: true,
+            attention: AttentionOverrides::default(),
+            allowed_tools: None,
+            persona_override: None,
+            document_filter: None,
+        }
+    }
+
+    pub fn allows_tool(&self, name: &str) -> bool {
+        self.allowed_tools
+            .as_ref()
+            .map_or(true, |tools| tools.iter().any(|tool| tool == name))
+    }
+}
+
+/// Attention settings replaced in a single channel, unset fields keep the
+/// global configuration.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct AttentionOverrides {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bot_names: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_phrases: Option<std::collections::HashMap<String, Vec<String>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confirm_stop_with_llm: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mention_only: Option<bool>,
+}
+
 // Implement the table traits
-impl SqliteVectorStoreTable for Document {
+impl SqliteVectorStoreTable for DocumentChunk {
     fn name() -> &'static str {
         "documents"
     }
@@ -65,6 +423,14 @@ impl SqliteVectorStoreTable for Document {
             Column::new("source_id", "TEXT").indexed(),
             Column::new("content", "TEXT"),
             Column::new("created_at", "TIMESTAMP DEFAULT CURRENT_TIMESTAMP"),
+            Column::new("parent_id", "TEXT"),
+            Column::new("chunk_index", "INTEGER NOT NULL DEFAULT 0"),
+            Column::new("start_offset", "INTEGER NOT NULL DEFAULT 0"),
+            Column::new("end_offset", "INTEGER NOT NULL DEFAULT 0"),
+            Column::new("heading_path", "TEXT NOT NULL DEFAULT ''"),
+            Column::new("content_hash", "TEXT NOT NULL DEFAULT ''"),
+            Column::new("metadata", "TEXT NOT NULL DEFAULT '{}'"),
+            Column::new("collection", "TEXT NOT NULL DEFAULT 'default'"),
         ]
     }
 
@@ -78,6 +444,15 @@ impl SqliteVectorStoreTable for Document {
             ("source_id", Box::new(self.source_id.clone())),
             ("content", Box::new(self.content.clone())),
             ("created_at", Box::new(self.created_at.to_rfc3339())),
+            ("parent_id", Box::new(self.parent_id.clone())),
+            // Stored as text, INTEGER affinity converts them back
+            ("chunk_index", Box::new(self.chunk_index.to_string())),
+            ("start_offset", Box::new(self.start_offset.to_string())),
+            ("end_offset", Box::new(self.end_offset.to_string())),
+            ("heading_path", Box::new(self.heading_path.clone())),
+            ("content_hash", Box::new(self.content_hash.clone())),
+            ("metadata", Box::new(self.metadata.to_json())),
+            ("collection", Box::new(self.collection.clone())),
         ]
     }
 }
@@ -123,6 +498,109 @@ impl SqliteVectorStoreTable for Message {
     }
 }
 
+impl SqliteVectorStoreTable for Summary {
+    fn name() -> &'static str {
+        "summaries"
+    }
+
+    fn schema() -> Vec<Column> {
+        vec![
+            Column::new("id", "TEXT PRIMARY KEY"),
+            Column::new("scope", "TEXT NOT NULL"),
+            Column::new("scope_id", "TEXT NOT NULL"),
+            Column::new("content", "TEXT NOT NULL"),
+            Column::new("message_count", "INTEGER NOT NULL"),
+            Column::new("covered_until", "TIMESTAMP NOT NULL"),
+            Column::new("created_at", "TIMESTAMP DEFAULT CURRENT_TIMESTAMP"),
+        ]
+    }
+
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn column_values(&self) -> Vec<(&'static str, Box<dyn ColumnValue>)> {
+        vec![
+            ("id", Box::new(self.id.clone())),
+            ("scope", Box::new(self.scope.clone())),
+            ("scope_id", Box::new(self.scope_id.clone())),
+            ("content", Box::new(self.content.clone())),
+            // Stored as text, INTEGER affinity converts it back
+            ("message_count", Box::new(self.message_count.to_string())),
+            ("covered_until", Box::new(self.covered_until.to_rfc3339())),
+            ("created_at", Box::new(self.created_at.to_rfc3339())),
+        ]
+    }
+}
+
+impl TryFrom<&Row<'_>> for RetentionPolicy {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        Ok(RetentionPolicy {
+            source: row
+                .get::<_, Option<String>>(0)?
+                .map(|source| {
+                    Source::from_str(&source).ok_or(rusqlite::Error::FromSqlConversionFailure(
+                        0,
+                        rusqlite::types::Type::Text,
+                        Box::new(super::error::ConversionError("Invalid source".to_string())),
+                    ))
+                })
+                .transpose()?,
+            channel_id: row.get(1)?,
+            max_age_days: row.get(2)?,
+            max_messages: row.get(3)?,
+        })
+    }
+}
+
+impl TryFrom<&Row<'_>> for MessageRevision {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        Ok(MessageRevision {
+            id: row.get(0)?,
+            message_id: row.get(1)?,
+            content: row.get(2)?,
+            edited_at: row.get(3)?,
+        })
+    }
+}
+
+impl TryFrom<&Row<'_>> for Fact {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        Ok(Fact {
+            id: row.get(0)?,
+            account_id: row.get(1)?,
+            content: row.get(2)?,
+            category: row.get(3)?,
+            confidence: row.get(4)?,
+            source_message_id: row.get(5)?,
+            created_at: row.get(6)?,
+            updated_at: row.get(7)?,
+        })
+    }
+}
+
+impl TryFrom<&Row<'_>> for Summary {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        Ok(Summary {
+            id: row.get(0)?,
+            scope: row.get(1)?,
+            scope_id: row.get(2)?,
+            content: row.get(3)?,
+            message_count: row.get(4)?,
+            covered_until: row.get(5)?,
+            created_at: row.get(6)?,
+        })
+    }
+}
+
 impl TryFrom<&Row<'_>> for Document {
     type Error = rusqlite::Error;
 
@@ -132,6 +610,40 @@ impl TryFrom<&Row<'_>> for Document {
             source_id: row.get(1)?,
             content: row.get(2)?,
             created_at: row.get(3)?,
+            metadata: json_column(row, 4)?.unwrap_or_default(),
+        })
+    }
+}
+
+impl TryFrom<&Row<'_>> for DocumentChunk {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        Ok(DocumentChunk {
+            id: row.get(0)?,
+            parent_id: row.get(1)?,
+            source_id: row.get(2)?,
+            chunk_index: row.get(3)?,
+            start_offset: row.get(4)?,
+            end_offset: row.get(5)?,
+            heading_path: row.get(6)?,
+            content: row.get(7)?,
+            created_at: row.get(8)?,
+            content_hash: row.get(9)?,
+            metadata: json_column(row, 10)?.unwrap_or_default(),
+            collection: row.get(11)?,
+        })
+    }
+}
+
+impl TryFrom<&Row<'_>> for EmbeddingModelRecord {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        Ok(EmbeddingModelRecord {
+            table_name: row.get(0)?,
+            model: row.get(1)?,
+            dimensions: row.get(2)?,
         })
     }
 }
@@ -141,11 +653,26 @@ impl TryFrom<&Row<'_>> for Account {
 
     fn try_from(row: &Row) -> Result<Self, Self::Error> {
         Ok(Account {
+            id: row.get(0)?,
+            source: row.get(1)?,
+            source_id: row.get(2)?,
+            name: row.get(3)?,
+            created_at: row.get(4)?,
+            updated_at: row.get(5)?,
+            person_id: row.get(6)?,
+        })
+    }
+}
+
+impl TryFrom<&Row<'_>> for Person {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        Ok(Person {
             id: row.get(0)?,
             name: row.get(1)?,
-            source: row.get(2)?,
-            created_at: row.get(3)?,
-            updated_at: row.get(4)?,
+            created_at: row.get(2)?,
+            updated_at: row.get(3)?,
         })
     }
 }
@@ -156,10 +683,12 @@ impl TryFrom<&Row<'_>> for Conversation {
     fn try_from(row: &Row) -> Result<Self, Self::Error> {
         Ok(Conversation {
             id: row.get(0)?,
-            user_id: row.get(1)?,
-            title: row.get(2)?,
-            created_at: row.get(3)?,
-            updated_at: row.get(4)?,
+            source: row.get(1)?,
+            channel_id: row.get(2)?,
+            thread_id: row.get(3)?,
+            title: row.get(4)?,
+            created_at: row.get(5)?,
+            updated_at: row.get(6)?,
         })
     }
 }
@@ -196,46 +725,80 @@ impl TryFrom<&Row<'_>> for Message {
     }
 }
 
+impl TryFrom<&Row<'_>> for HistoryEntry {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        Ok(HistoryEntry {
+            message_id: row.get(0)?,
+            account_id: row.get(1)?,
+            speaker: row.get(2)?,
+            role: row.get(3)?,
+            content: row.get(4)?,
+            created_at: row.get(5)?,
+        })
+    }
+}
+
 impl TryFrom<&Row<'_>> for Channel {
     type Error = rusqlite::Error;
 
     fn try_from(row: &Row) -> Result<Self, Self::Error> {
         Ok(Channel {
             id: row.get(0)?,
-            name: row.get(1)?,
-            source: row.get(2)?,
-            created_at: row.get(3)?,
-            updated_at: row.get(4)?,
+            channel_id: row.get(1)?,
+            channel_type: row.get(2)?,
+            source: row.get(3)?,
+            name: row.get(4)?,
+            guild_id: row.get(5)?,
+            guild_name: row.get(6)?,
+            topic: row.get(7)?,
+            created_at: row.get(8)?,
+            updated_at: row.get(9)?,
         })
     }
 }
 
-impl SqliteVectorStoreTable for Channel {
-    fn name() -> &'static str {
-        "channels"
-    }
+impl TryFrom<&Row<'_>> for ChannelSettings {
+    type Error = rusqlite::Error;
 
-    fn schema() -> Vec<Column> {
-        vec![
-            Column::new("id", "TEXT PRIMARY KEY"),
-            Column::new("name", "TEXT"),
-            Column::new("source", "TEXT"),
-            Column::new("created_at", "TIMESTAMP DEFAULT CURRENT_TIMESTAMP"),
-            Column::new("updated_at", "TIMESTAMP DEFAULT CURRENT_TIMESTAMP"),
-        ]
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        Ok(ChannelSettings {
+            channel_id: row.get(0)?,
+            enabled: row.get(1)?,
+            attention: json_column(row, 2)?.unwrap_or_default(),
+            allowed_tools: json_column(row, 3)?,
+            persona_override: row.get(4)?,
+            document_filter: json_column(row, 5)?,
+        })
     }
+}
 
-    fn id(&self) -> String {
-        self.id.clone()
-    }
+/// Deserializes a field stored as JSON text, as found in rows handed to serde
+/// by the vector store.
+fn from_json_text<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: serde::de::DeserializeOwned,
+{
+    let json = <String as serde::Deserialize>::deserialize(deserializer)?;
+    serde_json::from_str(&json).map_err(serde::de::Error::custom)
+}
 
-    fn column_values(&self) -> Vec<(&'static str, Box<dyn ColumnValue>)> {
-        vec![
-            ("id", Box::new(self.id.clone())),
-            ("name", Box::new(self.name.clone())),
-            ("source", Box::new(self.source.clone())),
-            ("created_at", Box::new(self.created_at.to_rfc3339())),
-            ("updated_at", Box::new(self.updated_at.to_rfc3339())),
-        ]
-    }
+/// Reads a nullable column holding a JSON document.
+fn json_column<T: serde::de::DeserializeOwned>(
+    row: &Row,
+    index: usize,
+) -> Result<Option<T>, rusqlite::Error> {
+    row.get::<_, Option<String>>(index)?
+        .map(|json| {
+            serde_json::from_str(&json).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(
+                    index,
+                    rusqlite::types::Type::Text,
+                    Box::new(e),
+                )
+            })
+        })
+        .transpose()
 }
@@ -0,0 +1,408 @@
+//! Tracking of the embedding model behind each vector store table, and the
+//! job moving a knowledge base to another model.
+
+use rig::embeddings::EmbeddingModel;
+use rusqlite::{Connection, OptionalExtension};
+use tokio_rusqlite::Connection as AsyncConnection;
+use tracing::{debug, info};
+
+use super::error::EmbeddingModelError;
+use super::models::EmbeddingModelRecord;
+
+/// Vector tables of the knowledge base's model. Each holds the vectors of
+/// the `content` column of the table of the same name.
+pub(super) const VECTOR_TABLES: &[&str] = &["documents", "messages", "summaries"];
+
+/// Records `model` for the vector tables without a record, and fails if any
+/// table holds vectors of another model.
+pub(super) fn check_embedding_model(
+    conn: &Connection,
+    model: &str,
+    dimensions: i64,
+) -> Result<(), EmbeddingModelError> {
+    for table in VECTOR_TABLES {
+        match embedding_model(conn, table)? {
+            Some(stored) if stored.model == model && stored.dimensions == dimensions => {}
+            Some(stored) => {
+                return Err(EmbeddingModelError::Mismatch {
+                    table: table.to_string(),
+                    stored_model: stored.model,
+                    stored_dimensions: stored.dimensions,
+                    model: model.to_string(),
+                    dimensions,
+                });
+            }
+            None => {
+                // Vectors stored before models were recorded are assumed to
+                // come from the model in use
+                info!(table, model, dimensions, "Recording embedding model");
+                record_embedding_model(conn, table, model, dimensions)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub(super) fn embedding_model(
+    conn: &Connection,
+    table: &str,
+) -> Result<Option<EmbeddingModelRecord>, rusqlite::Error> {
+    conn.query_row(
+        "SELECT table_name, model, dimensions FROM embedding_models WHERE table_name = ?1",
+        [table],
+        |row| EmbeddingModelRecord::try_from(row),
+    )
+    .optional()
+}
+
+pub(super) fn record_embedding_model(
+    conn: &Connection,
+    table: &str,
+    model: &str,
+    dimensions: i64,
+) -> Result<(), rusqlite::Error> {
+    conn.execute(
+        "INSERT INTO embedding_models (table_name, model, dimensions)
+         VALUES (?1, ?2, ?3)
+         ON CONFLICT (table_name) DO UPDATE SET
+             model = excluded.model,
+             dimensions = excluded.dimensions,
+             updated_at = CURRENT_TIMESTAMP",
+        rusqlite::params![table, model, dimensions],
+    )?;
+
+    Ok(())
+}
+
+/// What a run of a `ReembedJob` did.
+#[derive(Debug, Clone, Default)]
+pub struct ReembedReport {
+    /// Rows embedded by this run, not counting those of interrupted runs.
+    pub embedded: usize,
+    /// Tables switched to the new model.
+    pub completed: Vec<String>,
+}
+
+/// Re-embeds every document, message and summary with a new embedding model.
+///
+/// New vectors go to a `{table}_embeddings_next` table, filled in batches
+/// while the bot keeps running on the old model, and replace the old vectors
+/// once the whole table is done. Progress is saved after every batch, so an
+/// interrupted job resumes where it stopped when run again with the same
+/// model. Restart the bot with the new model once the job has completed,
+/// `KnowledgeBase::new` refuses the old one from then on.
+pub struct ReembedJob<E: EmbeddingModel> {
+    conn: AsyncConnection,
+    embedding_model: E,
+    model_name: String,
+    batch_size: usize,
+}
+
+impl<E: EmbeddingModel> ReembedJob<E> {
+    pub fn new(conn: AsyncConnection, embedding_model: E, model_name: impl Into<String>) -> Self {
+        Self {
+            conn,
+            embedding_model,
+            model_name: model_name.into(),
+            batch_size: 100,
+        }
+    }
+
+    /// Rows embedded per request to the model, capped by the model's limit.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    pub async fn run(&self) -> anyhow::Result<ReembedReport> {
+        let model = self.model_name.clone();
+        let dimensions = self.embedding_model.ndims() as i64;
+        let batch_size = self.batch_size.clamp(1, E::MAX_DOCUMENTS.max(1));
+
+        info!(model, dimensions, "Starting re-embedding job");
+
+        let tables = self
+            .conn
+            .call(move |conn| Ok(start_jobs(conn, &model, dimensions)?))
+            .await?;
+
+        let mut report = ReembedReport::default();
+
+        for table in tables {
+            loop {
+                let batch = self
+                    .conn
+                    .call(move |conn| Ok(next_batch(conn, table, batch_size)?))
+                    .await?;
+
+                if batch.is_empty() {
+                    // Rows added since the last batch are picked up by the
+                    // next round
+                    if self
+                        .conn
+                        .call(move |conn| Ok(finish_job(conn, table)?))
+                        .await?
+                    {
+                        info!(table, "Re-embedded table");
+                        report.completed.push(table.to_string());
+                        break;
+                    }
+                    continue;
+                }
+
+                let (rowids, contents): (Vec<i64>, Vec<String>) = batch.into_iter().unzip();
+                let vectors = self
+                    .embedding_model
+                    .embed_texts(contents)
+                    .await?
+                    .into_iter()
+                    .map(|embedding| serde_json::to_string(&embedding.vec))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                debug!(table, count = rowids.len(), "Re-embedded batch");
+                report.embedded += rowids.len();
+
+                self.conn
+                    .call(move |conn| Ok(save_batch(conn, table, &rowids, &vectors)?))
+                    .await?;
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+/// Creates the staging tables of the tables not on `model` yet, dropping
+/// those of a job for another model, and returns the tables to re-embed.
+fn start_jobs(
+    conn: &mut Connection,
+    model: &str,
+    dimensions: i64,
+) -> Result<Vec<&'static str>, rusqlite::Error> {
+    let tx = conn.transaction()?;
+    let mut tables = Vec::new();
+
+    for table in VECTOR_TABLES {
+        let job: Option<(String, i64)> = tx
+            .query_row(
+                "SELECT model, dimensions FROM reembed_jobs WHERE table_name = ?1",
+                [table],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        match job {
+            Some((job_model, job_dimensions))
+                if job_model == model && job_dimensions == dimensions =>
+            {
+                tables.push(*table);
+                continue;
+            }
+            Some(_) => drop_job(&tx, table)?,
+            None => {}
+        }
+
+        if embedding_model(&tx, table)?
+            .is_some_and(|stored| stored.model == model && stored.dimensions == dimensions)
+        {
+            continue;
+        }
+
+        // Same options as the table rig-sqlite created, with the new size
+        let create: Option<String> = tx
+            .query_row(
+                "SELECT sql FROM sqlite_master WHERE name = ?1",
+                [format!("{table}_embeddings")],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let Some(create) = create else {
+            // Nothing embedded yet, the table is created on first use
+            record_embedding_model(&tx, table, model, dimensions)?;
+            continue;
+        };
+
+        tx.execute_batch(&resized_vector_table(
+            &create,
+            &format!("{table}_embeddings_next"),
+            dimensions,
+        ))?;
+        // Deleted rows lose their new vector, so that a row reusing the rowid
+        // gets embedded
+        tx.execute_batch(&format!(
+            "CREATE TRIGGER {table}_reembed_delete AFTER DELETE ON {table} BEGIN
+                 DELETE FROM {table}_embeddings_next WHERE rowid = old.rowid;
+             END;"
+        ))?;
+        tx.execute(
+            "INSERT INTO reembed_jobs (table_name, model, dimensions) VALUES (?1, ?2, ?3)",
+            rusqlite::params![table, model, dimensions],
+        )?;
+        tables.push(*table);
+    }
+
+    tx.commit()?;
+    Ok(tables)
+}
+
+fn drop_job(conn: &Connection, table: &str) -> Result<(), rusqlite::Error> {
+    conn.execute_batch(&format!(
+        "DROP TRIGGER IF EXISTS {table}_reembed_delete;
+         DROP TABLE IF EXISTS {table}_embeddings_next;"
+    ))?;
+    conn.execute("DELETE FROM reembed_jobs WHERE table_name = ?1", [table])?;
+
+    Ok(())
+}
+
+/// Rows past the job's cursor, or once there are none, rows behind it that
+/// reused the rowid of a deleted row.
+fn next_batch(
+    conn: &Connection,
+    table: &str,
+    batch_size: usize,
+) -> Result<Vec<(i64, String)>, rusqlite::Error> {
+    for condition in [
+        "t.rowid > (SELECT last_rowid FROM reembed_jobs WHERE table_name = ?1)",
+        &format!("t.rowid NOT IN (SELECT rowid FROM {table}_embeddings_next)"),
+    ] {
+        let batch = conn
+            .prepare(&format!(
+                "SELECT t.rowid, t.content
+                 FROM {table} t
+                 WHERE {condition}
+                 ORDER BY t.rowid
+                 LIMIT ?2"
+            ))?
+            .query_map(rusqlite::params![table, batch_size as i64], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if !batch.is_empty() {
+            return Ok(batch);
+        }
+    }
+
+    Ok(Vec::new())
+}
+
+fn save_batch(
+    conn: &mut Connection,
+    table: &str,
+    rowids: &[i64],
+    vectors: &[String],
+) -> Result<(), rusqlite::Error> {
+    let tx = conn.transaction()?;
+
+    for (rowid, vector) in rowids.iter().zip(vectors) {
+        // sqlite-vec reads vectors given as JSON arrays
+        tx.execute(
+            &format!("INSERT INTO {table}_embeddings_next (rowid, embedding) VALUES (?1, ?2)"),
+            rusqlite::params![rowid, vector],
+        )?;
+    }
+    if let Some(last) = rowids.last() {
+        tx.execute(
+            "UPDATE reembed_jobs SET last_rowid = MAX(last_rowid, ?2), updated_at = CURRENT_TIMESTAMP
+             WHERE table_name = ?1",
+            rusqlite::params![table, last],
+        )?;
+    }
+
+    tx.commit()
+}
+
+/// Swaps the new vectors in, unless rows were added since the last batch.
+fn finish_job(conn: &mut Connection, table: &str) -> Result<bool, rusqlite::Error> {
+    let tx = conn.transaction()?;
+
+    if !next_batch(&tx, table, 1)?.is_empty() {
+        return Ok(false);
+    }
+    let (model, dimensions): (String, i64) = tx.query_row(
+        "SELECT model, dimensions FROM reembed_jobs WHERE table_name = ?1",
+        [table],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+
+    let create: String = tx.query_row(
+        "SELECT sql FROM sqlite_master WHERE name = ?1",
+        [format!("{table}_embeddings")],
+        |row| row.get(0),
+    )?;
+
+    tx.execute_batch(&format!(
+        "DROP TABLE {table}_embeddings;
+         {};
+         INSERT INTO {table}_embeddings (rowid, embedding)
+             SELECT rowid, embedding FROM {table}_embeddings_next;",
+        resized_vector_table(&create, &format!("{table}_embeddings"), dimensions)
+    ))?;
+    drop_job(&tx, table)?;
+    record_embedding_model(&tx, table, &model, dimensions)?;
+
+    tx.commit()?;
+    Ok(true)
+}
+
+/// Rewrites the `CREATE VIRTUAL TABLE` statement of a vec0 table for a table
+/// named `name` holding vectors of `dimensions`.
+fn resized_vector_table(create: &str, name: &str, dimensions: i64) -> String {
+    let using = create
+        .find("USING")
+        .or_else(|| create.find("using"))
+        .map_or(create, |start| &create[start..]);
+
+    let resized = match using.find("float[") {
+        Some(start) => {
+            let size = start + "float[".len();
+            let end = using[size..]
+                .find(']')
+                .map_or(using.len(), |end| size + end);
+            format!("{}{dimensions}{}", &using[..size], &using[end..])
+        }
+        None => using.to_string(),
+    };
+
+    format!("CREATE VIRTUAL TABLE {name} {resized}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_embedding_model_refuses_other_models() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        super::super::migrations::migrate(&mut conn).unwrap();
+
+        check_embedding_model(&conn, "text-embedding-3-small", 1536).unwrap();
+        check_embedding_model(&conn, "text-embedding-3-small", 1536).unwrap();
+
+        assert!(matches!(
+            check_embedding_model(&conn, "text-embedding-ada-002", 1536),
+            Err(EmbeddingModelError::Mismatch { .. })
+        ));
+        assert!(matches!(
+            check_embedding_model(&conn, "text-embedding-3-small", 512),
+            Err(EmbeddingModelError::Mismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_resized_vector_table_keeps_options() {
+        assert_eq!(
+            resized_vector_table(
+                "CREATE VIRTUAL TABLE documents_embeddings USING vec0(embedding float[1536])",
+                "documents_embeddings_next",
+                3072
+            ),
+            "CREATE VIRTUAL TABLE documents_embeddings_next USING vec0(embedding float[3072])"
+        );
+    }
+}
@@ -0,0 +1,532 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use rig::{
+    embeddings::EmbeddingModel,
+    vector_store::{VectorStoreError, VectorStoreIndex},
+};
+use rig_sqlite::SqliteVectorStoreTable;
+use rusqlite::types::Value;
+use serde::Deserialize;
+use tokio_rusqlite::Connection;
+
+use super::backend::{RelationalStore, StoreKey, VectorStore};
+use super::models::DocumentFilter;
+
+/// How keyword and vector results are combined by a [`HybridIndex`].
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct HybridConfig {
+    /// Results fetched from each retriever before fusion.
+    pub candidates: usize,
+    /// Dampens the weight of the top ranks, 60 is the usual choice.
+    pub rrf_k: f64,
+    /// Zero skips the full-text search.
+    pub keyword_weight: f64,
+    /// Zero skips the vector search.
+    pub vector_weight: f64,
+}
+
+impl Default for HybridConfig {
+    fn default() -> Self {
+        Self {
+            candidates: 20,
+            rrf_k: 60.0,
+            keyword_weight: 1.0,
+            vector_weight: 1.0,
+        }
+    }
+}
+
+/// Retrieves rows of a vector store table by both BM25 over its `{table}_fts`
+/// index and embedding similarity, merging the two rankings with reciprocal
+/// rank fusion. Keyword search catches exact identifiers such as addresses,
+/// error codes and API names that embeddings tend to blur.
+///
+/// Scores are fusion scores, higher is better.
+pub struct HybridIndex<E: EmbeddingModel + 'static, T: SqliteVectorStoreTable + 'static> {
+    rows: Rows,
+    vectors: Arc<dyn VectorStore>,
+    embedding_model: E,
+    /// Vector table holding the vectors, the one named after `T` unless a
+    /// collection has its own model.
+    vector_table: String,
+    config: HybridConfig,
+    filter: Option<DocumentFilter>,
+    table: PhantomData<T>,
+}
+
+/// Where a [`HybridIndex`] reads the rows it ranks.
+pub(super) enum Rows {
+    /// The table of `T` in the SQLite database, with its `{table}_fts` index.
+    Sqlite(Connection),
+    Messages(Arc<dyn RelationalStore>),
+    /// Summaries have no full-text index.
+    Summaries(Arc<dyn RelationalStore>),
+}
+
+impl<E, T> HybridIndex<E, T>
+where
+    E: EmbeddingModel + 'static,
+    T: SqliteVectorStoreTable + 'static,
+{
+    /// Searches the rows of `T`, embedding queries with the model the table
+    /// was embedded with.
+    pub(super) fn new(
+        rows: Rows,
+        vectors: Arc<dyn VectorStore>,
+        embedding_model: E,
+        config: HybridConfig,
+    ) -> Self {
+        Self {
+            rows,
+            vectors,
+            embedding_model,
+            vector_table: T::name().to_string(),
+            config,
+            filter: None,
+            table: PhantomData,
+        }
+    }
+
+    /// Searches the vectors of another table, embedding queries with
+    /// `embedding_model`.
+    pub(super) fn with_vector_table(mut self, embedding_model: E, table: String) -> Self {
+        self.embedding_model = embedding_model;
+        self.vector_table = table;
+        self
+    }
+
+    /// Only retrieves documents matching `filter`, in place of any filter
+    /// set before. Applies to indexes over `documents` only, other tables
+    /// lack the filtered columns.
+    pub fn with_filter(mut self, filter: DocumentFilter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Keys of the rows matching `query` best, by BM25 alone.
+    async fn keyword_keys(&self, query: &str, n: usize) -> anyhow::Result<Vec<StoreKey>> {
+        let conn = match &self.rows {
+            Rows::Sqlite(conn) => conn,
+            Rows::Messages(relational) => return relational.search_messages(query, n).await,
+            Rows::Summaries(_) => return Ok(Vec::new()),
+        };
+        let Some(query) = fts_query(query) else {
+            return Ok(Vec::new());
+        };
+        let table = T::name();
+        let (filter, mut params) = filter_sql(self.filter.as_ref(), 3);
+        params.splice(0..0, [Value::Text(query), Value::Integer(n as i64)]);
+
+        Ok(conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT t.rowid
+                     FROM {table}_fts
+                     JOIN {table} t ON t.rowid = {table}_fts.rowid
+                     WHERE {table}_fts MATCH ?1 AND {filter}
+                     ORDER BY bm25({table}_fts)
+                     LIMIT ?2"
+                ))?;
+
+                let keys = stmt
+                    .query_map(rusqlite::params_from_iter(params), |row| {
+                        Ok(StoreKey::from(row.get::<_, i64>(0)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(keys)
+            })
+            .await?)
+    }
+
+    /// Keys of the rows matching the filter, `None` without a filter.
+    async fn filtered_keys(&self) -> anyhow::Result<Option<Vec<StoreKey>>> {
+        let (Rows::Sqlite(conn), Some(filter)) = (&self.rows, &self.filter) else {
+            return Ok(None);
+        };
+        let table = T::name();
+        let (filter, params) = filter_sql(Some(filter), 1);
+
+        Ok(conn
+            .call(move |conn| {
+                let keys = conn
+                    .prepare(&format!("SELECT t.rowid FROM {table} t WHERE {filter}"))?
+                    .query_map(rusqlite::params_from_iter(params), |row| {
+                        Ok(StoreKey::from(row.get::<_, i64>(0)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(Some(keys))
+            })
+            .await?)
+    }
+
+    /// Keys of the rows closest to `query`, closest first. The vector store
+    /// only searches the rows matching the filter, so a selective filter
+    /// still gets its `n` nearest rows.
+    async fn vector_keys(&self, query: &str, n: usize) -> anyhow::Result<Vec<StoreKey>> {
+        let embedding = self
+            .embedding_model
+            .embed_texts(vec![query.to_string()])
+            .await?
+            .pop()
+            .ok_or_else(|| anyhow::anyhow!("No embedding returned"))?;
+        let among = self.filtered_keys().await?;
+
+        let nearest = self
+            .vectors
+            .nearest(&self.vector_table, embedding.vec, n, among)
+            .await?;
+
+        Ok(nearest.into_iter().map(|(key, _)| key).collect())
+    }
+
+    async fn fused_keys(&self, query: &str, n: usize) -> anyhow::Result<Vec<(f64, StoreKey)>> {
+        let candidates = self.config.candidates.max(n);
+
+        let vector_keys = if self.config.vector_weight > 0.0 {
+            self.vector_keys(query, candidates).await?
+        } else {
+            Vec::new()
+        };
+        let keyword_keys = if self.config.keyword_weight > 0.0 {
+            self.keyword_keys(query, candidates).await?
+        } else {
+            Vec::new()
+        };
+
+        let mut fused = reciprocal_rank_fusion(
+            &[
+                (self.config.vector_weight, strings_of(vector_keys)),
+                (self.config.keyword_weight, strings_of(keyword_keys)),
+            ],
+            self.config.rrf_k,
+        );
+        fused.truncate(n);
+
+        Ok(fused
+            .into_iter()
+            .map(|(score, key)| (score, StoreKey::new(key)))
+            .collect())
+    }
+
+    /// Loads rows by key, as their ID and a JSON object keyed by column
+    /// name, the way rig-sqlite hands rows to serde.
+    async fn rows(
+        &self,
+        keys: Vec<StoreKey>,
+    ) -> anyhow::Result<HashMap<StoreKey, (String, serde_json::Value)>> {
+        let conn = match &self.rows {
+            Rows::Sqlite(conn) => conn,
+            Rows::Messages(relational) => {
+                let rows = relational
+                    .messages_by_key(keys)
+                    .await?
+                    .into_iter()
+                    .map(|(key, msg)| {
+                        serde_json::to_value(&msg).map(|row| (key, (msg.id.clone(), row)))
+                    })
+                    .collect::<Result<_, _>>()?;
+                return Ok(rows);
+            }
+            Rows::Summaries(relational) => {
+                let rows = relational
+                    .summaries_by_key(keys)
+                    .await?
+                    .into_iter()
+                    .map(|(key, summary)| {
+                        serde_json::to_value(&summary).map(|row| (key, (summary.id.clone(), row)))
+                    })
+                    .collect::<Result<_, _>>()?;
+                return Ok(rows);
+            }
+        };
+        let table = T::name();
+        // Rows of the SQLite database are keyed by rowid
+        let rowids = keys
+            .iter()
+            .map(|key| key.as_str().parse())
+            .collect::<Result<Vec<i64>, _>>()?;
+        let rowids = serde_json::to_string(&rowids)?;
+
+        Ok(conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT rowid, * FROM {table} WHERE rowid IN (SELECT value FROM json_each(?1))"
+                ))?;
+                let columns: Vec<String> =
+                    stmt.column_names().into_iter().map(String::from).collect();
+
+                let rows = stmt
+                    .query_map([rowids], |row| {
+                        let mut object = serde_json::Map::new();
+                        for (i, column) in columns.iter().enumerate().skip(1) {
+                            object.insert(column.clone(), json_value(row.get_ref(i)?));
+                        }
+                        Ok((
+                            StoreKey::from(row.get::<_, i64>(0)?),
+                            (
+                                row.get::<_, String>("id")?,
+                                serde_json::Value::Object(object),
+                            ),
+                        ))
+                    })?
+                    .collect::<Result<HashMap<_, _>, _>>()?;
+
+                Ok(rows)
+            })
+            .await?)
+    }
+
+    async fn ranked_rows(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> anyhow::Result<Vec<(f64, String, serde_json::Value)>> {
+        let fused = self.fused_keys(query, n).await?;
+        let mut rows = self
+            .rows(fused.iter().map(|(_, key)| key.clone()).collect())
+            .await?;
+
+        Ok(fused
+            .into_iter()
+            .filter_map(|(score, key)| {
+                let (id, row) = rows.remove(&key)?;
+                Some((score, id, row))
+            })
+            .collect())
+    }
+}
+
+impl<E, T> VectorStoreIndex for HybridIndex<E, T>
+where
+    E: EmbeddingModel + 'static,
+    T: SqliteVectorStoreTable + Send + Sync + 'static,
+{
+    async fn top_n<D: for<'a> Deserialize<'a> + Send>(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, String, D)>, VectorStoreError> {
+        self.ranked_rows(query, n)
+            .await
+            .map_err(|e| VectorStoreError::DatastoreError(e.into()))?
+            .into_iter()
+            .map(|(score, id, row)| serde_json::from_value(row).map(|doc| (score, id, doc)))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(VectorStoreError::JsonError)
+    }
+
+    async fn top_n_ids(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, String)>, VectorStoreError> {
+        Ok(self
+            .ranked_rows(query, n)
+            .await
+            .map_err(|e| VectorStoreError::DatastoreError(e.into()))?
+            .into_iter()
+            .map(|(score, id, _)| (score, id))
+            .collect())
+    }
+}
+
+fn strings_of(keys: Vec<StoreKey>) -> Vec<String> {
+    keys.into_iter()
+        .map(|key| key.as_str().to_string())
+        .collect()
+}
+
+fn json_value(value: rusqlite::types::ValueRef) -> serde_json::Value {
+    use rusqlite::types::ValueRef;
+
+    match value {
+        ValueRef::Null | ValueRef::Blob(_) => serde_json::Value::Null,
+        ValueRef::Integer(i) => i.into(),
+        ValueRef::Real(f) => f.into(),
+        ValueRef::Text(text) => String::from_utf8_lossy(text).into(),
+    }
+}
+
+/// SQL condition on the documents aliased `t`, with its parameters numbered
+/// from `first_param`. Matches everything without a filter.
+fn filter_sql(filter: Option<&DocumentFilter>, first_param: usize) -> (String, Vec<Value>) {
+    let mut conditions = Vec::new();
+    let mut params = Vec::new();
+    let mut param = |value: &str| {
+        params.push(Value::Text(value.to_string()));
+        format!("?{}", first_param + params.len() - 1)
+    };
+
+    if let Some(filter) = filter {
+        if let Some(collection) = &filter.collection {
+            conditions.push(format!("t.collection = {}", param(collection)));
+        }
+        if let Some(source_id) = &filter.source_id {
+            conditions.push(format!("t.source_id = {}", param(source_id)));
+        }
+        if let Some(repo) = &filter.repo {
+            conditions.push(format!(
+                "json_extract(t.metadata, '$.repo') = {}",
+                param(repo)
+            ));
+        }
+        if let Some(language) = &filter.language {
+            conditions.push(format!(
+                "json_extract(t.metadata, '$.language') = {}",
+                param(language)
+            ));
+        }
+        for tag in &filter.tags {
+            conditions.push(format!(
+                "EXISTS (SELECT 1 FROM json_each(t.metadata, '$.tags') WHERE value = {})",
+                param(tag)
+            ));
+        }
+        for (key, value) in &filter.extra {
+            conditions.push(format!(
+                "json_extract(t.metadata, '$.extra.' || json_quote({})) = {}",
+                param(key),
+                param(value)
+            ));
+        }
+    }
+
+    if conditions.is_empty() {
+        ("1".to_string(), params)
+    } else {
+        (conditions.join(" AND "), params)
+    }
+}
+
+/// Merges rankings, best first, into one: every ID scores the sum of
+/// `weight / (k + rank)` over the rankings it appears in.
+fn reciprocal_rank_fusion(rankings: &[(f64, Vec<String>)], k: f64) -> Vec<(f64, String)> {
+    let mut scores: HashMap<&str, f64> = HashMap::new();
+
+    for (weight, ids) in rankings {
+        for (rank, id) in ids.iter().enumerate() {
+            *scores.entry(id).or_default() += weight / (k + rank as f64 + 1.0);
+        }
+    }
+
+    let mut fused: Vec<(f64, String)> = scores
+        .into_iter()
+        .map(|(id, score)| (score, id.to_string()))
+        .collect();
+    fused.sort_by(|a, b| b.0.total_cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+
+    fused
+}
+
+/// Turns free text into an FTS5 query matching any of its words. Every word
+/// is quoted so punctuation in addresses or paths isn't read as syntax.
+pub fn fts_query(text: &str) -> Option<String> {
+    let terms: Vec<String> = text
+        .split_whitespace()
+        .map(|word| word.trim_matches(|c: char| !c.is_alphanumeric() && c != '_'))
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect();
+
+    (!terms.is_empty()).then(|| terms.join(" OR "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn test_rrf_favours_ids_ranked_by_both() {
+        let fused = reciprocal_rank_fusion(
+            &[(1.0, ids(&["a", "b", "c"])), (1.0, ids(&["c", "d"]))],
+            60.0,
+        );
+
+        assert_eq!(fused[0].1, "c");
+        assert_eq!(fused.len(), 4);
+    }
+
+    #[test]
+    fn test_rrf_ignores_zero_weight_rankings() {
+        let fused = reciprocal_rank_fusion(&[(1.0, ids(&["a", "b"])), (0.0, ids(&["b"]))], 60.0);
+
+        assert_eq!(fused[0].1, "a");
+    }
+
+    #[test]
+    fn test_filter_sql_matches_metadata() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE documents (id TEXT, source_id TEXT, collection TEXT, metadata TEXT);
+             INSERT INTO documents VALUES
+                 ('a', 'github', 'docs', '{\"repo\":\"docs\",\"tags\":[\"controller\",\"vrf\"]}'),
+                 ('b', 'github', 'docs', '{\"repo\":\"docs\",\"extra\":{\"team\":\"core\"}}'),
+                 ('c', 'faq', 'faq', '{}');",
+        )
+        .unwrap();
+
+        let matching = |filter: DocumentFilter| -> Vec<String> {
+            let (sql, params) = filter_sql(Some(&filter), 1);
+            conn.prepare(&format!(
+                "SELECT id FROM documents t WHERE {sql} ORDER BY id"
+            ))
+            .unwrap()
+            .query_map(rusqlite::params_from_iter(params), |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+        };
+
+        assert_eq!(matching(DocumentFilter::default()), ["a", "b", "c"]);
+        assert_eq!(
+            matching(DocumentFilter {
+                collection: Some("faq".to_string()),
+                ..Default::default()
+            }),
+            ["c"]
+        );
+        assert_eq!(
+            matching(DocumentFilter {
+                repo: Some("docs".to_string()),
+                ..Default::default()
+            }),
+            ["a", "b"]
+        );
+        assert_eq!(
+            matching(DocumentFilter {
+                tags: vec!["controller".to_string()],
+                ..Default::default()
+            }),
+            ["a"]
+        );
+        assert_eq!(
+            matching(DocumentFilter {
+                extra: [("team".to_string(), "core".to_string())].into(),
+                ..Default::default()
+            }),
+            ["b"]
+        );
+    }
+
+    #[test]
+    fn test_fts_query_quotes_words() {
+        assert_eq!(
+            fts_query("what is \"0x04a1\"?").as_deref(),
+            Some("\"what\" OR \"is\" OR \"0x04a1\"")
+        );
+        assert_eq!(
+            fts_query("get_block_number()").as_deref(),
+            Some("\"get_block_number\"")
+        );
+        assert_eq!(fts_query(" ?! "), None);
+    }
+}
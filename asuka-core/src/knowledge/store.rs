@@ -1,290 +1,2036 @@
-use rig::{
-    embeddings::{EmbeddingModel, EmbeddingsBuilder},
-    vector_store::VectorStoreError,
-};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rig::{embeddings::EmbeddingModel, vector_store::VectorStoreError};
 use tokio_rusqlite::Connection;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
+
+use super::archive::{self, ArchiveManifest, ExportOptions};
+use super::backend::{DeletedRow, RelationalStore, SqliteStorage, StoreKey, VectorStore};
+use super::chunking::MarkdownChunker;
+use super::collections::{self, CollectionConfig, DEFAULT_COLLECTION};
+use super::embedding;
+use super::models::{
+    Account, Channel, ChannelMetadata, ChannelSettings, Conversation, ConversationHints, Document,
+    DocumentChunk, DocumentFilter, EmbeddingModelRecord, Fact, HistoryEntry, IndexReport, Message,
+    MessageRevision, NewFact, Person, PurgeReport, RetentionPolicy, Summary, SummaryScope,
+};
+use super::reembed;
+use super::search::{HybridConfig, HybridIndex, Rows};
+use super::types::Source;
+use rand::Rng;
+use rig_sqlite::SqliteError;
 
-use super::models::{Account, Channel, Document, Message};
-use rig_sqlite::{SqliteError, SqliteVectorIndex, SqliteVectorStore};
-use rusqlite::OptionalExtension;
+/// Silence after which a channel message starts a new conversation.
+const CONVERSATION_GAP_MINUTES: i64 = 30;
+
+/// Messages checked, and embedded, per request by `embed_pending_messages`.
+const PENDING_EMBEDDING_BATCH: usize = 100;
+
+/// Database pages copied per step of an online backup.
+const BACKUP_PAGES_PER_STEP: i32 = 1024;
 
 #[derive(Clone)]
 pub struct KnowledgeBase<E: EmbeddingModel + Clone + 'static> {
     conn: Connection,
-    document_store: SqliteVectorStore<E, Document>,
-    message_store: SqliteVectorStore<E, Message>,
     embedding_model: E,
+    chunker: MarkdownChunker,
+    collections: HashMap<String, CollectionConfig>,
+    /// Collections embedded with their own model.
+    collection_models: HashMap<String, E>,
+    background_embedding: bool,
+    relational: Arc<dyn RelationalStore>,
+    vectors: Arc<dyn VectorStore>,
 }
 
 impl<E: EmbeddingModel> KnowledgeBase<E> {
-    pub async fn new(conn: Connection, embedding_model: E) -> Result<Self, VectorStoreError> {
-        let document_store = SqliteVectorStore::new(conn.clone(), &embedding_model).await?;
-        let message_store = SqliteVectorStore::new(conn.clone(), &embedding_model).await?;
-
-        conn.call(|conn| {
-            conn.execute_batch(
-                "BEGIN;
-
-                -- User management tables
-                CREATE TABLE IF NOT EXISTS accounts (
-                    id INTEGER PRIMARY KEY AUTOINCREMENT,
-                    name TEXT NOT NULL,
-                    source_id TEXT NOT NULL UNIQUE,
-                    source TEXT NOT NULL,
-                    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-                    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
-                );
-                CREATE INDEX IF NOT EXISTS idx_source_id_source ON accounts(source_id, source);
-
-                -- Channel tables
-                CREATE TABLE IF NOT EXISTS channels (
-                    id INTEGER PRIMARY KEY AUTOINCREMENT,
-                    channel_id TEXT NOT NULL UNIQUE,
-                    channel_type TEXT NOT NULL,
-                    source TEXT NOT NULL,
-                    name TEXT,
-                    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-                    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
-                );
-                CREATE INDEX IF NOT EXISTS idx_channel_id_type ON channels(channel_id, channel_type);
+    /// Opens the knowledge base, migrating its schema. `model_name` identifies
+    /// the embedding model, e.g. `text-embedding-3-small`: vectors of another
+    /// model are refused until re-embedded with a `ReembedJob`.
+    pub async fn new(
+        conn: Connection,
+        embedding_model: E,
+        model_name: &str,
+    ) -> Result<Self, VectorStoreError> {
+        let storage = Arc::new(SqliteStorage::new(conn.clone()));
+        Self::with_storage(conn, embedding_model, model_name, storage.clone(), storage).await
+    }
 
-                COMMIT;"
-            )
-            .map_err(tokio_rusqlite::Error::from)
+    /// Like `new`, keeping messages, accounts, channels, summaries and facts
+    /// in `relational` and their vectors in `vectors`. Documents, the
+    /// embedding cache and the record of embedding models stay in the
+    /// SQLite database.
+    pub async fn with_storage(
+        conn: Connection,
+        embedding_model: E,
+        model_name: &str,
+        relational: Arc<dyn RelationalStore>,
+        vectors: Arc<dyn VectorStore>,
+    ) -> Result<Self, VectorStoreError> {
+        let model_name = model_name.to_string();
+        let dimensions = embedding_model.ndims();
+
+        conn.call(move |conn| {
+            super::migrations::migrate(conn)
+                .map_err(|e| tokio_rusqlite::Error::Other(Box::new(e)))?;
+            reembed::check_embedding_model(conn, &model_name, dimensions as i64)
+                .map_err(|e| tokio_rusqlite::Error::Other(Box::new(e)))
         })
         .await
         .map_err(|e| VectorStoreError::DatastoreError(Box::new(e)))?;
 
+        for table in reembed::VECTOR_TABLES {
+            vectors
+                .create_table(table, dimensions)
+                .await
+                .map_err(|e| VectorStoreError::DatastoreError(e.into()))?;
+        }
+
         Ok(Self {
             conn,
-            document_store,
-            message_store,
             embedding_model,
+            chunker: MarkdownChunker::default(),
+            collections: HashMap::new(),
+            collection_models: HashMap::new(),
+            background_embedding: false,
+            relational,
+            vectors,
         })
     }
 
-    pub async fn create_user(&self, name: String, source: String) -> Result<i64, SqliteError> {
-        self.conn
+    /// Sets how documents are split before they are embedded, in the
+    /// default collection and those without their own settings.
+    pub fn with_chunker(mut self, chunker: MarkdownChunker) -> Self {
+        self.chunker = chunker;
+        self
+    }
+
+    /// Stores new messages right away and embeds them in the background, so
+    /// that handling a message doesn't wait on the embedding API. Messages
+    /// are missing from vector search until then.
+    pub fn with_background_embedding(mut self, enabled: bool) -> Self {
+        self.background_embedding = enabled;
+        self
+    }
+
+    /// Registers the settings of a named document collection. Collections
+    /// without settings use the defaults.
+    pub fn with_collection(mut self, name: impl Into<String>, config: CollectionConfig) -> Self {
+        self.collections.insert(name.into(), config);
+        self
+    }
+
+    /// Embeds a named collection with its own model, e.g. a code model for
+    /// "changelog", keeping its vectors in a `collection_{name}` vector
+    /// table. `model_name` is recorded like the knowledge base's: documents
+    /// of the collection embedded with another model, including the
+    /// knowledge base's, are re-embedded right away. Register the model on
+    /// every start, vector search misses the collection's documents
+    /// otherwise.
+    pub async fn with_collection_model(
+        mut self,
+        name: &str,
+        embedding_model: E,
+        model_name: &str,
+    ) -> anyhow::Result<Self> {
+        if !collections::is_valid_name(name) {
+            anyhow::bail!(
+                "Collection {name} can't have its own embedding model, its name may only hold letters, digits and underscores"
+            );
+        }
+
+        let collection = name.to_string();
+        let table = collections::vector_table_name(name);
+        let model = model_name.to_string();
+        let dimensions = embedding_model.ndims();
+        let (stale, documents) = self
+            .conn
             .call(move |conn| {
-                conn.query_row(
-                    "INSERT INTO accounts (name, source, created_at, updated_at)
-                     VALUES (?1, ?2, CURRENT_TIMESTAMP, CURRENT_TIMESTAMP)
-                     ON CONFLICT(name) DO UPDATE SET 
-                         updated_at = CURRENT_TIMESTAMP
-                     RETURNING id",
-                    rusqlite::params![name, source],
-                    |row| row.get(0),
-                )
-                .map_err(tokio_rusqlite::Error::from)
+                let stale =
+                    collections::record_model(conn, &collection, &model, dimensions as i64)?;
+                Ok((stale, collections::documents(conn, &collection)?))
             })
+            .await?;
+
+        if stale {
+            info!(
+                collection = name,
+                model = model_name,
+                "Re-embedding collection"
+            );
+            self.vectors.drop_table(&table).await?;
+        }
+        self.vectors.create_table(&table, dimensions).await?;
+
+        // Vectors of the knowledge base's model, from before the collection
+        // had its own
+        let keys: Vec<StoreKey> = documents.iter().map(|(key, _)| key.clone()).collect();
+        self.vectors
+            .remove_vectors("documents", keys.clone())
+            .await?;
+
+        let missing: HashSet<StoreKey> = self
+            .vectors
+            .missing_vectors(&table, keys)
+            .await?
+            .into_iter()
+            .collect();
+        let pending: Vec<_> = documents
+            .into_iter()
+            .filter(|(key, _)| missing.contains(key))
+            .collect();
+
+        if !pending.is_empty() {
+            info!(
+                collection = name,
+                count = pending.len(),
+                "Embedding collection"
+            );
+            let (keys, contents): (Vec<StoreKey>, Vec<String>) = pending.into_iter().unzip();
+            let vectors = embed_contents(&embedding_model, contents).await?;
+            self.vectors
+                .add_vectors(&table, keys.into_iter().zip(vectors).collect())
+                .await?;
+        }
+
+        self.collection_models
+            .insert(name.to_string(), embedding_model);
+        Ok(self)
+    }
+
+    pub fn collection_config(&self, name: &str) -> Option<&CollectionConfig> {
+        self.collections.get(name)
+    }
+
+    /// Vector search over document chunks.
+    pub fn document_index(self) -> HybridIndex<E, DocumentChunk> {
+        self.hybrid_document_index(vector_only())
+    }
+
+    /// Vector search over messages.
+    pub fn message_index(self) -> HybridIndex<E, Message> {
+        self.hybrid_message_index(vector_only())
+    }
+
+    /// Like `document_index`, with full-text matches merged into the results.
+    pub fn hybrid_document_index(self, config: HybridConfig) -> HybridIndex<E, DocumentChunk> {
+        HybridIndex::new(
+            Rows::Sqlite(self.conn),
+            self.vectors,
+            self.embedding_model,
+            config,
+        )
+    }
+
+    /// Hybrid index over one document collection, with the collection's
+    /// retrieval settings.
+    pub fn collection_index(self, name: &str) -> HybridIndex<E, DocumentChunk> {
+        let config = self
+            .collections
+            .get(name)
+            .map(|collection| collection.retrieval.clone())
+            .unwrap_or_default();
+        let embedding_model = self.collection_models.get(name).cloned();
+
+        let index = self
+            .hybrid_document_index(config)
+            .with_filter(DocumentFilter {
+                collection: Some(name.to_string()),
+                ..Default::default()
+            });
+
+        match embedding_model {
+            Some(embedding_model) => {
+                index.with_vector_table(embedding_model, collections::vector_table_name(name))
+            }
+            None => index,
+        }
+    }
+
+    /// Like `message_index`, with full-text matches merged into the results.
+    pub fn hybrid_message_index(self, config: HybridConfig) -> HybridIndex<E, Message> {
+        HybridIndex::new(
+            Rows::Messages(self.relational),
+            self.vectors,
+            self.embedding_model,
+            config,
+        )
+    }
+
+    /// Vector search over summaries.
+    pub fn summary_index(self) -> HybridIndex<E, Summary> {
+        HybridIndex::new(
+            Rows::Summaries(self.relational),
+            self.vectors,
+            self.embedding_model,
+            vector_only(),
+        )
+    }
+
+    /// Creates the account of a platform user, or refreshes its display name.
+    pub async fn upsert_account(
+        &self,
+        source: Source,
+        source_id: &str,
+        name: &str,
+    ) -> Result<Account, SqliteError> {
+        self.relational
+            .upsert_account(source, source_id, name)
             .await
-            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
     }
 
-    pub fn document_index(self) -> SqliteVectorIndex<E, Document> {
-        SqliteVectorIndex::new(self.embedding_model, self.document_store)
+    pub async fn get_account(
+        &self,
+        source: Source,
+        source_id: &str,
+    ) -> Result<Option<Account>, SqliteError> {
+        self.relational
+            .get_account(source, source_id)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
     }
 
-    pub fn message_index(self) -> SqliteVectorIndex<E, Message> {
-        SqliteVectorIndex::new(self.embedding_model, self.message_store)
+    pub async fn get_account_by_id(&self, id: i64) -> Result<Option<Account>, SqliteError> {
+        self.relational
+            .get_account_by_id(id)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
     }
 
-    pub async fn get_user_by_source(&self, source: String) -> Result<Option<Account>, SqliteError> {
-        self.conn
-            .call(move |conn| {
-                let mut stmt = conn.prepare(
-                    "SELECT id, name, source, created_at, updated_at FROM accounts WHERE source = ?1"
-                )?;
+    /// Lists accounts ordered by name, optionally restricted to one platform.
+    pub async fn list_accounts(&self, source: Option<Source>) -> Result<Vec<Account>, SqliteError> {
+        self.relational
+            .list_accounts(source)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
+    }
 
-                let account = stmt.query_row(rusqlite::params![source], |row| {
-                    Account::try_from(row)
-                }).optional()?;
+    /// Returns the most recent messages written by an account, newest first.
+    pub async fn account_messages(
+        &self,
+        account_id: i64,
+        limit: usize,
+    ) -> Result<Vec<Message>, SqliteError> {
+        self.relational
+            .account_messages(account_id, limit)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
+    }
 
-                Ok(account)
-            })
+    /// Issues a one-time code that links another account to `account_id` when
+    /// confirmed from it, replacing any code previously issued to the account.
+    pub async fn create_link_code(
+        &self,
+        account_id: i64,
+        ttl: std::time::Duration,
+    ) -> Result<String, SqliteError> {
+        let code = generate_link_code();
+
+        self.relational
+            .create_link_code(account_id, &code, ttl)
             .await
-            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+            .map_err(|e| SqliteError::DatabaseError(e.into()))?;
+
+        Ok(code)
     }
 
-    pub async fn create_channel(
+    /// Confirms a link code from `account_id`, attaching both accounts to the
+    /// same person and merging their persons if both were already linked.
+    ///
+    /// Returns `None` when the code is unknown, expired or was issued to
+    /// `account_id` itself.
+    pub async fn confirm_link_code(
         &self,
-        channel_id: String,
-        channel_type: String,
-        name: Option<String>,
-    ) -> Result<i64, SqliteError> {
-        self.conn
-            .call(move |conn| {
-                conn.query_row(
-                    "INSERT INTO channels (channel_id, channel_type, name, created_at, updated_at)
-                     VALUES (?1, ?2, ?3, CURRENT_TIMESTAMP, CURRENT_TIMESTAMP)
-                     ON CONFLICT(channel_id) DO UPDATE SET 
-                         name = COALESCE(?3, name),
-                         updated_at = CURRENT_TIMESTAMP
-                     RETURNING id",
-                    rusqlite::params![channel_id, channel_type, name],
-                    |row| row.get(0),
-                )
-                .map_err(tokio_rusqlite::Error::from)
-            })
+        code: &str,
+        account_id: i64,
+    ) -> Result<Option<Person>, SqliteError> {
+        self.relational
+            .confirm_link_code(&code.trim().to_uppercase(), account_id)
             .await
-            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
     }
 
-    pub async fn get_channel(&self, id: i64) -> Result<Option<Channel>, SqliteError> {
-        self.conn
-            .call(move |conn| {
-                let mut stmt = conn.prepare(
-                    "SELECT id, name, source, created_at, updated_at FROM channels WHERE id = ?1",
-                )?;
+    /// Detaches an account from its person, leaving its other accounts linked.
+    pub async fn unlink_account(&self, account_id: i64) -> Result<(), SqliteError> {
+        self.relational
+            .unlink_account(account_id)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
+    }
 
-                let channel = stmt
-                    .query_row(rusqlite::params![id], |row| Channel::try_from(row))
-                    .optional()?;
+    pub async fn get_person(&self, id: i64) -> Result<Option<Person>, SqliteError> {
+        self.relational
+            .get_person(id)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
+    }
 
-                Ok(channel)
-            })
+    pub async fn person_accounts(&self, person_id: i64) -> Result<Vec<Account>, SqliteError> {
+        self.relational
+            .person_accounts(person_id)
             .await
-            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
+    }
+
+    /// Returns the most recent messages written from any of a person's
+    /// accounts, newest first.
+    pub async fn person_messages(
+        &self,
+        person_id: i64,
+        limit: usize,
+    ) -> Result<Vec<Message>, SqliteError> {
+        self.relational
+            .person_messages(person_id, limit)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
+    }
+
+    /// Records a channel's metadata as last seen by a client.
+    pub async fn upsert_channel(&self, channel: ChannelMetadata) -> Result<Channel, SqliteError> {
+        self.relational
+            .upsert_channel(channel)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
+    }
+
+    pub async fn get_channel(&self, id: i64) -> Result<Option<Channel>, SqliteError> {
+        self.relational
+            .get_channel(id)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
     }
 
     pub async fn get_channels_by_source(
         &self,
         source: String,
     ) -> Result<Vec<Channel>, SqliteError> {
-        self.conn
-            .call(move |conn| {
-                let mut stmt = conn.prepare(
-                    "SELECT id, name, source, created_at, updated_at FROM channels WHERE source = ?1"
-                )?;
-
-                let channels = stmt.query_map(rusqlite::params![source], |row| {
-                    Channel::try_from(row)
-                }).and_then(|mapped_rows| {
-                    mapped_rows.collect::<Result<Vec<Channel>, _>>()
-                })?;
+        self.relational
+            .get_channels_by_source(&source)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
+    }
 
-                Ok(channels)
-            })
+    pub async fn channel_settings(
+        &self,
+        channel_id: &str,
+    ) -> Result<Option<ChannelSettings>, SqliteError> {
+        self.relational
+            .channel_settings(channel_id)
             .await
-            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
     }
 
-    pub async fn create_message(&self, msg: Message) -> anyhow::Result<i64> {
-        let embeddings = EmbeddingsBuilder::new(self.embedding_model.clone())
-            .documents(vec![msg.clone()])?
-            .build()
-            .await?;
+    pub async fn set_channel_settings(&self, settings: ChannelSettings) -> anyhow::Result<()> {
+        self.relational.set_channel_settings(settings).await
+    }
 
-        let store = self.message_store.clone();
+    /// Stores a message and its embedding. With background embedding, the
+    /// message is stored right away and embedded afterwards. Returns its key,
+    /// or `None` when the message was stored already.
+    pub async fn create_message(&self, msg: Message) -> anyhow::Result<Option<StoreKey>> {
+        let content = msg.content.clone();
+        let Some(key) = self.relational.insert_message(msg).await? else {
+            return Ok(None);
+        };
+        self.index_message(key.clone(), content, false).await?;
 
-        self.conn
-            .call(move |conn| {
-                let tx = conn.transaction()?;
+        Ok(Some(key))
+    }
 
-                // First upsert the channel
-                tx.execute(
-                    "INSERT INTO channels (channel_id, channel_type, source, name, created_at, updated_at) 
-                     VALUES (?1, ?2, ?3, NULL, CURRENT_TIMESTAMP, CURRENT_TIMESTAMP)
-                     ON CONFLICT (channel_id) DO UPDATE SET 
-                     updated_at = CURRENT_TIMESTAMP",
-                    [
-                        &msg.channel_id,
-                        &msg.channel_type.as_str().to_string(),
-                        &msg.source.as_str().to_string(),
-                    ],
-                )?;
+    /// Embeds a stored message, in the background with background
+    /// embedding. The vector of an edited message replaces its previous one.
+    async fn index_message(
+        &self,
+        key: StoreKey,
+        content: String,
+        edited: bool,
+    ) -> anyhow::Result<()> {
+        if !self.background_embedding {
+            self.embed_messages(vec![(key, content)], edited).await?;
+            return Ok(());
+        }
 
-                let id = store.add_rows_with_txn(&tx, embeddings)?;
+        let knowledge = self.clone();
+        tokio::spawn(async move {
+            let messages = vec![(key.clone(), content)];
+            if let Err(err) = knowledge.embed_messages(messages, edited).await {
+                warn!(
+                    ?err,
+                    key = key.as_str(),
+                    "Failed to embed message in the background"
+                );
+            }
+        });
 
-                tx.commit()?;
+        Ok(())
+    }
 
-                Ok(id)
-            })
+    /// Embeds the messages stored without an embedding, e.g. by background
+    /// embedding interrupted by a restart. Returns how many were embedded.
+    pub async fn embed_pending_messages(&self) -> anyhow::Result<usize> {
+        let mut embedded = 0;
+        let mut after = None;
+
+        loop {
+            let candidates = self
+                .relational
+                .messages_after(after.clone(), PENDING_EMBEDDING_BATCH)
+                .await?;
+            let Some((last, _)) = candidates.last() else {
+                return Ok(embedded);
+            };
+            after = Some(last.clone());
+
+            let missing: HashSet<StoreKey> = self
+                .vectors
+                .missing_vectors(
+                    "messages",
+                    candidates.iter().map(|(key, _)| key.clone()).collect(),
+                )
+                .await?
+                .into_iter()
+                .collect();
+            let pending: Vec<_> = candidates
+                .into_iter()
+                .filter(|(key, _)| missing.contains(key))
+                .collect();
+
+            if !pending.is_empty() {
+                embedded += self.embed_messages(pending, false).await?;
+            }
+        }
+    }
+
+    /// Adds the vectors of stored messages, replacing those of edited
+    /// messages. Returns how many were added.
+    async fn embed_messages(
+        &self,
+        messages: Vec<(StoreKey, String)>,
+        edited: bool,
+    ) -> anyhow::Result<usize> {
+        let (keys, contents): (Vec<StoreKey>, Vec<String>) = messages.into_iter().unzip();
+        let vectors: Vec<_> = keys
+            .iter()
+            .cloned()
+            .zip(embed_contents(&self.embedding_model, contents).await?)
+            .collect();
+
+        let added = if edited {
+            let count = vectors.len();
+            self.vectors.replace_vectors("messages", vectors).await?;
+            count
+        } else {
+            self.vectors.add_vectors("messages", vectors).await?
+        };
+
+        // A message deleted while it was embedded would keep its vector
+        let stored: HashSet<StoreKey> = self
+            .relational
+            .messages_by_key(keys.clone())
+            .await?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        let deleted: Vec<StoreKey> = keys
+            .into_iter()
+            .filter(|key| !stored.contains(key))
+            .collect();
+        if !deleted.is_empty() {
+            self.vectors.remove_vectors("messages", deleted).await?;
+        }
+
+        debug!(added, "Embedded stored messages");
+        Ok(added)
+    }
+
+    /// Replaces the content of a stored message after it was edited on its
+    /// platform, re-embedding it and keeping the previous content as a
+    /// revision. Returns that revision, or `None` when the message is unknown
+    /// or its content didn't change.
+    pub async fn edit_message(
+        &self,
+        source: Source,
+        id: &str,
+        content: &str,
+    ) -> anyhow::Result<Option<MessageRevision>> {
+        let Some((key, revision)) = self.relational.edit_message(source, id, content).await? else {
+            return Ok(None);
+        };
+
+        self.index_message(key, content.to_string(), true).await?;
+        Ok(Some(revision))
+    }
+
+    /// Returns the earlier contents of a message, oldest first.
+    pub async fn message_revisions(
+        &self,
+        source: Source,
+        id: &str,
+    ) -> Result<Vec<MessageRevision>, SqliteError> {
+        self.relational
+            .message_revisions(source, id)
             .await
-            .map_err(|e| anyhow::anyhow!(e))
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
     }
 
     pub async fn get_message(&self, id: i64) -> Result<Option<Message>, SqliteError> {
-        self.conn
-            .call(move |conn| {
-                Ok(conn.prepare("SELECT id, source, source_id, channel_type, channel_id, account_id, role, content, created_at FROM messages WHERE id = ?1")?
-                    .query_row(rusqlite::params![id], |row| {
-                        Message::try_from(row)
-                    }).optional()?)
-            })
+        self.relational
+            .get_message(id)
             .await
-            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
     }
 
     pub async fn get_recent_messages(
         &self,
-        channel_id: i64,
+        channel_id: &str,
         limit: usize,
     ) -> Result<Vec<Message>, SqliteError> {
+        self.relational
+            .recent_messages(channel_id, limit)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
+    }
+
+    /// Returns the last `limit` messages of a channel, oldest first, with each
+    /// speaker's display name resolved through `accounts`.
+    pub async fn channel_messages(
+        &self,
+        channel_id: &str,
+        limit: i64,
+    ) -> anyhow::Result<Vec<HistoryEntry>> {
+        self.relational.channel_history(channel_id, limit).await
+    }
+
+    /// Files a stored message under a conversation: the one of the message it
+    /// replies to, the one of its thread, or the latest of its channel when it
+    /// was active within `CONVERSATION_GAP_MINUTES`. Starts a new conversation
+    /// otherwise.
+    pub async fn assign_conversation(
+        &self,
+        msg: &Message,
+        hints: ConversationHints,
+    ) -> Result<Conversation, SqliteError> {
+        self.relational
+            .assign_conversation(
+                msg,
+                hints,
+                chrono::Duration::minutes(CONVERSATION_GAP_MINUTES),
+            )
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
+    }
+
+    pub async fn get_conversation(&self, id: i64) -> Result<Option<Conversation>, SqliteError> {
+        self.relational
+            .get_conversation(id)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
+    }
+
+    /// Returns the conversations of a channel, most recently active first.
+    pub async fn channel_conversations(
+        &self,
+        channel_id: &str,
+        limit: usize,
+    ) -> Result<Vec<Conversation>, SqliteError> {
+        self.relational
+            .channel_conversations(channel_id, limit)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
+    }
+
+    /// Returns the last `limit` messages of a conversation, oldest first.
+    pub async fn conversation_messages(
+        &self,
+        conversation_id: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<HistoryEntry>> {
+        self.relational
+            .conversation_messages(conversation_id, limit)
+            .await
+    }
+
+    pub async fn set_conversation_title(&self, id: i64, title: &str) -> Result<(), SqliteError> {
+        self.relational
+            .set_conversation_title(id, title)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
+    }
+
+    /// Returns the most recent summary of `scope`, which covers all of its
+    /// older summaries.
+    pub async fn latest_summary(
+        &self,
+        scope: &SummaryScope,
+    ) -> Result<Option<Summary>, SqliteError> {
+        self.relational
+            .latest_summary(scope)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
+    }
+
+    /// Returns up to `limit` messages of `scope` created after `after`,
+    /// oldest first.
+    pub async fn messages_after(
+        &self,
+        scope: &SummaryScope,
+        after: Option<chrono::DateTime<chrono::Utc>>,
+        limit: i64,
+    ) -> anyhow::Result<Vec<HistoryEntry>> {
+        self.relational.scope_messages(scope, after, limit).await
+    }
+
+    pub async fn add_summary(&self, summary: Summary) -> anyhow::Result<()> {
+        let mut vectors =
+            embed_contents(&self.embedding_model, vec![summary.content.clone()]).await?;
+        let vector = vectors
+            .pop()
+            .ok_or_else(|| anyhow::anyhow!("No embedding returned for summary"))?;
+
+        let key = self.relational.insert_summary(summary).await?;
+        self.vectors
+            .add_vectors("summaries", vec![(key, vector)])
+            .await?;
+
+        Ok(())
+    }
+
+    /// Stores a fact, or raises the confidence of an identical one already
+    /// known about the account.
+    pub async fn add_fact(&self, fact: NewFact) -> Result<Fact, SqliteError> {
+        self.relational
+            .add_fact(fact)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
+    }
+
+    /// Lists the facts known about an account and the accounts linked to it,
+    /// most confident first.
+    pub async fn account_facts(
+        &self,
+        account_id: i64,
+        min_confidence: f64,
+    ) -> Result<Vec<Fact>, SqliteError> {
+        self.relational
+            .account_facts(account_id, min_confidence)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
+    }
+
+    pub async fn delete_fact(&self, id: i64) -> Result<bool, SqliteError> {
+        self.relational
+            .delete_fact(id)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
+    }
+
+    /// Forgets every fact about an account, returning how many were deleted.
+    pub async fn delete_account_facts(&self, account_id: i64) -> Result<usize, SqliteError> {
+        self.relational
+            .delete_account_facts(account_id)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
+    }
+
+    /// Returns the creation time of the last message of the account facts
+    /// were extracted from.
+    pub async fn facts_extracted_until(
+        &self,
+        account_id: i64,
+    ) -> Result<Option<chrono::DateTime<chrono::Utc>>, SqliteError> {
+        self.relational
+            .facts_extracted_until(account_id)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
+    }
+
+    pub async fn set_facts_extracted_until(
+        &self,
+        account_id: i64,
+        until: chrono::DateTime<chrono::Utc>,
+    ) -> Result<(), SqliteError> {
+        self.relational
+            .set_facts_extracted_until(account_id, until)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
+    }
+
+    /// Adds a retention policy, replacing the one of the same source and
+    /// channel if any.
+    pub async fn set_retention_policy(&self, policy: RetentionPolicy) -> Result<(), SqliteError> {
+        self.relational
+            .set_retention_policy(policy)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
+    }
+
+    pub async fn remove_retention_policy(
+        &self,
+        source: Option<Source>,
+        channel_id: Option<&str>,
+    ) -> Result<bool, SqliteError> {
+        self.relational
+            .remove_retention_policy(source, channel_id)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
+    }
+
+    pub async fn retention_policies(&self) -> Result<Vec<RetentionPolicy>, SqliteError> {
+        self.relational
+            .retention_policies()
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))
+    }
+
+    /// Deletes the messages, and their embeddings, that fall outside of a
+    /// retention policy. Returns how many were deleted.
+    pub async fn apply_retention(&self) -> Result<usize, SqliteError> {
+        let policies = self.retention_policies().await?;
+        let now = chrono::Utc::now();
+        let mut deleted = 0;
+
+        for policy in policies {
+            let expired = self
+                .relational
+                .delete_expired_messages(policy, now)
+                .await
+                .map_err(|e| SqliteError::DatabaseError(e.into()))?;
+            deleted += self
+                .forget("messages", expired)
+                .await
+                .map_err(|e| SqliteError::DatabaseError(e.into()))?;
+        }
+
+        Ok(deleted)
+    }
+
+    /// Removes a message and its embedding, e.g. after it was deleted on its
+    /// platform. Returns whether it was stored.
+    pub async fn delete_message(&self, source: Source, id: &str) -> Result<bool, SqliteError> {
+        let Some(deleted) = self
+            .relational
+            .delete_message(source, id)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))?
+        else {
+            return Ok(false);
+        };
+
+        self.forget("messages", vec![deleted])
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))?;
+        Ok(true)
+    }
+
+    /// Forgets an account: its messages with their embeddings, the facts and
+    /// summaries about it, and the summaries of the channels it wrote in,
+    /// which are rebuilt from the remaining messages.
+    pub async fn purge_account(&self, account_id: i64) -> Result<PurgeReport, SqliteError> {
+        let Some(purged) = self
+            .relational
+            .purge_account(account_id)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(e.into()))?
+        else {
+            return Ok(PurgeReport::default());
+        };
+
+        Ok(PurgeReport {
+            messages: self
+                .forget("messages", purged.messages)
+                .await
+                .map_err(|e| SqliteError::DatabaseError(e.into()))?,
+            summaries: self
+                .forget("summaries", purged.summaries)
+                .await
+                .map_err(|e| SqliteError::DatabaseError(e.into()))?,
+            facts: purged.facts,
+        })
+    }
+
+    /// Removes the vectors of deleted rows and the cached embeddings of
+    /// their contents, so that no embedding of deleted content outlives
+    /// it. Returns how many rows there were.
+    async fn forget(&self, table: &str, deleted: Vec<DeletedRow>) -> anyhow::Result<usize> {
+        if deleted.is_empty() {
+            return Ok(0);
+        }
+
+        let count = deleted.len();
+        let (keys, contents): (Vec<StoreKey>, Vec<Vec<String>>) = deleted
+            .into_iter()
+            .map(|row| (row.key, row.contents))
+            .unzip();
+        let contents: Vec<String> = contents.into_iter().flatten().collect();
+
+        self.vectors.remove_vectors(table, keys).await?;
         self.conn
-            .call(move |conn| {
-                let mut stmt = conn.prepare(
-                    "SELECT id, source, source_id, channel_type, channel_id, account_id, role, content, created_at 
-                     FROM messages 
-                     WHERE channel_id = ?1 
-                     ORDER BY created_at DESC 
-                     LIMIT ?2",
-                )?;
+            .call(move |conn| Ok(delete_cached_embeddings(conn, &contents)?))
+            .await?;
 
-                let messages = stmt
-                    .query_map(rusqlite::params![channel_id, limit], |row| {
-                        Message::try_from(row)
-                    })?
+        Ok(count)
+    }
+
+    /// Embedding model behind each vector store table.
+    pub async fn embedding_models(&self) -> Result<Vec<EmbeddingModelRecord>, SqliteError> {
+        self.conn
+            .call(|conn| {
+                let models = conn
+                    .prepare(
+                        "SELECT table_name, model, dimensions FROM embedding_models ORDER BY table_name",
+                    )?
+                    .query_map([], |row| EmbeddingModelRecord::try_from(row))?
                     .collect::<Result<Vec<_>, _>>()?;
 
-                Ok(messages)
+                Ok(models)
             })
             .await
             .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
     }
 
-    pub async fn channel_messages(
+    /// Writes the knowledge base to a directory of JSON Lines files, from one
+    /// read transaction so the bot can keep running meanwhile.
+    pub async fn export(
         &self,
-        channel_id: &str,
-        limit: i64,
-    ) -> anyhow::Result<Vec<(String, String)>> {
-        let channel_id = channel_id.to_string();
+        dir: impl AsRef<Path>,
+        options: ExportOptions,
+    ) -> anyhow::Result<ArchiveManifest> {
+        let dir = dir.as_ref().to_path_buf();
+
+        let manifest = self
+            .conn
+            .call(move |conn| {
+                archive::export(conn, &dir, &options)
+                    .map_err(|e| tokio_rusqlite::Error::Other(Box::new(e)))
+            })
+            .await?;
+
+        Ok(manifest)
+    }
+
+    /// Loads a directory written by `export`. The knowledge base has to be
+    /// empty, and exported embeddings must come from its embedding model.
+    pub async fn import(&self, dir: impl AsRef<Path>) -> anyhow::Result<ArchiveManifest> {
+        let dir = dir.as_ref().to_path_buf();
+
+        let manifest = self
+            .conn
+            .call(move |conn| {
+                archive::import(conn, &dir).map_err(|e| tokio_rusqlite::Error::Other(Box::new(e)))
+            })
+            .await?;
+
+        Ok(manifest)
+    }
+
+    /// Snapshots the database to `path` with SQLite's online backup API,
+    /// without closing it. Queries wait while the pages are copied.
+    pub async fn backup(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref().to_path_buf();
+
+        self.conn
+            .call(move |conn| {
+                let mut target = rusqlite::Connection::open(&path)?;
+                rusqlite::backup::Backup::new(conn, &mut target)?.run_to_completion(
+                    BACKUP_PAGES_PER_STEP,
+                    Duration::ZERO,
+                    None,
+                )?;
+
+                Ok(())
+            })
+            .await?;
+
+        info!("Backed up KnowledgeBase");
+        Ok(())
+    }
+
+    /// Returns the chunk with the given ID along with up to `radius` chunks
+    /// before and after it in the same document, in document order. Useful
+    /// when a retrieved chunk lacks the surrounding context.
+    pub async fn neighbouring_chunks(
+        &self,
+        chunk_id: &str,
+        radius: i64,
+    ) -> Result<Vec<DocumentChunk>, SqliteError> {
+        let chunk_id = chunk_id.to_string();
 
         self.conn
             .call(move |conn| {
                 let mut stmt = conn.prepare(
-                    "SELECT source_id, content 
-                     FROM messages 
-                     WHERE channel_id = ?1
-                     ORDER BY created_at DESC 
-                     LIMIT ?2",
+                    "SELECT d.id, d.parent_id, d.source_id, d.chunk_index, d.start_offset, d.end_offset,
+                            d.heading_path, d.content, d.created_at, d.content_hash,
+                            d.metadata, d.collection
+                     FROM documents d
+                     JOIN documents chunk ON chunk.parent_id = d.parent_id
+                     WHERE chunk.id = ?1
+                       AND d.chunk_index BETWEEN chunk.chunk_index - ?2 AND chunk.chunk_index + ?2
+                     ORDER BY d.chunk_index",
                 )?;
-                let messages = stmt
-                    .query_map([&channel_id, &limit.to_string()], |row| {
-                        Ok((row.get(0)?, row.get(1)?))
+
+                let chunks = stmt
+                    .query_map(rusqlite::params![chunk_id, radius], |row| {
+                        DocumentChunk::try_from(row)
                     })?
                     .collect::<Result<Vec<_>, _>>()?;
-                Ok(messages)
+
+                Ok(chunks)
             })
             .await
-            .map_err(|e| anyhow::anyhow!(e))
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Indexes documents in the default collection, skipping those stored
+    /// with the same content and re-embedding those whose content changed.
+    pub async fn add_documents<I>(&mut self, documents: I) -> anyhow::Result<IndexReport>
+    where
+        I: IntoIterator<Item = Document>,
+    {
+        self.index_documents(DEFAULT_COLLECTION, documents, None)
+            .await
     }
 
-    pub async fn add_documents<'a, I>(&mut self, documents: I) -> anyhow::Result<()>
+    /// Like `add_documents`, and also removes the stored documents of
+    /// `source_id` missing from `documents`, e.g. files deleted from a repo.
+    pub async fn sync_documents<I>(
+        &mut self,
+        source_id: &str,
+        documents: I,
+    ) -> anyhow::Result<IndexReport>
     where
         I: IntoIterator<Item = Document>,
     {
-        info!("Adding documents to KnowledgeBase");
-        let embeddings = EmbeddingsBuilder::new(self.embedding_model.clone())
-            .documents(documents)?
-            .build()
+        self.index_documents(DEFAULT_COLLECTION, documents, Some(source_id.to_string()))
+            .await
+    }
+
+    /// Like `add_documents`, into a named collection. Documents stored in
+    /// another collection move to this one.
+    pub async fn add_collection_documents<I>(
+        &mut self,
+        collection: &str,
+        documents: I,
+    ) -> anyhow::Result<IndexReport>
+    where
+        I: IntoIterator<Item = Document>,
+    {
+        self.index_documents(collection, documents, None).await
+    }
+
+    /// Like `sync_documents`, within a named collection.
+    pub async fn sync_collection_documents<I>(
+        &mut self,
+        collection: &str,
+        source_id: &str,
+        documents: I,
+    ) -> anyhow::Result<IndexReport>
+    where
+        I: IntoIterator<Item = Document>,
+    {
+        self.index_documents(collection, documents, Some(source_id.to_string()))
+            .await
+    }
+
+    async fn index_documents<I>(
+        &mut self,
+        collection: &str,
+        documents: I,
+        prune_source_id: Option<String>,
+    ) -> anyhow::Result<IndexReport>
+    where
+        I: IntoIterator<Item = Document>,
+    {
+        info!(collection, "Adding documents to KnowledgeBase");
+
+        let chunker = match self.collections.get(collection) {
+            Some(config) => self.chunker.with_config(config.chunker.clone()),
+            None => self.chunker.clone(),
+        };
+
+        // Content hash, source, metadata and collection of every stored document
+        let stored: HashMap<String, (String, String, String, String)> = self
+            .conn
+            .call(|conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT parent_id, content_hash, source_id, metadata, collection
+                     FROM documents
+                     WHERE chunk_index = 0",
+                )?;
+
+                let stored = stmt
+                    .query_map([], |row| {
+                        Ok((
+                            row.get(0)?,
+                            (row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?),
+                        ))
+                    })?
+                    .collect::<Result<HashMap<_, _>, _>>()?;
+
+                Ok(stored)
+            })
             .await?;
 
-        debug!("Adding embeddings to document store");
-        self.document_store.add_rows(embeddings).await?;
+        let mut report = IndexReport::default();
+        let mut seen = HashSet::new();
+        let mut replaced = Vec::new();
+        let mut retagged = Vec::new();
+        let mut chunks = Vec::new();
 
-        info!("Successfully added documents to KnowledgeBase");
-        Ok(())
+        for document in documents {
+            if !seen.insert(document.id.clone()) {
+                continue;
+            }
+
+            let content_hash = document.content_hash();
+            match stored.get(&document.id) {
+                // Moving collections re-chunks the document with the new settings
+                Some((stored_hash, _, metadata, stored_collection))
+                    if *stored_hash == content_hash && stored_collection == collection =>
+                {
+                    let metadata_json = document.metadata.to_json();
+                    if *metadata != metadata_json {
+                        retagged.push((document.id.clone(), metadata_json));
+                    }
+                    report.unchanged += 1;
+                    continue;
+                }
+                Some(_) => {
+                    report.updated += 1;
+                    replaced.push(document.id.clone());
+                }
+                None => report.added += 1,
+            }
+
+            chunks.extend(
+                chunker
+                    .chunk(&document.content)
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, chunk)| {
+                        DocumentChunk::new(&document, collection, &content_hash, index, chunk)
+                    }),
+            );
+        }
+
+        if let Some(source_id) = prune_source_id {
+            for (id, (_, stored_source_id, _, stored_collection)) in &stored {
+                if *stored_source_id == source_id
+                    && stored_collection == collection
+                    && !seen.contains(id)
+                {
+                    report.removed += 1;
+                    replaced.push(id.clone());
+                }
+            }
+        }
+
+        let (embedding_model, vector_table) = match self.collection_models.get(collection) {
+            Some(embedding_model) => (embedding_model, collections::vector_table_name(collection)),
+            None => (&self.embedding_model, "documents".to_string()),
+        };
+        let vectors = if chunks.is_empty() {
+            Vec::new()
+        } else {
+            debug!(chunk_count = chunks.len(), vector_table = %vector_table, "Embedding document chunks");
+            let contents = chunks.iter().map(|chunk| chunk.content.clone()).collect();
+            embed_contents(embedding_model, contents).await?
+        };
+
+        let (deleted, keys) = self
+            .conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+
+                let mut deleted = Vec::new();
+                for id in &replaced {
+                    deleted.extend(delete_documents(
+                        &tx,
+                        "parent_id = ?1",
+                        rusqlite::params![id],
+                    )?);
+                }
+                let keys = chunks
+                    .iter()
+                    .map(|chunk| insert_chunk(&tx, chunk))
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                for (id, metadata) in &retagged {
+                    tx.execute(
+                        "UPDATE documents SET metadata = ?1 WHERE parent_id = ?2",
+                        rusqlite::params![metadata, id],
+                    )?;
+                }
+
+                tx.commit()?;
+
+                Ok((deleted, keys))
+            })
+            .await?;
+
+        // Vectors of replaced chunks go first, SQLite may hand their rowids
+        // to the new chunks
+        let mut stale: HashMap<String, Vec<StoreKey>> = HashMap::new();
+        for (table, row) in deleted {
+            stale.entry(table).or_default().push(row.key);
+        }
+        for (table, keys) in stale {
+            self.vectors.remove_vectors(&table, keys).await?;
+        }
+        if !keys.is_empty() {
+            self.vectors
+                .add_vectors(&vector_table, keys.into_iter().zip(vectors).collect())
+                .await?;
+        }
+
+        info!(?report, "Successfully added documents to KnowledgeBase");
+        Ok(report)
+    }
+}
+
+/// Search without its full-text half.
+fn vector_only() -> HybridConfig {
+    HybridConfig {
+        keyword_weight: 0.0,
+        ..Default::default()
+    }
+}
+
+/// Embeds texts in batches the model accepts.
+async fn embed_contents<E: EmbeddingModel>(
+    embedding_model: &E,
+    contents: Vec<String>,
+) -> anyhow::Result<Vec<Vec<f64>>> {
+    let mut vectors = Vec::with_capacity(contents.len());
+
+    for batch in contents.chunks(E::MAX_DOCUMENTS.max(1)) {
+        for embedding in embedding_model.embed_texts(batch.to_vec()).await? {
+            vectors.push(embedding.vec);
+        }
+    }
+
+    Ok(vectors)
+}
+
+/// Stores a document chunk. Returns its key.
+fn insert_chunk(conn: &rusqlite::Connection, chunk: &DocumentChunk) -> rusqlite::Result<StoreKey> {
+    conn.query_row(
+        "INSERT INTO documents (id, source_id, content, created_at, parent_id, chunk_index,
+                                start_offset, end_offset, heading_path, content_hash, metadata, collection)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
+         RETURNING rowid",
+        rusqlite::params![
+            chunk.id,
+            chunk.source_id,
+            chunk.content,
+            chunk.created_at.to_rfc3339(),
+            chunk.parent_id,
+            chunk.chunk_index,
+            chunk.start_offset,
+            chunk.end_offset,
+            chunk.heading_path,
+            chunk.content_hash,
+            chunk.metadata.to_json(),
+            chunk.collection,
+        ],
+        |row| Ok(StoreKey::from(row.get::<_, i64>(0)?)),
+    )
+}
+
+/// Eight characters from an alphabet without look-alikes (0/O, 1/I/L).
+fn generate_link_code() -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ23456789";
+    let mut rng = rand::thread_rng();
+
+    (0..8)
+        .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+        .collect()
+}
+
+/// Deletes the document chunks matching `filter`. Returns each with the
+/// vector table holding its vector: its collection's when the collection
+/// has its own embedding model, `documents` otherwise.
+pub fn delete_documents(
+    conn: &rusqlite::Connection,
+    filter: &str,
+    params: &[&dyn rusqlite::ToSql],
+) -> rusqlite::Result<Vec<(String, DeletedRow)>> {
+    let deleted = conn
+        .prepare(&format!(
+            "DELETE FROM documents WHERE {filter} RETURNING rowid, collection, content"
+        ))?
+        .query_map(params, |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut own_model = conn.prepare("SELECT 1 FROM embedding_models WHERE table_name = ?1")?;
+    deleted
+        .into_iter()
+        .map(|(rowid, collection, content)| {
+            let table = collections::vector_table_name(&collection);
+            let table = if own_model.exists([&table])? {
+                table
+            } else {
+                "documents".to_string()
+            };
+
+            Ok((
+                table,
+                DeletedRow {
+                    key: rowid.into(),
+                    contents: content.into_iter().collect(),
+                },
+            ))
+        })
+        .collect()
+}
+
+/// Forgets the cached embeddings of deleted contents. The cache is keyed by
+/// content hash, so that no embedding of deleted content outlives it.
+pub fn delete_cached_embeddings(
+    conn: &rusqlite::Connection,
+    contents: &[String],
+) -> rusqlite::Result<usize> {
+    let mut delete = conn.prepare("DELETE FROM embedding_cache WHERE content_hash = ?1")?;
+    let mut deleted = 0;
+    for content in contents {
+        deleted += delete.execute([embedding::content_hash(content)])?;
+    }
+
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use rig::vector_store::VectorStoreIndex;
+
+    use super::super::embedding::HashingEmbeddingModel;
+    use super::super::types::ChannelType;
+    use super::*;
+
+    async fn knowledge() -> KnowledgeBase<HashingEmbeddingModel> {
+        unsafe {
+            rusqlite::ffi::sqlite3_auto_extension(Some(std::mem::transmute(
+                sqlite_vec::sqlite3_vec_init as *const (),
+            )));
+        }
+
+        let conn = Connection::open_in_memory().await.unwrap();
+        KnowledgeBase::new(
+            conn,
+            HashingEmbeddingModel::new(64),
+            HashingEmbeddingModel::NAME,
+        )
+        .await
+        .unwrap()
+    }
+
+    fn document(id: &str, content: &str) -> Document {
+        Document {
+            id: id.to_string(),
+            source_id: "docs".to_string(),
+            content: content.to_string(),
+            created_at: chrono::Utc::now(),
+            metadata: Default::default(),
+        }
+    }
+
+    /// A user message in `channel_id`, sent `minutes` after a fixed start.
+    fn message(id: &str, channel_id: &str, account_id: &str, minutes: i64) -> Message {
+        let start = chrono::DateTime::parse_from_rfc3339("2024-06-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+
+        Message {
+            id: id.to_string(),
+            source: Source::Discord,
+            source_id: id.to_string(),
+            channel_type: ChannelType::Text,
+            channel_id: channel_id.to_string(),
+            account_id: account_id.to_string(),
+            role: "user".to_string(),
+            content: format!("message {id} from {account_id}"),
+            created_at: start + chrono::Duration::minutes(minutes),
+        }
+    }
+
+    /// Number of rows in `table`, e.g. to check that embeddings went away
+    /// with their rows.
+    async fn count_rows(knowledge: &KnowledgeBase<HashingEmbeddingModel>, table: &str) -> i64 {
+        let sql = format!("SELECT COUNT(*) FROM {table}");
+        knowledge
+            .conn
+            .call(move |conn| Ok(conn.query_row(&sql, [], |row| row.get(0))?))
+            .await
+            .unwrap()
+    }
+
+    async fn converse(
+        knowledge: &KnowledgeBase<HashingEmbeddingModel>,
+        msg: Message,
+        hints: ConversationHints,
+    ) -> i64 {
+        knowledge.create_message(msg.clone()).await.unwrap();
+        knowledge.assign_conversation(&msg, hints).await.unwrap().id
+    }
+
+    #[tokio::test]
+    async fn test_knowledge_base_runs_offline() {
+        let mut knowledge = knowledge().await;
+
+        let report = knowledge
+            .add_documents([
+                document("vrf.md", "Request randomness from the VRF provider."),
+                document("paymaster.md", "The paymaster sponsors transaction fees."),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(report.added, 2);
+
+        let results = knowledge
+            .document_index()
+            .top_n::<DocumentChunk>("How do I get randomness from the VRF?", 1)
+            .await
+            .unwrap();
+        assert_eq!(results[0].2.parent_id, "vrf.md");
+    }
+
+    #[tokio::test]
+    async fn test_sync_reindexes_only_changed_documents() {
+        let mut knowledge = knowledge().await;
+        let docs = || {
+            [
+                document("vrf.md", "Request randomness from the VRF provider."),
+                document("paymaster.md", "The paymaster sponsors transaction fees."),
+                document("session.md", "Session keys sign transactions for a game."),
+            ]
+        };
+
+        let report = knowledge.sync_documents("docs", docs()).await.unwrap();
+        assert_eq!(report.added, 3);
+
+        let report = knowledge.sync_documents("docs", docs()).await.unwrap();
+        assert_eq!(
+            report,
+            IndexReport {
+                unchanged: 3,
+                ..Default::default()
+            }
+        );
+
+        let report = knowledge
+            .sync_documents(
+                "docs",
+                [
+                    document("vrf.md", "Request randomness from the Cartridge VRF."),
+                    document("paymaster.md", "The paymaster sponsors transaction fees."),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            IndexReport {
+                added: 0,
+                updated: 1,
+                unchanged: 1,
+                removed: 1,
+            }
+        );
+
+        let chunks: Vec<(String, String)> = knowledge
+            .conn
+            .call(|conn| {
+                let chunks = conn
+                    .prepare("SELECT parent_id, content FROM documents ORDER BY parent_id")?
+                    .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(chunks)
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            chunks,
+            [
+                (
+                    "paymaster.md".to_string(),
+                    "The paymaster sponsors transaction fees.".to_string()
+                ),
+                (
+                    "vrf.md".to_string(),
+                    "Request randomness from the Cartridge VRF.".to_string()
+                ),
+            ]
+        );
+        assert_eq!(count_rows(&knowledge, "documents_embeddings").await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_selective_filter_still_gets_vector_hits() {
+        let mut knowledge = knowledge().await;
+        knowledge
+            .add_documents((0..40).map(|i| {
+                document(
+                    &format!("fees-{i}.md"),
+                    &format!("Note {i}: the paymaster sponsors transaction fees."),
+                )
+            }))
+            .await
+            .unwrap();
+        knowledge
+            .add_documents([Document {
+                source_id: "faq".to_string(),
+                ..document("keys.md", "Session keys sign game transactions.")
+            }])
+            .await
+            .unwrap();
+
+        let results = knowledge
+            .hybrid_document_index(HybridConfig {
+                candidates: 2,
+                keyword_weight: 0.0,
+                ..Default::default()
+            })
+            .with_filter(DocumentFilter {
+                source_id: Some("faq".to_string()),
+                ..Default::default()
+            })
+            .top_n_ids("Who pays the transaction fees?", 2)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].1, "keys.md#0");
+    }
+
+    #[tokio::test]
+    async fn test_collection_with_its_own_model() {
+        let mut knowledge = knowledge().await.with_collection(
+            "faq",
+            CollectionConfig {
+                retrieval: HybridConfig {
+                    keyword_weight: 0.0,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        knowledge
+            .add_documents([document("vrf.md", "Request randomness from the VRF.")])
+            .await
+            .unwrap();
+        knowledge
+            .add_collection_documents(
+                "faq",
+                [document("fees.md", "Who pays the fees? The paymaster.")],
+            )
+            .await
+            .unwrap();
+
+        // Documents embedded with the knowledge base's model move over
+        let mut knowledge = knowledge
+            .with_collection_model("faq", HashingEmbeddingModel::new(32), "hashing-32")
+            .await
+            .unwrap();
+        assert_eq!(count_rows(&knowledge, "documents_embeddings").await, 1);
+        assert_eq!(count_rows(&knowledge, "collection_faq_embeddings").await, 1);
+        let models = knowledge.embedding_models().await.unwrap();
+        assert!(models
+            .iter()
+            .any(|record| record.table_name == "collection_faq"
+                && record.model == "hashing-32"
+                && record.dimensions == 32));
+
+        knowledge
+            .sync_collection_documents(
+                "faq",
+                "docs",
+                [document("keys.md", "Session keys sign game transactions.")],
+            )
+            .await
+            .unwrap();
+        assert_eq!(count_rows(&knowledge, "collection_faq_embeddings").await, 1);
+
+        let results = knowledge
+            .clone()
+            .collection_index("faq")
+            .top_n_ids("How do session keys sign?", 5)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].1, "keys.md#0");
+
+        // Another model re-embeds the collection into a table of its size
+        let knowledge = knowledge
+            .with_collection_model("faq", HashingEmbeddingModel::new(16), "hashing-16")
+            .await
+            .unwrap();
+        assert_eq!(count_rows(&knowledge, "collection_faq_embeddings").await, 1);
+        assert!(knowledge
+            .collection_index("faq")
+            .top_n_ids("session keys", 5)
+            .await
+            .unwrap()
+            .iter()
+            .any(|(_, id)| id == "keys.md#0"));
+    }
+
+    #[tokio::test]
+    async fn test_link_codes_are_single_use_and_expire() {
+        let knowledge = knowledge().await;
+        let discord = knowledge
+            .upsert_account(Source::Discord, "1", "ada")
+            .await
+            .unwrap();
+        let telegram = knowledge
+            .upsert_account(Source::Telegram, "2", "ada")
+            .await
+            .unwrap();
+
+        let expired = knowledge
+            .create_link_code(discord.id, Duration::ZERO)
+            .await
+            .unwrap();
+        assert!(knowledge
+            .confirm_link_code(&expired, telegram.id)
+            .await
+            .unwrap()
+            .is_none());
+
+        let code = knowledge
+            .create_link_code(discord.id, Duration::from_secs(600))
+            .await
+            .unwrap();
+        // Codes can't be confirmed by the account they were issued to
+        assert!(knowledge
+            .confirm_link_code(&code, discord.id)
+            .await
+            .unwrap()
+            .is_none());
+
+        let person = knowledge
+            .confirm_link_code(&code.to_lowercase(), telegram.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(knowledge.person_accounts(person.id).await.unwrap().len(), 2);
+        assert!(knowledge
+            .confirm_link_code(&code, telegram.id)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_linking_merges_persons_and_unlinking_detaches() {
+        let knowledge = knowledge().await;
+        let mut accounts = Vec::new();
+        for (source, source_id) in [
+            (Source::Discord, "1"),
+            (Source::Telegram, "2"),
+            (Source::Twitter, "3"),
+            (Source::Github, "4"),
+        ] {
+            accounts.push(
+                knowledge
+                    .upsert_account(source, source_id, "ada")
+                    .await
+                    .unwrap(),
+            );
+        }
+
+        let link = |issuer: i64, confirmer: i64| {
+            let knowledge = knowledge.clone();
+            async move {
+                let code = knowledge
+                    .create_link_code(issuer, Duration::from_secs(600))
+                    .await
+                    .unwrap();
+                knowledge
+                    .confirm_link_code(&code, confirmer)
+                    .await
+                    .unwrap()
+                    .unwrap()
+            }
+        };
+
+        let first = link(accounts[0].id, accounts[1].id).await;
+        let second = link(accounts[2].id, accounts[3].id).await;
+        assert_ne!(first.id, second.id);
+
+        // Both persons are merged into the issuer's
+        let merged = link(accounts[0].id, accounts[2].id).await;
+        assert_eq!(merged.id, first.id);
+        assert_eq!(knowledge.person_accounts(first.id).await.unwrap().len(), 4);
+        assert!(knowledge.get_person(second.id).await.unwrap().is_none());
+
+        knowledge.unlink_account(accounts[3].id).await.unwrap();
+        let unlinked = knowledge
+            .get_account_by_id(accounts[3].id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(unlinked.person_id, None);
+        assert_eq!(knowledge.person_accounts(first.id).await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_conversation_ends_after_a_gap() {
+        let knowledge = knowledge().await;
+
+        let first = converse(
+            &knowledge,
+            message("1", "general", "ada", 0),
+            Default::default(),
+        )
+        .await;
+        let second = converse(
+            &knowledge,
+            message("2", "general", "bob", 20),
+            Default::default(),
+        )
+        .await;
+        assert_eq!(first, second);
+
+        // More than CONVERSATION_GAP_MINUTES after the latest message
+        let third = converse(
+            &knowledge,
+            message("3", "general", "ada", 60),
+            Default::default(),
+        )
+        .await;
+        assert_ne!(third, first);
+
+        // Other channels have their own conversations
+        let elsewhere = converse(
+            &knowledge,
+            message("4", "random", "ada", 61),
+            Default::default(),
+        )
+        .await;
+        assert_ne!(elsewhere, third);
+    }
+
+    #[tokio::test]
+    async fn test_reply_joins_the_conversation_of_its_parent() {
+        let knowledge = knowledge().await;
+
+        let old = converse(
+            &knowledge,
+            message("1", "general", "ada", 0),
+            Default::default(),
+        )
+        .await;
+        let current = converse(
+            &knowledge,
+            message("2", "general", "bob", 120),
+            Default::default(),
+        )
+        .await;
+        assert_ne!(old, current);
+
+        let reply = converse(
+            &knowledge,
+            message("3", "general", "bob", 121),
+            ConversationHints {
+                reply_to: Some("1".to_string()),
+                thread_id: None,
+            },
+        )
+        .await;
+        assert_eq!(reply, old);
+    }
+
+    #[tokio::test]
+    async fn test_thread_is_one_conversation() {
+        let knowledge = knowledge().await;
+        let in_thread = || ConversationHints {
+            reply_to: None,
+            thread_id: Some("launch".to_string()),
+        };
+
+        let thread = converse(&knowledge, message("1", "general", "ada", 0), in_thread()).await;
+        let channel = converse(
+            &knowledge,
+            message("2", "general", "bob", 1),
+            Default::default(),
+        )
+        .await;
+        assert_ne!(channel, thread);
+
+        // Threads don't expire after a gap
+        let later = converse(&knowledge, message("3", "general", "bob", 240), in_thread()).await;
+        assert_eq!(later, thread);
+    }
+
+    #[tokio::test]
+    async fn test_pending_messages_are_embedded_once() {
+        let knowledge = knowledge().await;
+        knowledge
+            .create_message(message("1", "general", "ada", 0))
+            .await
+            .unwrap();
+        // Stored without a vector, as if background embedding was interrupted
+        for id in ["2", "3"] {
+            knowledge
+                .relational
+                .insert_message(message(id, "general", "ada", 1))
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(knowledge.embed_pending_messages().await.unwrap(), 2);
+        assert_eq!(count_rows(&knowledge, "messages_embeddings").await, 3);
+        assert_eq!(knowledge.embed_pending_messages().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_retention_drops_messages_past_max_age() {
+        let knowledge = knowledge().await;
+        knowledge
+            .create_message(message("1", "general", "ada", 0))
+            .await
+            .unwrap();
+        let mut recent = message("2", "general", "ada", 0);
+        recent.created_at = chrono::Utc::now();
+        knowledge.create_message(recent).await.unwrap();
+
+        knowledge
+            .set_retention_policy(RetentionPolicy {
+                source: Some(Source::Discord),
+                channel_id: None,
+                max_age_days: Some(30),
+                max_messages: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(knowledge.apply_retention().await.unwrap(), 1);
+
+        let kept = knowledge.get_recent_messages("general", 10).await.unwrap();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].id, "2");
+        assert_eq!(count_rows(&knowledge, "messages_embeddings").await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_retention_keeps_newest_messages_per_channel() {
+        let knowledge = knowledge().await;
+        for (id, channel_id, minutes) in [
+            ("1", "general", 0),
+            ("2", "general", 1),
+            ("3", "general", 2),
+            ("4", "random", 0),
+        ] {
+            knowledge
+                .create_message(message(id, channel_id, "ada", minutes))
+                .await
+                .unwrap();
+        }
+
+        knowledge
+            .set_retention_policy(RetentionPolicy {
+                source: None,
+                channel_id: Some("general".to_string()),
+                max_age_days: None,
+                max_messages: Some(2),
+            })
+            .await
+            .unwrap();
+        assert_eq!(knowledge.apply_retention().await.unwrap(), 1);
+
+        let mut kept: Vec<_> = knowledge
+            .get_recent_messages("general", 10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|msg| msg.id)
+            .collect();
+        kept.sort();
+        assert_eq!(kept, ["2", "3"]);
+        assert_eq!(
+            knowledge
+                .get_recent_messages("random", 10)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(count_rows(&knowledge, "messages_embeddings").await, 3);
+    }
+
+    #[tokio::test]
+    async fn test_message_delivered_twice_is_stored_once() {
+        let knowledge = knowledge().await;
+        let msg = message("1", "general", "ada", 0);
+
+        assert!(knowledge
+            .create_message(msg.clone())
+            .await
+            .unwrap()
+            .is_some());
+        assert!(knowledge.create_message(msg).await.unwrap().is_none());
+        assert_eq!(count_rows(&knowledge, "messages").await, 1);
+        assert_eq!(count_rows(&knowledge, "messages_embeddings").await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_edited_message_replaces_its_vector() {
+        let knowledge = knowledge().await;
+        knowledge
+            .create_message(message("1", "general", "ada", 0))
+            .await
+            .unwrap();
+        knowledge
+            .create_message(message("2", "general", "bob", 1))
+            .await
+            .unwrap();
+
+        let revision = knowledge
+            .edit_message(Source::Discord, "1", "the paymaster sponsors fees")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(revision.content, "message 1 from ada");
+        assert_eq!(count_rows(&knowledge, "messages_embeddings").await, 2);
+
+        let results = knowledge
+            .message_index()
+            .top_n::<Message>("who sponsors the fees?", 1)
+            .await
+            .unwrap();
+        assert_eq!(results[0].2.content, "the paymaster sponsors fees");
+    }
+
+    /// Caches an embedding of `content`, as a `CachedEmbeddingModel` would.
+    async fn cache_embedding(knowledge: &KnowledgeBase<HashingEmbeddingModel>, content: &str) {
+        let hash = embedding::content_hash(content);
+        knowledge
+            .conn
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO embedding_cache (model, content_hash, embedding) VALUES ('cached', ?1, x'00')",
+                    [hash],
+                )?;
+                Ok(())
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_deleting_a_message_forgets_its_cached_embeddings() {
+        let knowledge = knowledge().await;
+        knowledge
+            .create_message(message("1", "general", "ada", 0))
+            .await
+            .unwrap();
+        knowledge
+            .edit_message(Source::Discord, "1", "gm")
+            .await
+            .unwrap();
+        cache_embedding(&knowledge, "message 1 from ada").await;
+        cache_embedding(&knowledge, "gm").await;
+        cache_embedding(&knowledge, "unrelated").await;
+
+        assert!(knowledge
+            .delete_message(Source::Discord, "1")
+            .await
+            .unwrap());
+        assert_eq!(count_rows(&knowledge, "embedding_cache").await, 1);
+    }
+
+    /// Stores a message and a fact for `name`, and edits the message so it
+    /// has a revision. Returns the account.
+    async fn active_account(
+        knowledge: &KnowledgeBase<HashingEmbeddingModel>,
+        name: &str,
+        message_id: &str,
+    ) -> Account {
+        let account = knowledge
+            .upsert_account(Source::Discord, name, name)
+            .await
+            .unwrap();
+        knowledge
+            .create_message(message(message_id, "general", name, 0))
+            .await
+            .unwrap();
+        knowledge
+            .edit_message(Source::Discord, message_id, &format!("edited by {name}"))
+            .await
+            .unwrap()
+            .unwrap();
+        knowledge
+            .add_fact(NewFact {
+                account_id: account.id,
+                content: format!("{name} uses Firefox"),
+                category: None,
+                confidence: 0.9,
+                source_message_id: Some(message_id.to_string()),
+            })
+            .await
+            .unwrap();
+
+        account
+    }
+
+    #[tokio::test]
+    async fn test_purge_removes_everything_about_the_account() {
+        let knowledge = knowledge().await;
+        let ada = active_account(&knowledge, "ada", "1").await;
+        cache_embedding(&knowledge, "message 1 from ada").await;
+        cache_embedding(&knowledge, "edited by ada").await;
+
+        let report = knowledge.purge_account(ada.id).await.unwrap();
+        assert_eq!(report.messages, 1);
+        assert_eq!(report.facts, 1);
+
+        assert!(knowledge.get_account_by_id(ada.id).await.unwrap().is_none());
+        assert!(knowledge
+            .get_recent_messages("general", 10)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(knowledge
+            .message_revisions(Source::Discord, "1")
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(knowledge
+            .account_facts(ada.id, 0.0)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(count_rows(&knowledge, "messages_embeddings").await, 0);
+        assert_eq!(count_rows(&knowledge, "embedding_cache").await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_purge_leaves_other_accounts_alone() {
+        let knowledge = knowledge().await;
+        let ada = active_account(&knowledge, "ada", "1").await;
+        let bob = active_account(&knowledge, "bob", "2").await;
+
+        knowledge.purge_account(ada.id).await.unwrap();
+
+        assert!(knowledge.get_account_by_id(bob.id).await.unwrap().is_some());
+        let messages = knowledge.account_messages(bob.id, 10).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, "edited by bob");
+        assert_eq!(
+            knowledge
+                .message_revisions(Source::Discord, "2")
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(knowledge.account_facts(bob.id, 0.0).await.unwrap().len(), 1);
+        assert_eq!(count_rows(&knowledge, "messages_embeddings").await, 1);
     }
 }
@@ -20,3 +20,4 @@ pub mod clients;
 pub mod knowledge;
 pub mod loaders;
 pub mod mcp;
+pub mod memory;
@@ -0,0 +1,184 @@
+use rig::{
+    completion::{CompletionModel, ModelChoice},
+    embeddings::EmbeddingModel,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use crate::knowledge::{Fact, KnowledgeBase, NewFact, SummaryScope};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FactConfig {
+    /// New messages from an account needed before running the extractor.
+    pub batch_size: usize,
+    /// Facts below this confidence are discarded.
+    pub min_confidence: f64,
+    /// Facts given to the agent when the user speaks.
+    pub max_facts_in_context: usize,
+}
+
+impl Default for FactConfig {
+    fn default() -> Self {
+        Self {
+            batch_size: 10,
+            min_confidence: 0.6,
+            max_facts_in_context: 10,
+        }
+    }
+}
+
+/// A fact as returned by the LLM.
+#[derive(Debug, Deserialize, PartialEq)]
+struct ExtractedFact {
+    fact: String,
+    #[serde(default)]
+    category: Option<String>,
+    confidence: f64,
+    #[serde(default)]
+    message_id: Option<String>,
+}
+
+/// Extracts durable facts about users from the messages they write.
+#[derive(Clone)]
+pub struct FactExtractor<M: CompletionModel, E: EmbeddingModel + 'static> {
+    config: FactConfig,
+    completion_model: M,
+    knowledge: KnowledgeBase<E>,
+}
+
+impl<M: CompletionModel, E: EmbeddingModel + 'static> FactExtractor<M, E> {
+    pub fn new(config: FactConfig, completion_model: M, knowledge: KnowledgeBase<E>) -> Self {
+        Self {
+            config,
+            completion_model,
+            knowledge,
+        }
+    }
+
+    pub fn config(&self) -> &FactConfig {
+        &self.config
+    }
+
+    /// Runs the extractor over the messages of an account written since the
+    /// last run, once there are enough of them. Returns the facts stored.
+    pub async fn extract(&self, account_id: i64) -> anyhow::Result<Vec<Fact>> {
+        let since = self.knowledge.facts_extracted_until(account_id).await?;
+        let messages = self
+            .knowledge
+            .messages_after(
+                &SummaryScope::Account(account_id),
+                since,
+                self.config.batch_size as i64,
+            )
+            .await?;
+
+        if messages.len() < self.config.batch_size {
+            debug!(
+                account_id,
+                pending = messages.len(),
+                "Not enough messages to extract facts"
+            );
+            return Ok(Vec::new());
+        }
+
+        let Some(last) = messages.last() else {
+            return Ok(Vec::new());
+        };
+
+        let known = self
+            .knowledge
+            .account_facts(account_id, self.config.min_confidence)
+            .await?;
+
+        let prompt = format!(
+            "Extract durable facts about the user from their messages: their setup, projects, \
+            skills, preferences and anything else still true next month. Skip opinions about \
+            the current conversation and facts already known.\n\n\
+            Known facts:\n{}\n\nMessages:\n{}\n\n\
+            Reply with a JSON array of objects with the fields \"fact\" (a short sentence), \
+            \"category\", \"confidence\" (0 to 1) and \"message_id\". Reply with [] if there \
+            is nothing to remember.",
+            known
+                .iter()
+                .map(|fact| format!("- {}", fact.content))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            messages
+                .iter()
+                .map(|message| format!("[{}] {}", message.message_id, message.content))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        );
+
+        let request = self.completion_model.completion_request(&prompt).build();
+        let extracted = match self.completion_model.completion(request).await?.choice {
+            ModelChoice::Message(text) => parse_facts(&text),
+            ModelChoice::ToolCall(_, _) => Vec::new(),
+        };
+
+        let mut stored = Vec::new();
+        for fact in extracted {
+            if fact.confidence < self.config.min_confidence || fact.fact.trim().is_empty() {
+                continue;
+            }
+
+            stored.push(
+                self.knowledge
+                    .add_fact(NewFact {
+                        account_id,
+                        content: fact.fact.trim().to_string(),
+                        category: fact.category,
+                        confidence: fact.confidence.min(1.0),
+                        source_message_id: fact.message_id,
+                    })
+                    .await?,
+            );
+        }
+
+        self.knowledge
+            .set_facts_extracted_until(account_id, last.created_at)
+            .await?;
+
+        info!(account_id, count = stored.len(), "Extracted facts");
+        Ok(stored)
+    }
+}
+
+/// Reads the JSON array of the reply, ignoring any text around it.
+fn parse_facts(text: &str) -> Vec<ExtractedFact> {
+    let (Some(start), Some(end)) = (text.find('['), text.rfind(']')) else {
+        return Vec::new();
+    };
+
+    if end < start {
+        return Vec::new();
+    }
+
+    serde_json::from_str(&text[start..=end]).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_facts_ignores_surrounding_text() {
+        let facts = parse_facts(
+            "Here you go:\n```json\n[{\"fact\": \"Uses Firefox on Linux\", \"category\": \"setup\", \
+            \"confidence\": 0.9, \"message_id\": \"42\"}]\n```",
+        );
+
+        assert_eq!(
+            facts,
+            vec![ExtractedFact {
+                fact: "Uses Firefox on Linux".to_string(),
+                category: Some("setup".to_string()),
+                confidence: 0.9,
+                message_id: Some("42".to_string()),
+            }]
+        );
+        assert!(parse_facts("Nothing to remember.").is_empty());
+        assert!(parse_facts("[not json]").is_empty());
+    }
+}
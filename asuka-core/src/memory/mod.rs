@@ -0,0 +1,5 @@
+mod facts;
+mod summary;
+
+pub use facts::{FactConfig, FactExtractor};
+pub use summary::{Summarizer, SummaryConfig};
@@ -0,0 +1,275 @@
+use rig::{
+    completion::{CompletionModel, ModelChoice},
+    embeddings::EmbeddingModel,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use tracing::{debug, info};
+
+use crate::knowledge::{HistoryEntry, KnowledgeBase, Summary, SummaryScope};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SummaryConfig {
+    /// Messages condensed at a time, a summary is only written once this many
+    /// are waiting.
+    pub batch_size: usize,
+    /// Newest messages left out of the summary, they are given to the agent
+    /// verbatim.
+    pub keep_recent: usize,
+    pub max_words: usize,
+}
+
+impl Default for SummaryConfig {
+    fn default() -> Self {
+        Self {
+            batch_size: 20,
+            keep_recent: 10,
+            max_words: 200,
+        }
+    }
+}
+
+/// Keeps a rolling summary per channel and per account: each new summary
+/// condenses the previous one with the messages written since.
+#[derive(Clone)]
+pub struct Summarizer<M: CompletionModel, E: EmbeddingModel + 'static> {
+    config: SummaryConfig,
+    completion_model: M,
+    knowledge: KnowledgeBase<E>,
+    /// Scopes being summarized, so that concurrent calls don't condense the
+    /// same messages twice.
+    in_progress: Arc<Mutex<HashSet<String>>>,
+}
+
+/// Marks a scope as being summarized until dropped, even when the summary
+/// fails or its future is dropped.
+struct InProgress {
+    scopes: Arc<Mutex<HashSet<String>>>,
+    key: String,
+}
+
+impl Drop for InProgress {
+    fn drop(&mut self) {
+        self.scopes.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl<M: CompletionModel, E: EmbeddingModel + 'static> Summarizer<M, E> {
+    pub fn new(config: SummaryConfig, completion_model: M, knowledge: KnowledgeBase<E>) -> Self {
+        Self {
+            config,
+            completion_model,
+            knowledge,
+            in_progress: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    pub fn config(&self) -> &SummaryConfig {
+        &self.config
+    }
+
+    /// Writes a new summary of `scope` if enough messages have accumulated
+    /// since the latest one. Returns the summary written, if any. Skipped
+    /// while another call summarizes the same scope.
+    pub async fn summarize(
+        &self,
+        scope: &SummaryScope,
+        agent_name: &str,
+    ) -> anyhow::Result<Option<Summary>> {
+        let key = format!("{}:{}", scope.kind(), scope.id());
+        let started = self.in_progress.lock().unwrap().insert(key.clone());
+        if !started {
+            debug!(scope = key, "Scope is already being summarized");
+            return Ok(None);
+        }
+        let _in_progress = InProgress {
+            scopes: self.in_progress.clone(),
+            key,
+        };
+
+        let previous = self.knowledge.latest_summary(scope).await?;
+        let pending = self
+            .knowledge
+            .messages_after(
+                scope,
+                previous.as_ref().map(|summary| summary.covered_until),
+                (self.config.batch_size + self.config.keep_recent) as i64,
+            )
+            .await?;
+
+        if pending.len() < self.config.batch_size + self.config.keep_recent {
+            debug!(
+                scope = scope.kind(),
+                scope_id = scope.id(),
+                pending = pending.len(),
+                "Not enough messages to summarize"
+            );
+            return Ok(None);
+        }
+
+        let condensed = &pending[..self.config.batch_size];
+        let Some(last) = condensed.last() else {
+            return Ok(None);
+        };
+
+        let content = self
+            .condense(
+                previous.as_ref().map(|summary| summary.content.as_str()),
+                condensed,
+                agent_name,
+            )
+            .await?;
+
+        let summary = Summary {
+            id: format!("{}:{}:{}", scope.kind(), scope.id(), last.message_id),
+            scope: scope.kind().to_string(),
+            scope_id: scope.id(),
+            content,
+            message_count: previous.as_ref().map_or(0, |summary| summary.message_count)
+                + condensed.len() as i64,
+            covered_until: last.created_at,
+            created_at: chrono::Utc::now(),
+        };
+
+        info!(
+            scope = summary.scope,
+            scope_id = summary.scope_id,
+            message_count = summary.message_count,
+            "Storing summary"
+        );
+        self.knowledge.add_summary(summary.clone()).await?;
+
+        Ok(Some(summary))
+    }
+
+    async fn condense(
+        &self,
+        previous: Option<&str>,
+        messages: &[HistoryEntry],
+        agent_name: &str,
+    ) -> anyhow::Result<String> {
+        let prompt = format!(
+            "You keep the long-term memory of {agent_name}. Write an updated summary of the \
+            conversation in at most {} words. Keep names, facts, decisions and open questions, \
+            drop small talk. Reply with the summary only.\n\n\
+            Summary so far:\n{}\n\nNew messages:\n{}",
+            self.config.max_words,
+            previous.unwrap_or("(none)"),
+            HistoryEntry::transcript(messages, agent_name),
+        );
+
+        let request = self.completion_model.completion_request(&prompt).build();
+
+        match self.completion_model.completion(request).await?.choice {
+            ModelChoice::Message(text) => Ok(text.trim().to_string()),
+            ModelChoice::ToolCall(name, _) => Err(anyhow::anyhow!(
+                "Unexpected tool call {name} while summarizing"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use rig::completion::{CompletionError, CompletionRequest, CompletionResponse};
+    use tokio_rusqlite::Connection;
+
+    use super::*;
+    use crate::knowledge::{ChannelType, HashingEmbeddingModel, Message, Source};
+
+    /// Answers every prompt with the same summary, counting the calls.
+    #[derive(Clone, Default)]
+    struct CountingModel {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl CompletionModel for CountingModel {
+        type Response = ();
+
+        async fn completion(
+            &self,
+            _request: CompletionRequest,
+        ) -> Result<CompletionResponse<()>, CompletionError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+
+            Ok(CompletionResponse {
+                choice: ModelChoice::Message("They talked about the launch.".to_string()),
+                raw_response: (),
+            })
+        }
+    }
+
+    fn message(index: i64) -> Message {
+        Message {
+            id: index.to_string(),
+            source: Source::Discord,
+            source_id: index.to_string(),
+            channel_type: ChannelType::Text,
+            channel_id: "general".to_string(),
+            account_id: "42".to_string(),
+            role: "user".to_string(),
+            content: format!("launch update {index}"),
+            created_at: chrono::DateTime::from_timestamp(1_717_243_200 + index * 60, 0).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_summarizes_full_batches_once() {
+        unsafe {
+            rusqlite::ffi::sqlite3_auto_extension(Some(std::mem::transmute(
+                sqlite_vec::sqlite3_vec_init as *const (),
+            )));
+        }
+        let knowledge = KnowledgeBase::new(
+            Connection::open_in_memory().await.unwrap(),
+            HashingEmbeddingModel::new(64),
+            HashingEmbeddingModel::NAME,
+        )
+        .await
+        .unwrap();
+
+        let model = CountingModel::default();
+        let config = SummaryConfig {
+            batch_size: 3,
+            keep_recent: 2,
+            max_words: 50,
+        };
+        let summarizer = Summarizer::new(config, model.clone(), knowledge.clone());
+        let scope = SummaryScope::Channel("general".to_string());
+
+        for index in 0..4 {
+            knowledge.create_message(message(index)).await.unwrap();
+        }
+        assert!(summarizer
+            .summarize(&scope, "asuka")
+            .await
+            .unwrap()
+            .is_none());
+
+        knowledge.create_message(message(4)).await.unwrap();
+        let (first, second) = tokio::join!(
+            summarizer.summarize(&scope, "asuka"),
+            summarizer.summarize(&scope, "asuka")
+        );
+        let summaries: Vec<Summary> = [first.unwrap(), second.unwrap()]
+            .into_iter()
+            .flatten()
+            .collect();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(model.calls.load(Ordering::SeqCst), 1);
+
+        // The oldest batch is condensed, the most recent messages are kept
+        assert_eq!(summaries[0].message_count, 3);
+        assert_eq!(summaries[0].covered_until, message(2).created_at);
+
+        assert!(summarizer
+            .summarize(&scope, "asuka")
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
@@ -1,5 +1,5 @@
 use asuka_core::attention::{Attention, AttentionConfig};
-use asuka_core::knowledge::Document;
+use asuka_core::knowledge::{Document, DocumentMetadata};
 use clap::{command, Parser};
 use rig::providers::{self, openai};
 
@@ -19,6 +19,10 @@ struct Args {
     #[arg(long, default_value = "examples/src/characters/shinobi.toml")]
     character: String,
 
+    /// Path to attention configuration TOML file
+    #[arg(long)]
+    attention_config: Option<String>,
+
     /// Path to database
     #[arg(long, default_value = ":memory:")]
     db_path: String,
@@ -51,7 +55,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let args = Args::parse();
 
-    let repo = GitLoader::new(args.github_repo, &args.github_path)?;
+    let repo = GitLoader::new(args.github_repo.clone(), &args.github_path)?;
 
     let character_content =
         std::fs::read_to_string(&args.character).expect("Failed to read character file");
@@ -70,10 +74,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     let conn = Connection::open(args.db_path).await?;
-    let mut knowledge = KnowledgeBase::new(conn.clone(), embedding_model).await?;
-
-    knowledge
-        .add_documents(
+    let mut knowledge = KnowledgeBase::new(
+        conn.clone(),
+        embedding_model,
+        openai::TEXT_EMBEDDING_3_SMALL,
+    )
+    .await?;
+
+    let report = knowledge
+        .sync_documents(
+            "github",
             repo.with_dir("src/pages/vrf")?
                 .read_with_path()
                 .ignore_errors()
@@ -81,20 +91,50 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .map(|(path, content)| Document {
                     id: path.to_string_lossy().to_string(),
                     source_id: "github".to_string(),
+                    metadata: DocumentMetadata {
+                        title: path
+                            .file_stem()
+                            .map(|stem| stem.to_string_lossy().to_string()),
+                        repo: Some(args.github_repo.clone()),
+                        tags: vec!["vrf".to_string()],
+                        ..Default::default()
+                    },
                     content,
                     created_at: chrono::Utc::now(),
                 }),
         )
         .await?;
+    println!(
+        "Indexed documents: {} added, {} updated, {} unchanged, {} removed",
+        report.added, report.updated, report.unchanged, report.removed
+    );
 
     let agent = Agent::new(character, completion_model, knowledge);
 
-    let config = AttentionConfig {
+    let mut config = AttentionConfig {
         bot_names: vec![agent.character.name.clone()],
-        ..Default::default()
+        ..match &args.attention_config {
+            Some(path) => AttentionConfig::load(path)?,
+            None => Default::default(),
+        }
     };
+    if config.proactive.topics.is_empty() {
+        config.proactive.topics = agent.character.topics.clone();
+    }
     let attention = Attention::new(config, should_respond_completion_model);
 
+    // Drop messages that fall outside of the configured retention policies
+    let retention = agent.knowledge().clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            if let Err(err) = retention.apply_retention().await {
+                tracing::error!(?err, "Failed to apply retention policies");
+            }
+        }
+    });
+
     let discord = DiscordClient::new(agent, attention);
     discord.start(&args.discord_api_token).await?;
 